@@ -0,0 +1,115 @@
+use crate::{
+    as_buffer, as_string, is_buffer, is_string, obj_val,
+    object::{Arity, Obj, ObjBuffer, ObjString, ObjType},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_buffer_natives() {
+    vm().define_native("buffer", Arity::Exact(1), buffer_native);
+    vm().define_native(
+        "bufferFromString",
+        Arity::Exact(1),
+        buffer_from_string_native,
+    );
+    vm().define_native("bufferToString", Arity::Exact(1), buffer_to_string_native);
+    vm().define_native("bufferFromHex", Arity::Exact(1), buffer_from_hex_native);
+    vm().define_native("bufferToHex", Arity::Exact(1), buffer_to_hex_native);
+}
+
+// 取出缓冲区参数 不是缓冲区时报告运行时错误
+fn buffer_arg(name: &str, value: Value) -> Option<*mut ObjBuffer> {
+    if !is_buffer!(value) {
+        vm().native_error(format!("{}() expects a buffer.", name));
+        return None;
+    }
+    Some(as_buffer!(value))
+}
+
+// 取出字符串参数 不是字符串时报告运行时错误
+fn string_arg(name: &str, value: Value) -> Option<*mut ObjString> {
+    if !is_string!(value) {
+        vm().native_error(format!("{}() expects a string.", name));
+        return None;
+    }
+    Some(as_string!(value))
+}
+
+// buffer(size) 创建全零的缓冲区
+fn buffer_native(_arg_count: usize, args: *mut Value) -> Value {
+    match unsafe { *args } {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => {
+            obj_val!(ObjBuffer::new(vec![0; n as usize]))
+        }
+        _ => {
+            vm().native_error("buffer() size must be a non-negative integer.".into());
+            Value::Nil
+        }
+    }
+}
+
+// bufferFromString(s) 字符串的 UTF-8 编码
+fn buffer_from_string_native(_arg_count: usize, args: *mut Value) -> Value {
+    match string_arg("bufferFromString", unsafe { *args }) {
+        Some(string) => {
+            let bytes = unsafe { (*string).chars.as_bytes().to_vec() };
+            obj_val!(ObjBuffer::new(bytes))
+        }
+        None => Value::Nil,
+    }
+}
+
+// bufferToString(b) 按 UTF-8 解码
+fn buffer_to_string_native(_arg_count: usize, args: *mut Value) -> Value {
+    let buffer = match buffer_arg("bufferToString", unsafe { *args }) {
+        Some(buffer) => buffer,
+        None => return Value::Nil,
+    };
+    match String::from_utf8(unsafe { (*buffer).bytes.clone() }) {
+        Ok(string) => obj_val!(ObjString::take_string(string)),
+        Err(_) => {
+            vm().native_error("Buffer is not valid UTF-8.".into());
+            Value::Nil
+        }
+    }
+}
+
+// bufferFromHex(s) 解析十六进制字符串 每两个字符一个字节
+fn buffer_from_hex_native(_arg_count: usize, args: *mut Value) -> Value {
+    let string = match string_arg("bufferFromHex", unsafe { *args }) {
+        Some(string) => string,
+        None => return Value::Nil,
+    };
+
+    let hex = unsafe { (*string).chars.as_bytes() };
+    let bytes: Option<Vec<u8>> = if hex.len() % 2 == 0 {
+        hex.chunks(2)
+            .map(|pair| {
+                let digits = std::str::from_utf8(pair).ok()?;
+                u8::from_str_radix(digits, 16).ok()
+            })
+            .collect()
+    } else {
+        None
+    };
+    match bytes {
+        Some(bytes) => obj_val!(ObjBuffer::new(bytes)),
+        None => {
+            vm().native_error("Invalid hex string.".into());
+            Value::Nil
+        }
+    }
+}
+
+// bufferToHex(b) 小写十六进制字符串
+fn buffer_to_hex_native(_arg_count: usize, args: *mut Value) -> Value {
+    let buffer = match buffer_arg("bufferToHex", unsafe { *args }) {
+        Some(buffer) => buffer,
+        None => return Value::Nil,
+    };
+    let hex: String = unsafe { &(*buffer).bytes }
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    obj_val!(ObjString::take_string(hex))
+}
@@ -0,0 +1,51 @@
+use crate::{
+    as_builder, is_builder, memory, obj_val,
+    object::{Arity, Obj, ObjBuilder, ObjString, ObjType},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_builder_natives() {
+    vm().define_native("stringBuilder", Arity::Exact(0), string_builder_native);
+    vm().define_native("builderAppend", Arity::Exact(2), builder_append_native);
+    vm().define_native("builderToString", Arity::Exact(1), builder_to_string_native);
+}
+
+// 取出构建器参数 不是构建器时报告运行时错误
+fn builder_arg(name: &str, value: Value) -> Option<*mut ObjBuilder> {
+    if !is_builder!(value) {
+        vm().native_error(format!("{}() expects a string builder.", name));
+        return None;
+    }
+    Some(as_builder!(value))
+}
+
+// stringBuilder() 创建空的字符串构建器
+fn string_builder_native(_arg_count: usize, _args: *mut Value) -> Value {
+    obj_val!(ObjBuilder::new())
+}
+
+// builderAppend(b, value) 追加值的 print 形式 返回构建器以便连续调用
+fn builder_append_native(_arg_count: usize, args: *mut Value) -> Value {
+    let builder = match builder_arg("builderAppend", unsafe { *args }) {
+        Some(builder) => builder,
+        None => return Value::Nil,
+    };
+    let value = unsafe { *args.add(1) };
+    let text = value.to_string();
+    memory::note_external(text.len());
+    unsafe { (*builder).chars.push_str(&text) };
+
+    unsafe { *args }
+}
+
+// builderToString(b) 取出目前构建的字符串
+fn builder_to_string_native(_arg_count: usize, args: *mut Value) -> Value {
+    match builder_arg("builderToString", unsafe { *args }) {
+        Some(builder) => {
+            let chars = unsafe { (*builder).chars.clone() };
+            obj_val!(ObjString::take_string(chars))
+        }
+        None => Value::Nil,
+    }
+}
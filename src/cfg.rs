@@ -0,0 +1,192 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use crate::{
+    as_function,
+    chunk::{Chunk, OpCode},
+    object::{ObjFunction, ObjType},
+    value::as_obj,
+};
+
+// 基本块 [start, end) 为字节码偏移区间
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>, // 后继基本块的起始偏移
+}
+
+// 读取跳转指令的目标偏移 非跳转指令返回None
+fn jump_target(chunk: &Chunk, offset: usize) -> Option<usize> {
+    let jump = ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+    match OpCode::from(chunk.code[offset]) {
+        OpCode::Jump | OpCode::JumpIfFalse => Some(offset + 3 + jump),
+        OpCode::Loop => Some(offset + 3 - jump),
+        _ => None,
+    }
+}
+
+// 按跳转目标和跳转/返回之后的指令切分基本块
+pub fn basic_blocks(chunk: &Chunk) -> Vec<BasicBlock> {
+    let mut leaders = BTreeSet::new();
+    leaders.insert(0);
+
+    let mut offset = 0;
+    while offset < chunk.count() {
+        let next = offset + chunk.instruction_len(offset);
+        match OpCode::from(chunk.code[offset]) {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                leaders.insert(jump_target(chunk, offset).unwrap());
+                leaders.insert(next);
+            }
+            OpCode::Return => {
+                leaders.insert(next);
+            }
+            _ => {}
+        }
+        offset = next;
+    }
+    leaders.retain(|&leader| leader < chunk.count());
+
+    let starts: Vec<usize> = leaders.into_iter().collect();
+    let mut blocks = vec![];
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(chunk.count());
+
+        // 找到块内最后一条指令
+        let mut last = start;
+        while last + chunk.instruction_len(last) < end {
+            last += chunk.instruction_len(last);
+        }
+
+        let mut successors = vec![];
+        match OpCode::from(chunk.code[last]) {
+            OpCode::Jump | OpCode::Loop => successors.push(jump_target(chunk, last).unwrap()),
+            OpCode::JumpIfFalse => {
+                successors.push(end);
+                successors.push(jump_target(chunk, last).unwrap());
+            }
+            OpCode::Return => {}
+            _ => successors.push(end),
+        }
+        successors.retain(|&successor| successor < chunk.count());
+
+        blocks.push(BasicBlock {
+            start,
+            end,
+            successors,
+        });
+    }
+
+    blocks
+}
+
+// 沿控制流空跑一遍字节码 按指令的栈效应求出函数执行中栈深度的最大值
+// entry 为进入函数时已占用的槽位(被调用者和参数) 不可达的代码不计入
+// 栈深度降到0以下或汇合处深度不一致时返回出错位置的偏移
+pub fn max_stack_depth(chunk: &Chunk, entry: usize) -> Result<usize, usize> {
+    let blocks: HashMap<usize, BasicBlock> = basic_blocks(chunk)
+        .into_iter()
+        .map(|block| (block.start, block))
+        .collect();
+    let mut max = entry;
+    let mut visited: HashMap<usize, i64> = HashMap::new();
+    let mut worklist = vec![(0, entry as i64)];
+    while let Some((start, depth)) = worklist.pop() {
+        let Some(block) = blocks.get(&start) else {
+            continue;
+        };
+        // 正确的字节码在汇合处深度一致 只需要处理一次
+        if let Some(&seen) = visited.get(&start) {
+            if seen != depth {
+                return Err(start);
+            }
+            continue;
+        }
+        visited.insert(start, depth);
+
+        let mut depth = depth;
+        let mut offset = block.start;
+        while offset < block.end {
+            let op = OpCode::from(chunk.code[offset]);
+            let arg_count = op.info().operands.arg_count(|i| chunk.code[offset + 1 + i]);
+            depth += op.info().effect.resolve(arg_count) as i64;
+            if depth < 0 {
+                return Err(offset);
+            }
+            max = max.max(depth as usize);
+            offset += chunk.instruction_len(offset);
+        }
+        for &successor in &block.successors {
+            worklist.push((successor, depth));
+        }
+    }
+    Ok(max)
+}
+
+// DOT 标签转义 每行左对齐
+fn escape_label(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\l"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// 把一个函数的控制流图写成 DOT 子图 并递归处理嵌套函数
+fn write_function(out: &mut String, function: *mut ObjFunction, index: &mut usize) {
+    let function = unsafe { function.as_ref().unwrap() };
+    let chunk = &function.chunk;
+    let id = *index;
+    *index += 1;
+
+    writeln!(out, "  subgraph cluster_{} {{", id).unwrap();
+    writeln!(
+        out,
+        "    label=\"{}\";",
+        escape_label(function.display_name())
+    )
+    .unwrap();
+    for block in basic_blocks(chunk) {
+        let mut text = String::new();
+        let mut offset = block.start;
+        while offset < block.end {
+            write!(text, "{:04} ", offset).unwrap();
+            chunk.write_instruction(&mut text, offset);
+            offset += chunk.instruction_len(offset);
+        }
+        writeln!(
+            out,
+            "    f{}_{} [shape=box, fontname=monospace, label=\"{}\"];",
+            id,
+            block.start,
+            escape_label(&text)
+        )
+        .unwrap();
+        for successor in block.successors {
+            writeln!(out, "    f{}_{} -> f{}_{};", id, block.start, id, successor).unwrap();
+        }
+    }
+    writeln!(out, "  }}").unwrap();
+
+    for value in &chunk.constants.values {
+        if value.is_obj_type(ObjType::Function) {
+            write_function(out, as_function!(*value), index);
+        }
+    }
+}
+
+// 输出函数(及其嵌套函数)的控制流图 Graphviz DOT 格式
+pub fn function_to_dot(function: *mut ObjFunction) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph cfg {{").unwrap();
+    write_function(&mut out, function, &mut 0);
+    writeln!(out, "}}").unwrap();
+    out
+}
@@ -1,97 +1,177 @@
-use crate::value::{Value, ValueArray};
-
-pub enum OpCode {
-    Constant,     // 写入常量
-    Nil,          // 空指令 nil
-    True,         // true指令
-    False,        // false指令
-    Pop,          // 弹出指令
-    GetLocal,     // 获取局部变量
-    SetLocal,     // 赋值局部变量
-    GetGlobal,    // 获取全局变量
-    DefineGlobal, // 定义全局变量
-    SetGlobal,    // 赋值全局变量
-    GetUpvalue,   // 获取升值指令
-    SetUpvalue,   // 赋值升值指令
-    GetProperty,  // 获取属性指令
-    SetProperty,  // 赋值属性指令
-    GetSuper,     // 获取父类指令
-    Equal,        // 赋值指令 =
-    Greater,      // 大于指令 >
-    Less,         // 小于指令 <
-    Add,          // 加指令 +
-    Subtract,     // 减指令 -
-    Multiply,     // 乘指令 *
-    Divide,       // 除指令 /
-    Not,          // 非指令 !
-    Negate,       // 负指令 -
-    Print,        // 打印指令
-    Jump,         // 分支跳转指令
-    JumpIfFalse,  // if false分支跳转指令
-    Loop,         // 循环指令
-    Call,         // 调用指令
-    Invoke,       // 执行指令
-    SuperInvoke,  // 父类执行指令
-    Closure,      // 闭包指令
-    CloseUpvalue, // 关闭提升值
-    Return,       // 返回指令
-    Class,        // 类指令
-    Inherit,      // 继承指令
-    Method,       // 方法指令
+use crate::{
+    as_function,
+    object::ObjFunction,
+    value::{as_obj, Value, ValueArray},
+};
+
+// 操作数格式
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    None,       // 无操作数
+    Byte,       // 单字节 局部变量槽/提升值槽/参数数
+    Short,      // 两字节参数数
+    Constant,   // 单字节常量索引
+    Jump,       // 两字节向前跳转偏移
+    Loop,       // 两字节向后跳转偏移
+    Invoke,     // 常量索引 + 参数数
+    WideInvoke, // 常量索引 + 两字节参数数
+    Intrinsic,  // 内联函数编号 + 参数数
+    Closure,    // 常量索引 + 每个提升值两字节
 }
 
-impl Into<OpCode> for u8 {
-    fn into(self) -> OpCode {
-        match self {
-            0 => OpCode::Constant,
-            1 => OpCode::Nil,
-            2 => OpCode::True,
-            3 => OpCode::False,
-            4 => OpCode::Pop,
-            5 => OpCode::GetLocal,
-            6 => OpCode::SetLocal,
-            7 => OpCode::GetGlobal,
-            8 => OpCode::DefineGlobal,
-            9 => OpCode::SetGlobal,
-            10 => OpCode::GetUpvalue,
-            11 => OpCode::SetUpvalue,
-            12 => OpCode::GetProperty,
-            13 => OpCode::SetProperty,
-            14 => OpCode::GetSuper,
-            15 => OpCode::Equal,
-            16 => OpCode::Greater,
-            17 => OpCode::Less,
-            18 => OpCode::Add,
-            19 => OpCode::Subtract,
-            20 => OpCode::Multiply,
-            21 => OpCode::Divide,
-            22 => OpCode::Not,
-            23 => OpCode::Negate,
-            24 => OpCode::Print,
-            25 => OpCode::Jump,
-            26 => OpCode::JumpIfFalse,
-            27 => OpCode::Loop,
-            28 => OpCode::Call,
-            29 => OpCode::Invoke,
-            30 => OpCode::SuperInvoke,
-            31 => OpCode::Closure,
-            32 => OpCode::CloseUpvalue,
-            33 => OpCode::Return,
-            34 => OpCode::Class,
-            35 => OpCode::Inherit,
-            36 => OpCode::Method,
-            _ => {
-                println!("Unknown opcode {}", self as u8);
+// 指令对栈深度的影响
+#[derive(Clone, Copy)]
+pub enum StackEffect {
+    Fixed(i32),     // 固定变化量
+    MinusArgs(i32), // 固定变化量再减去参数数
+}
+
+// 指令元数据
+pub struct OpInfo {
+    pub name: &'static str,  // 反汇编名
+    pub operands: Operands,  // 操作数格式
+    pub effect: StackEffect, // 栈效应
+}
+
+// 指令定义表 新增指令只需在这里加一行
+// 生成 OpCode 枚举、字节解码以及元数据表 虚拟机的 match 与反汇编都依赖它
+macro_rules! opcodes {
+    ($($op:ident => $name:literal, $operands:ident, $effect:expr;)*) => {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        pub enum OpCode {
+            $($op,)*
+        }
+
+        const OPCODES: &[OpCode] = &[$(OpCode::$op,)*];
+
+        static OP_INFOS: &[OpInfo] = &[$(OpInfo {
+            name: $name,
+            operands: Operands::$operands,
+            effect: $effect,
+        },)*];
+    };
+}
+
+use StackEffect::{Fixed, MinusArgs};
+
+opcodes! {
+    Constant => "OP_CONSTANT", Constant, Fixed(1);              // 写入常量
+    Nil => "OP_NIL", None, Fixed(1);                            // 空指令 nil
+    True => "OP_TRUE", None, Fixed(1);                          // true指令
+    False => "OP_FALSE", None, Fixed(1);                        // false指令
+    Pop => "OP_POP", None, Fixed(-1);                           // 弹出指令
+    GetLocal => "OP_GET_LOCAL", Byte, Fixed(1);                 // 获取局部变量
+    SetLocal => "OP_SET_LOCAL", Byte, Fixed(0);                 // 赋值局部变量
+    GetGlobal => "OP_GET_GLOBAL", Constant, Fixed(1);           // 获取全局变量
+    DefineGlobal => "OP_DEFINE_GLOBAL", Constant, Fixed(-1);    // 定义全局变量
+    SetGlobal => "OP_SET_GLOBAL", Constant, Fixed(0);           // 赋值全局变量
+    GetUpvalue => "OP_GET_UPVALUE", Byte, Fixed(1);             // 获取升值指令
+    SetUpvalue => "OP_SET_UPVALUE", Byte, Fixed(0);             // 赋值升值指令
+    GetProperty => "OP_GET_PROPERTY", Constant, Fixed(0);       // 获取属性指令
+    SetProperty => "OP_SET_PROPERTY", Constant, Fixed(-1);      // 赋值属性指令
+    GetSuper => "OP_GET_SUPER", Constant, Fixed(-1);            // 获取父类指令
+    Equal => "OP_EQUAL", None, Fixed(-1);                       // 赋值指令 =
+    Greater => "OP_GREATER", None, Fixed(-1);                   // 大于指令 >
+    Less => "OP_LESS", None, Fixed(-1);                         // 小于指令 <
+    Add => "OP_ADD", None, Fixed(-1);                           // 加指令 +
+    Subtract => "OP_SUBTRACT", None, Fixed(-1);                 // 减指令 -
+    Multiply => "OP_MULTIPLY", None, Fixed(-1);                 // 乘指令 *
+    Divide => "OP_DIVIDE", None, Fixed(-1);                     // 除指令 /
+    Not => "OP_NOT", None, Fixed(0);                            // 非指令 !
+    Negate => "OP_NEGATE", None, Fixed(0);                      // 负指令 -
+    Print => "OP_PRINT", None, Fixed(-1);                       // 打印指令
+    Jump => "OP_JUMP", Jump, Fixed(0);                          // 分支跳转指令
+    JumpIfFalse => "OP_JUMP_IF_FALSE", Jump, Fixed(0);          // if false分支跳转指令
+    Loop => "OP_LOOP", Loop, Fixed(0);                          // 循环指令
+    Call => "OP_CALL", Byte, MinusArgs(0);                      // 调用指令
+    Invoke => "OP_INVOKE", Invoke, MinusArgs(0);                // 执行指令
+    SuperInvoke => "OP_SUPER_INVOKE", Invoke, MinusArgs(-1);    // 父类执行指令
+    Closure => "OP_CLOSURE", Closure, Fixed(1);                 // 闭包指令
+    CloseUpvalue => "OP_CLOSE_UPVALUE", None, Fixed(-1);        // 关闭提升值
+    Return => "OP_RETURN", None, Fixed(-1);                     // 返回指令
+    Class => "OP_CLASS", Constant, Fixed(1);                    // 类指令
+    Inherit => "OP_INHERIT", None, Fixed(-1);                   // 继承指令
+    Method => "OP_METHOD", Constant, Fixed(-1);                 // 方法指令
+    BuildList => "OP_BUILD_LIST", Byte, MinusArgs(1);           // 用栈顶若干元素构造列表
+    GetIndex => "OP_GET_INDEX", None, Fixed(-1);                // 下标取值 a[i]
+    SetIndex => "OP_SET_INDEX", None, Fixed(-2);                // 下标赋值 a[i] = v
+    Import => "OP_IMPORT", Constant, Fixed(1);                  // 导入并执行模块
+    CallIntrinsic => "OP_CALL_INTRINSIC", Intrinsic, MinusArgs(0); // 调用编译器认得的原生函数
+    CallWide => "OP_CALL_WIDE", Short, MinusArgs(0);            // 超过255个参数的调用
+    InvokeWide => "OP_INVOKE_WIDE", WideInvoke, MinusArgs(0);   // 超过255个参数的方法调用
+    SuperInvokeWide => "OP_SUPER_INVOKE_WIDE", WideInvoke, MinusArgs(-1); // 超过255个参数的父类方法调用
+    CallLocal => "OP_CALL_LOCAL", Byte, MinusArgs(0);           // 调用局部变量 先试闭包
+    CallUpvalue => "OP_CALL_UPVALUE", Byte, MinusArgs(0);       // 调用提升值 先试闭包
+    PopN => "OP_POP_N", Byte, MinusArgs(0);                     // 弹出栈顶若干个值
+    CloseUpvalueN => "OP_CLOSE_UPVALUE_N", Byte, MinusArgs(0);  // 关闭栈顶若干个值上的提升值并弹出
+    BitAnd => "OP_BIT_AND", None, Fixed(-1);                    // 按位与 &
+    BitOr => "OP_BIT_OR", None, Fixed(-1);                      // 按位或 |
+    BitXor => "OP_BIT_XOR", None, Fixed(-1);                    // 按位异或 ^
+    BitNot => "OP_BIT_NOT", None, Fixed(0);                     // 按位取反 ~
+    ShiftLeft => "OP_SHIFT_LEFT", None, Fixed(-1);              // 左移 <<
+    ShiftRight => "OP_SHIFT_RIGHT", None, Fixed(-1);            // 算术右移 >>
+}
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    pub fn info(self) -> &'static OpInfo {
+        &OP_INFOS[self as usize]
+    }
+}
+
+impl From<u8> for OpCode {
+    fn from(val: u8) -> Self {
+        match OpCode::from_byte(val) {
+            Some(op) => op,
+            None => {
+                println!("Unknown opcode {}", { val });
                 panic!("Invalid Opcode.")
             }
         }
     }
 }
 
+impl Operands {
+    // 调用类指令的参数数 read(i) 读第i个操作数字节 其它指令为0
+    pub fn arg_count(self, read: impl Fn(usize) -> u8) -> usize {
+        match self {
+            Operands::Byte => read(0) as usize,
+            Operands::Invoke | Operands::Intrinsic => read(1) as usize,
+            Operands::Short => ((read(0) as usize) << 8) | read(1) as usize,
+            Operands::WideInvoke => ((read(1) as usize) << 8) | read(2) as usize,
+            _ => 0,
+        }
+    }
+}
+
+impl StackEffect {
+    // 按参数数求出实际的栈深度变化
+    pub fn resolve(self, arg_count: usize) -> i32 {
+        match self {
+            Fixed(n) => n,
+            MinusArgs(n) => n - arg_count as i32,
+        }
+    }
+}
+
+// 局部变量的调试信息 --debug-info 时由编译器生成
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,  // 栈帧中的槽位
+    pub start: usize, // 变量生效处的字节码偏移
+    pub end: usize,   // 变量失效处的字节码偏移 不含
+}
+
 pub struct Chunk {
     pub code: Vec<u8>,
     pub lines: Vec<usize>,
+    pub columns: Vec<usize>, // 每个字节对应的列号 从没有源码映射的字节码恢复时为空
+    pub file: Option<String>, // 源文件名 REPL、-e 的代码和不带源码映射的字节码没有
     pub constants: ValueArray,
+    pub locals: Vec<LocalInfo>, // 没有开启调试信息时为空
 }
 
 impl Chunk {
@@ -99,21 +179,65 @@ impl Chunk {
         Chunk {
             code: vec![],
             lines: vec![],
+            columns: vec![],
+            file: None,
             constants: ValueArray::new(),
+            locals: vec![],
         }
     }
 
-    pub fn write_chunk(&mut self, byte: u8, line: usize) {
+    // 执行到 offset 处的指令时仍然有效的局部变量 按槽位排序
+    pub fn live_locals(&self, offset: usize) -> Vec<&LocalInfo> {
+        let mut locals: Vec<_> = self
+            .locals
+            .iter()
+            .filter(|local| local.start <= offset && offset < local.end)
+            .collect();
+        locals.sort_by_key(|local| local.slot);
+        locals
+    }
+
+    pub fn write_chunk(&mut self, byte: u8, line: usize, column: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
+    }
+
+    // 丢掉 len 之后的字节码 连同行号、列号和在那里开始的局部变量
+    // 被丢掉的代码中的跳转只能指向被丢掉的部分 常量表不动
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.columns.truncate(len);
+        self.locals.retain(|local| local.start < len);
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.write_value(value);
-        return self.constants.count() - 1;
+        self.constants.count() - 1
     }
 
     pub fn count(&self) -> usize {
         self.code.len()
     }
+
+    // 根据指令元数据计算 offset 处指令的总长度(含操作数)
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        let op: OpCode = self.code[offset].into();
+        match op.info().operands {
+            Operands::None => 1,
+            Operands::Byte | Operands::Constant => 2,
+            Operands::Short
+            | Operands::Jump
+            | Operands::Loop
+            | Operands::Invoke
+            | Operands::Intrinsic => 3,
+            Operands::WideInvoke => 4,
+            Operands::Closure => {
+                let constant = self.code[offset + 1];
+                let function = as_function!(self.constants.values[constant as usize]);
+                2 + unsafe { (*function).upvalue_count } * 2
+            }
+        }
+    }
 }
@@ -0,0 +1,324 @@
+use std::time::Duration;
+
+use crate::diagnostic;
+
+// 退出码 沿用 sysexits.h 的约定
+pub const EXIT_USAGE: i32 = 64; // 命令行用法错误
+pub const EXIT_COMPILE: i32 = 65; // 编译错误
+pub const EXIT_RUNTIME: i32 = 70; // 运行时错误
+pub const EXIT_IO: i32 = 74; // 读写文件、加载插件失败
+pub const EXIT_TIMEOUT: i32 = 124; // 执行超时 同 timeout(1)
+pub const EXIT_TEST_FAILED: i32 = 1; // test 子命令有未通过的用例
+
+pub const USAGE: &str = "\
+Usage: rslox [options] [script [args...]]
+       rslox [options] -e code [args...]
+       rslox disasm <script>
+       rslox cfg <script>
+       rslox compile <script> <out.loxb>
+       rslox doc <script>
+       rslox test <dir>
+       rslox examples [name] [--source]";
+
+const HELP: &str = "
+Without a script, starts an interactive REPL.
+Arguments after the script (or after --) are passed to the script as args().
+
+Options:
+  -e, --eval <code>            Run code given on the command line
+      --check                  Compile only and report errors
+      --trace                  Trace every executed instruction
+      --watch-global <name>    Print a global whenever its value changes (repeatable)
+      --watch-local <slot>     Print a local slot of the running function whenever it changes
+      --unbuffered             Write each print to stdout immediately
+      --timeout <ms>           Stop the script with an error after <ms> milliseconds
+      --max-call-depth <n>     Report a stack overflow after <n> nested calls (default 64)
+      --dump-bytecode          Print the bytecode of each compiled function
+      --stats                  Print size, limits and an opcode histogram for each function
+      --debug-info             Record local variable names for locals() and traces
+      --deny-warnings          Treat compile warnings as errors
+      --strict                 Make assignments to undeclared globals compile errors
+      --strict-math            Make division by zero and NaN runtime errors
+      --no-prelude             Do not load the standard prelude
+      --path <dir>             Add a module search directory (repeatable)
+      --no-cache               Do not read or write the module compile cache
+      --no-source-map          Leave file names and columns out of compiled bytecode
+      --plugin <lib>           Load a native plugin (repeatable)
+      --deterministic          Use a fixed clock and random seed
+      --record <log>           Record clock and random values to a log
+      --replay <log>           Replay clock and random values from a log
+      --gc-log                 Log every garbage collection to stderr
+      --audit-log              Log calls to natives that touch files or the system
+      --time                   Report time, instructions and allocations
+      --heap-dump-on-exit <f>  Write a JSON heap dump before exiting
+      --coverage <f>           Write line coverage as lcov (JSON if <f> ends in .json)
+      --color <when>           Colored diagnostics: always, never or auto
+      --source                 With examples, print the example instead of running it
+  -h, --help                   Print this help
+  -V, --version                Print the version
+
+Subcommand doc prints Markdown for the functions, classes and methods in a
+script, using the '///' comments written right before each declaration.
+Subcommand test runs every .lox file under <dir> and checks the
+'// expect: ...' and error comments used by the craftinginterpreters test suite.
+Subcommand examples lists the bundled example programs, or runs one by name.
+
+Exit codes: 0 success, 1 failed tests, 64 usage error, 65 compile error,
+70 runtime error, 74 I/O error, 124 timeout.";
+
+pub enum Command {
+    Repl,
+    Run(String),  // 运行脚本文件
+    Eval(String), // 运行命令行给出的代码
+    Disasm(String),
+    Cfg(String),
+    Compile(String, String),
+    Doc(String),              // 从文档注释生成 Markdown
+    Test(String),             // 运行目录下的一致性测试
+    Examples(Option<String>), // 列出或运行附带的示例
+    Help,
+    Version,
+}
+
+// 解析后的命令行
+pub struct Options {
+    pub command: Command,
+    pub script_args: Vec<String>, // 传给脚本的参数
+    pub check: bool,
+    pub trace: bool,
+    pub watch_globals: Vec<String>,
+    pub watch_locals: Vec<usize>,
+    pub unbuffered: bool,
+    pub timeout: Option<Duration>,
+    pub max_call_depth: Option<usize>,
+    pub dump_bytecode: bool,
+    pub stats: bool,
+    pub debug_info: bool,
+    pub deny_warnings: bool,
+    pub strict_math: bool,
+    pub strict: bool,
+    pub no_prelude: bool,
+    pub no_cache: bool,
+    pub no_source_map: bool,
+    pub paths: Vec<String>,
+    pub plugins: Vec<String>,
+    pub deterministic: bool,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub gc_log: bool,
+    pub audit_log: bool,
+    pub time: bool,
+    pub heap_dump: Option<String>,
+    pub coverage: Option<String>,
+    pub color: Option<bool>,
+    pub source: bool, // 打印示例的源码而不运行
+}
+
+impl Options {
+    fn new() -> Options {
+        Options {
+            command: Command::Repl,
+            script_args: vec![],
+            check: false,
+            trace: false,
+            watch_globals: vec![],
+            watch_locals: vec![],
+            unbuffered: false,
+            timeout: None,
+            max_call_depth: None,
+            dump_bytecode: false,
+            stats: false,
+            debug_info: false,
+            deny_warnings: false,
+            strict_math: false,
+            strict: false,
+            no_prelude: false,
+            no_cache: false,
+            no_source_map: false,
+            paths: vec![],
+            plugins: vec![],
+            deterministic: false,
+            record: None,
+            replay: None,
+            gc_log: false,
+            audit_log: false,
+            time: false,
+            heap_dump: None,
+            coverage: None,
+            color: None,
+            source: false,
+        }
+    }
+}
+
+pub fn help() -> String {
+    format!("{}\n{}", USAGE, HELP)
+}
+
+// 解析命令行参数(不含程序名) 出错时返回错误信息
+// 选项可以写成 --name value 或 --name=value 脚本路径之后的参数都属于脚本
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::new();
+    let mut eval = None;
+    let mut positional: Vec<String> = vec![];
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positional.extend(args.by_ref());
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            let subcommand = positional.is_empty()
+                && eval.is_none()
+                && matches!(
+                    arg.as_str(),
+                    "disasm" | "cfg" | "compile" | "doc" | "test" | "examples"
+                );
+            positional.push(arg);
+            // 遇到脚本路径后不再解析选项
+            if !subcommand && positional.len() == 1 {
+                positional.extend(args.by_ref());
+                break;
+            }
+            continue;
+        }
+
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => (flag.to_string(), Some(value.into())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || -> Result<String, String> {
+            match inline.clone() {
+                Some(value) => Ok(value),
+                None => args
+                    .next()
+                    .ok_or_else(|| format!("Missing value after {}.", flag)),
+            }
+        };
+
+        match flag.as_str() {
+            "-e" | "--eval" => eval = Some(value()?),
+            "--path" => options.paths.push(value()?),
+            "--plugin" => options.plugins.push(value()?),
+            "--watch-global" => options.watch_globals.push(value()?),
+            "--watch-local" => {
+                let slot = value()?;
+                let slot = slot.parse::<usize>().map_err(|_| {
+                    format!(
+                        "Invalid value '{}' for --watch-local: expected a slot number.",
+                        slot
+                    )
+                })?;
+                options.watch_locals.push(slot);
+            }
+            "--record" => options.record = Some(value()?),
+            "--replay" => options.replay = Some(value()?),
+            "--heap-dump-on-exit" => options.heap_dump = Some(value()?),
+            "--coverage" => options.coverage = Some(value()?),
+            "--timeout" => {
+                let ms = value()?;
+                let ms = ms.parse::<u64>().map_err(|_| {
+                    format!(
+                        "Invalid value '{}' for --timeout: expected milliseconds.",
+                        ms
+                    )
+                })?;
+                options.timeout = Some(Duration::from_millis(ms));
+            }
+            "--max-call-depth" => {
+                let depth = value()?;
+                options.max_call_depth = match depth.parse::<usize>() {
+                    Ok(depth) if depth > 0 => Some(depth),
+                    _ => {
+                        return Err(format!(
+                            "Invalid value '{}' for --max-call-depth: expected a positive number.",
+                            depth
+                        ))
+                    }
+                };
+            }
+            "--color" => {
+                let when = value()?;
+                options.color = Some(diagnostic::parse_color(&when).ok_or_else(|| {
+                    format!(
+                        "Invalid value '{}' for --color: expected always, never or auto.",
+                        when
+                    )
+                })?);
+            }
+            _ => {
+                if inline.is_some() {
+                    return Err(format!("Option {} does not take a value.", flag));
+                }
+                match flag.as_str() {
+                    "-h" | "--help" => options.command = Command::Help,
+                    "-V" | "--version" => options.command = Command::Version,
+                    "--check" => options.check = true,
+                    "--trace" => options.trace = true,
+                    "--unbuffered" => options.unbuffered = true,
+                    "--dump-bytecode" => options.dump_bytecode = true,
+                    "--stats" => options.stats = true,
+                    "--debug-info" => options.debug_info = true,
+                    "--deny-warnings" => options.deny_warnings = true,
+                    "--strict-math" => options.strict_math = true,
+                    "--strict" => options.strict = true,
+                    "--no-prelude" => options.no_prelude = true,
+                    "--no-cache" => options.no_cache = true,
+                    "--no-source-map" => options.no_source_map = true,
+                    "--deterministic" => options.deterministic = true,
+                    "--gc-log" => options.gc_log = true,
+                    "--audit-log" => options.audit_log = true,
+                    "--time" => options.time = true,
+                    "--source" => options.source = true,
+                    _ => return Err(format!("Unknown option '{}'.", flag)),
+                }
+            }
+        }
+    }
+
+    if options.record.is_some() && options.replay.is_some() {
+        return Err("Options --record and --replay cannot be used together.".into());
+    }
+    if !matches!(options.command, Command::Repl) {
+        return Ok(options);
+    }
+
+    options.command = match eval {
+        Some(code) => {
+            options.script_args = positional;
+            Command::Eval(code)
+        }
+        None => match positional.first().map(String::as_str) {
+            None => Command::Repl,
+            Some("disasm") if positional.len() == 2 => Command::Disasm(positional.remove(1)),
+            Some("cfg") if positional.len() == 2 => Command::Cfg(positional.remove(1)),
+            Some("doc") if positional.len() == 2 => Command::Doc(positional.remove(1)),
+            Some("test") if positional.len() == 2 => Command::Test(positional.remove(1)),
+            Some("examples") if positional.len() <= 2 => {
+                Command::Examples(positional.get(1).cloned())
+            }
+            Some("compile") if positional.len() == 3 => {
+                Command::Compile(positional.remove(1), positional.remove(1))
+            }
+            Some("disasm" | "cfg" | "compile" | "doc" | "test" | "examples") => {
+                return Err(format!(
+                    "Wrong number of arguments for '{}'.",
+                    positional[0]
+                ));
+            }
+            Some(_) => {
+                let script = positional.remove(0);
+                options.script_args = positional;
+                Command::Run(script)
+            }
+        },
+    };
+    if options.check && !matches!(options.command, Command::Run(_) | Command::Eval(_)) {
+        return Err("Option --check needs a script or -e code.".into());
+    }
+    if options.source && !matches!(options.command, Command::Examples(Some(_))) {
+        return Err("Option --source needs an example name.".into());
+    }
+    Ok(options)
+}
@@ -1,256 +1,89 @@
-use std::ptr::null_mut;
+use std::{collections::HashSet, ptr::null_mut};
 
 use crate::{
-    chunk::{Chunk, OpCode},
+    as_string, cfg,
+    chunk::{Chunk, LocalInfo, OpCode},
+    debug,
+    diagnostic::{Diag, Severity},
+    handle::HandleScope,
+    intrinsic::Intrinsic,
     obj_val,
-    object::{Obj, ObjFunction, ObjString},
-    scanner::{Token, TokenType},
+    object::{Obj, ObjFunction, ObjString, ObjType},
+    scanner::{Scanner, Token, TokenType},
     value::Value,
-    vm::{vm, UINT8_COUNT},
+    vm::{is_falsey, vm, UINT8_COUNT},
 };
 
-static RULES: [ParseRule; 40] = [
-    ParseRule {
-        _token: "(",
-        prefix: Some(Compiler::grouping),
-        infix: Some(Compiler::call),
-        precedence: Precedence::Call,
-    },
-    ParseRule {
-        _token: ")",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "{",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "}",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: ",",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: ".",
-        prefix: None,
-        infix: Some(Compiler::dot),
-        precedence: Precedence::Call,
-    },
-    ParseRule {
-        _token: "-",
-        prefix: Some(Compiler::unary),
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Term,
-    },
-    ParseRule {
-        _token: "+",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Term,
-    },
-    ParseRule {
-        _token: ";",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "/",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Factor,
-    },
-    ParseRule {
-        _token: "*",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Factor,
-    },
-    ParseRule {
-        _token: "!",
-        prefix: Some(Compiler::unary),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "!=",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Equality,
-    },
-    ParseRule {
-        _token: "=",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "==",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Equality,
-    },
-    ParseRule {
-        _token: ">",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Comparison,
-    },
-    ParseRule {
-        _token: ">=",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Comparison,
-    },
-    ParseRule {
-        _token: "<",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Comparison,
-    },
-    ParseRule {
-        _token: "<=",
-        prefix: None,
-        infix: Some(Compiler::binary),
-        precedence: Precedence::Comparison,
-    },
-    ParseRule {
-        _token: "IDENTIFIER",
-        prefix: Some(Compiler::variable),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "STRING",
-        prefix: Some(Compiler::string),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "NUMBER",
-        prefix: Some(Compiler::number),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "and",
-        prefix: None,
-        infix: Some(Compiler::and),
-        precedence: Precedence::And,
-    },
-    ParseRule {
-        _token: "class",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "else",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "false",
-        prefix: Some(Compiler::literal),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "for",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "fun",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "if",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "nil",
-        prefix: Some(Compiler::literal),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "or",
-        prefix: None,
-        infix: Some(Compiler::or),
-        precedence: Precedence::Or,
-    },
-    ParseRule {
-        _token: "print",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "return",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "super",
-        prefix: Some(Compiler::super_),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "this",
-        prefix: Some(Compiler::this),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "true",
-        prefix: Some(Compiler::literal),
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "var",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "while",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "ERROR",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-    ParseRule {
-        _token: "EOF",
-        prefix: None,
-        infix: None,
-        precedence: Precedence::None,
-    },
-];
+// 语句和表达式允许的最大嵌套深度
+const MAX_NESTING: usize = 200;
+
+// 每种记号的解析规则: 前缀解析函数、中缀解析函数和中缀优先级 _ 表示没有
+// 生成对 TokenType 的穷尽 match 新增记号时漏写规则无法编译 也不依赖记号的声明顺序
+macro_rules! parse_rules {
+    (@parse_fn _) => {
+        None
+    };
+    (@parse_fn $function:ident) => {
+        Some(CompilerCtx::$function)
+    };
+    (
+        none: $($none:ident),*;
+        $($token:ident => $prefix:tt, $infix:tt, $precedence:ident;)*
+    ) => {
+        fn get_rule(type_: TokenType) -> ParseRule {
+            match type_ {
+                $(TokenType::$token => ParseRule {
+                    prefix: parse_rules!(@parse_fn $prefix),
+                    infix: parse_rules!(@parse_fn $infix),
+                    precedence: Precedence::$precedence,
+                },)*
+                $(TokenType::$none)|* => ParseRule {
+                    prefix: None,
+                    infix: None,
+                    precedence: Precedence::None,
+                },
+            }
+        }
+    };
+}
+
+parse_rules! {
+    // 不参与表达式解析的记号
+    none:
+        RightParen, LeftBrace, RightBrace, RightBracket, Comma, Semicolon, Equal, Colon, Class,
+        Else, For, Fun, If, Import, Print, Return, Var, While, Error, Eof;
+    LeftParen => grouping, call, Call;
+    LeftBracket => list, subscript, Call;
+    Dot => _, dot, Call;
+    Minus => unary, binary, Term;
+    Plus => _, binary, Term;
+    Slash => _, binary, Factor;
+    Star => _, binary, Factor;
+    Bang => unary, _, None;
+    BangEqual => _, binary, Equality;
+    EqualEqual => _, binary, Equality;
+    Greater => _, binary, Comparison;
+    GreaterEqual => _, binary, Comparison;
+    Less => _, binary, Comparison;
+    LessEqual => _, binary, Comparison;
+    Ampersand => _, binary, BitAnd;
+    Pipe => _, binary, BitOr;
+    Caret => _, binary, BitXor;
+    Tilde => unary, _, None;
+    LessLess => _, binary, Shift;
+    GreaterGreater => _, binary, Shift;
+    Question => _, conditional, Conditional;
+    Identifier => variable, _, None;
+    String => string, _, None;
+    Number => number, _, None;
+    And => _, and, And;
+    False => literal, _, None;
+    Nil => literal, _, None;
+    Or => _, or, Or;
+    Super => super_, _, None;
+    This => this, _, None;
+    True => literal, _, None;
+}
 
 #[derive(PartialEq, Eq, Clone, Copy)]
 // 函数类型
@@ -266,6 +99,18 @@ struct Local {
     name: Token,       // 变量名
     depth: i32,        // 作用域深度
     is_captured: bool, // 是否被捕获
+    is_read: bool,     // 是否被读取过
+}
+
+impl Local {
+    fn new() -> Local {
+        Local {
+            name: Token::default(),
+            depth: 0,
+            is_captured: false,
+            is_read: false,
+        }
+    }
 }
 
 // 提升值
@@ -275,24 +120,38 @@ struct Upvalue {
     is_local: bool, // 是否为局部变量
 }
 
+impl Upvalue {
+    fn new() -> Upvalue {
+        Upvalue {
+            index: 0,
+            is_local: false,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum Precedence {
     None = 0,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,  // =
+    Conditional, // ?:
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
+    Shift,       // << >>
+    Term,        // + -
+    Factor,      // * /
+    Unary,       // ! - ~
+    Call,        // . ()
     Primary,
 }
 
-impl Into<i32> for Precedence {
-    fn into(self) -> i32 {
-        self as i32
+impl From<Precedence> for i32 {
+    fn from(val: Precedence) -> Self {
+        val as i32
     }
 }
 
@@ -301,33 +160,36 @@ impl From<i32> for Precedence {
         match value {
             0 => Precedence::None,
             1 => Precedence::Assignment,
-            2 => Precedence::Or,
-            3 => Precedence::And,
-            4 => Precedence::Equality,
-            5 => Precedence::Comparison,
-            6 => Precedence::Term,
-            7 => Precedence::Factor,
-            8 => Precedence::Unary,
-            9 => Precedence::Call,
-            10 => Precedence::Primary,
-            _ => panic!("error Precedence!"),
+            2 => Precedence::Conditional,
+            3 => Precedence::Or,
+            4 => Precedence::And,
+            5 => Precedence::Equality,
+            6 => Precedence::Comparison,
+            7 => Precedence::BitOr,
+            8 => Precedence::BitXor,
+            9 => Precedence::BitAnd,
+            10 => Precedence::Shift,
+            11 => Precedence::Term,
+            12 => Precedence::Factor,
+            13 => Precedence::Unary,
+            14 => Precedence::Call,
+            _ => Precedence::Primary,
         }
     }
 }
 
 // 声明返回值为 void 函数指针 ParseFn
-type ParseFn = fn(&mut Compiler, bool) -> ();
+type ParseFn = fn(&mut CompilerCtx, bool) -> ();
 
 // 解析规则
 struct ParseRule {
-    _token: &'static str,
     prefix: Option<ParseFn>, // 前缀
     infix: Option<ParseFn>,  // 中缀
     precedence: Precedence,  // 优先级
 }
 
 // 类编译器
-pub struct ClassCompiler {
+struct ClassCompiler {
     enclosing: *mut ClassCompiler, // 上一个类编译器
     has_superclass: bool,          // 是否存在父类
 }
@@ -341,22 +203,42 @@ impl ClassCompiler {
     }
 }
 
+// 调用表达式中被调用的变量
+#[derive(Clone, Copy)]
+enum Callee {
+    Intrinsic(Intrinsic), // 名字是内联函数的全局变量
+    Local,                // 局部变量 多半是闭包
+    Upvalue,              // 提升值 多半是闭包 如递归的局部函数
+}
+
 pub struct Compiler {
     pub enclosing: *mut Compiler,   // 上一个编译器 用来还原current
     pub function: *mut ObjFunction, // 当前编译函数对象
-    type_: FunctionType,        // 当前函数类型
+    type_: FunctionType,            // 当前函数类型
 
     locals: Vec<Local>,     // 局部变量数组
     local_count: usize,     // 局部变量数量
+    max_locals: usize,      // 同时存在的局部变量最多有几个 含槽位0
     upvalues: Vec<Upvalue>, // 提升值数组
     scope_depth: usize,     // 局部变量作用域深度
+
+    callee: Option<Callee>, // 紧接着要调用的变量 call 据此选择专用的调用指令
 }
 
 pub struct Parser {
     current: Token,
     previous: Token,
     pub had_error: bool,
+    pub had_warning: bool,
     pub panic_mode: bool,
+
+    pub global_declarations: Vec<(Token, &'static str)>, // 全局函数和类的声明及种类
+    pub global_reads: HashSet<String>,                   // 被读取过的全局变量名
+    pub global_defines: HashSet<String>,                 // 脚本中用 var fun class 定义的全局变量名
+    pub global_writes: Vec<(Token, *mut ObjString)>,     // 给全局变量赋值的位置
+    pub has_import: bool,                                // 导入的模块也可能定义全局变量
+    depth: usize,                                        // 语句和表达式的嵌套深度
+    pub file: Option<String>,                            // 正在编译的源文件 记在每个函数的块中
 }
 
 impl Parser {
@@ -365,81 +247,155 @@ impl Parser {
             current: Token::default(),
             previous: Token::default(),
             had_error: false,
+            had_warning: false,
             panic_mode: false,
+            global_declarations: vec![],
+            global_reads: HashSet::new(),
+            global_defines: HashSet::new(),
+            global_writes: vec![],
+            has_import: false,
+            depth: 0,
+            file: None,
         }
     }
 }
 
-fn check(type_: TokenType) -> bool {
-    vm().parser.current.type_ == type_
+// 一次编译的全部状态 扫描器、解析器、嵌套函数的编译器链和类编译器链
+// 不读写虚拟机的全局状态 只通过虚拟机分配对象 编译期间登记为GC根
+pub struct CompilerCtx {
+    scanner: Scanner,
+    pub parser: Parser,
+    pub current: *mut Compiler,         // 正在编译的最内层函数
+    class_compiler: *mut ClassCompiler, // 正在编译的最内层类
+    pub diagnostics: Vec<Diag>,         // 错误和警告 按出现的顺序
+
+    pub print_code: bool,          // 编译结束时打印字节码
+    pub print_stats: bool,         // 编译结束时打印字节码块的统计
+    pub deny_warnings: bool,       // 把编译警告视为错误
+    pub strict_globals: bool,      // 给未声明的全局变量赋值报错而不是警告
+    pub warn_unused_globals: bool, // 检查未使用的顶层函数和类
+    pub debug_info: bool,          // 生成局部变量的调试信息
+
+    // REPL 会话中已有的顶层局部变量 按槽位顺序 编译后加上本次输入新声明的 不是 REPL 时为 None
+    pub repl_locals: Option<Vec<String>>,
 }
 
-fn current_chunk() -> &'static mut Chunk {
-    unsafe { &mut (*(*vm().current_compiler).function).chunk }
-}
+impl CompilerCtx {
+    pub fn new(source: String, file: Option<&str>) -> CompilerCtx {
+        let mut parser = Parser::new();
+        parser.file = file.map(String::from);
+        CompilerCtx {
+            scanner: Scanner::new(source),
+            parser,
+            current: null_mut(),
+            class_compiler: null_mut(),
+            diagnostics: vec![],
+            print_code: false,
+            print_stats: false,
+            deny_warnings: false,
+            strict_globals: false,
+            warn_unused_globals: true,
+            debug_info: false,
+            repl_locals: None,
+        }
+    }
 
-fn current() -> &'static mut Compiler {
-    unsafe { &mut (*vm().current_compiler) }
-}
+    pub fn source(&self) -> &str {
+        &self.scanner.source
+    }
 
-fn identifiers_equal(a: &Token, b: &Token) -> bool {
-    if a.length != b.length {
-        return false;
+    fn check(&self, type_: TokenType) -> bool {
+        self.parser.current.type_ == type_
     }
-    a.message == b.message
-}
 
-fn mark_initialized() {
-    // 全局函数声明时没必要标记
-    if current().scope_depth == 0 {
-        return;
+    fn current_chunk(&self) -> &'static mut Chunk {
+        unsafe { &mut (*(*self.current).function).chunk }
+    }
+
+    fn current(&self) -> &'static mut Compiler {
+        unsafe { &mut (*self.current) }
+    }
+
+    fn mark_initialized(&mut self) {
+        // 全局函数声明时没必要标记
+        if self.current().scope_depth == 0 {
+            return;
+        }
+        self.current().locals[self.current().local_count - 1].depth =
+            self.current().scope_depth as i32;
+        self.open_local_info(self.current().local_count - 1);
+    }
+
+    // 变量从初始化之后生效 调试信息中记下生效位置
+    fn open_local_info(&mut self, slot: usize) {
+        if self.debug_info {
+            let name = self.current().locals[slot].name.lexeme.clone();
+            let start = self.current_chunk().code.len();
+            self.current_chunk().locals.push(LocalInfo {
+                name,
+                slot,
+                start,
+                end: usize::MAX,
+            });
+        }
+    }
+
+    // 局部变量离开作用域 补上调试信息中的失效位置
+    fn close_local_info(&mut self, slot: usize) {
+        let end = self.current_chunk().code.len();
+        if let Some(local) = self
+            .current_chunk()
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|local| local.slot == slot && local.end == usize::MAX)
+        {
+            local.end = end;
+        }
     }
-    current().locals[current().local_count - 1].depth = current().scope_depth as i32;
+}
+
+fn identifiers_equal(a: &Token, b: &Token) -> bool {
+    a.lexeme == b.lexeme
 }
 
 // 同步token
 fn synthetic_token(text: &str) -> Token {
     let mut token = Token::default();
-    token.message = text.into();
+    token.lexeme = text.into();
     token.length = text.len();
     token
 }
 
-fn get_rule(type_: TokenType) -> &'static ParseRule {
-    &RULES[type_ as usize]
-}
-
 impl Compiler {
-    pub fn new(type_: FunctionType) -> Compiler {
-        let mut compiler = Compiler {
-            enclosing: vm().current_compiler,
+    // 编译器放在堆上 保证 ctx.current 指向的地址稳定
+    fn new(ctx: &mut CompilerCtx, type_: FunctionType) -> Box<Compiler> {
+        let mut compiler = Box::new(Compiler {
+            enclosing: ctx.current,
             function: ObjFunction::new(),
             type_,
-            locals: Vec::with_capacity(UINT8_COUNT),
+            locals: (0..UINT8_COUNT).map(|_| Local::new()).collect(),
             local_count: 0,
-            upvalues: Vec::with_capacity(UINT8_COUNT),
+            max_locals: 1,
+            upvalues: vec![Upvalue::new(); UINT8_COUNT],
             scope_depth: 0,
-        };
+            callee: None,
+        });
 
-        vm().current_compiler = &mut compiler as *mut Compiler;
+        ctx.current = &mut *compiler as *mut Compiler;
+        unsafe { (*compiler.function).chunk.file = ctx.parser.file.clone() };
 
         if type_ != FunctionType::Script {
-            let start = vm().parser.previous.start;
-            let length = vm().parser.previous.length;
+            let start = ctx.parser.previous.start;
+            let name = ctx.parser.previous.lexeme.clone();
             unsafe {
-                (*compiler.function).name = ObjString::take_string(
-                    String::from_utf8(
-                        vm().scanner.as_ref().unwrap().source.as_bytes()[start..start + length]
-                            .to_vec(),
-                    )
-                    .unwrap(),
-                );
+                (*compiler.function).span.start = start;
+                (*compiler.function).name = ObjString::take_string(name);
             }
         }
 
         // 局部插槽将空字符串占用 无法显式使用
-        let local = &mut compiler.locals[compiler.local_count];
-        compiler.local_count += 1;
+        let local = &mut compiler.locals[0];
         local.depth = 0;
         local.is_captured = false;
 
@@ -450,28 +406,35 @@ impl Compiler {
             _ => {
                 local.name.start = 0;
                 local.name.length = 4;
-                local.name.message = "this".into();
+                local.name.lexeme = "this".into();
             }
         }
+        compiler.local_count += 1;
         compiler
     }
+}
 
+impl CompilerCtx {
     fn advance(&mut self) {
-        vm().parser.previous = vm().parser.current.clone();
+        self.parser.previous = self.parser.current.clone();
 
         loop {
-            vm().parser.current = vm().scanner.as_mut().unwrap().scan_token();
-            if let TokenType::Error = vm().parser.current.type_ {
+            // 取走 Eof 之后记号流结束 当前记号一直停在 Eof 上
+            self.parser.current = match self.scanner.next() {
+                Some(token) => token,
+                None => self.parser.previous.clone(),
+            };
+            if let TokenType::Error = self.parser.current.type_ {
             } else {
                 break;
             }
 
-            self.error_at_current(&vm().parser.current.message);
+            self.error_at_current(&self.parser.current.lexeme.clone());
         }
     }
 
     fn match_(&mut self, type_: TokenType) -> bool {
-        if !check(type_) {
+        if !self.check(type_) {
             return false;
         }
         self.advance();
@@ -490,19 +453,24 @@ impl Compiler {
         }
 
         // 如果处于异常模式  则同步掉异常继续编译
-        if vm().parser.panic_mode {
+        if self.parser.panic_mode {
             self.synchronize();
         }
     }
 
     // 语句
     fn statement(&mut self) {
+        if !self.enter_nesting() {
+            return;
+        }
         if self.match_(TokenType::Print) {
             self.print_statement();
         } else if self.match_(TokenType::For) {
             self.for_statement();
         } else if self.match_(TokenType::If) {
             self.if_statement();
+        } else if self.match_(TokenType::Import) {
+            self.import_statement();
         } else if self.match_(TokenType::Return) {
             self.return_statement();
         } else if self.match_(TokenType::While) {
@@ -514,6 +482,19 @@ impl Compiler {
         } else {
             self.expression_statement();
         }
+        self.parser.depth -= 1;
+    }
+
+    // 导入语句 import "name"; 执行一次对应模块 模块的全局定义与当前程序共享
+    fn import_statement(&mut self) {
+        self.parser.has_import = true;
+        self.consume(TokenType::String, "Expect module name after 'import'.");
+        let name = self.parser.previous.string_value().to_string();
+        let constant = self.make_constant(obj_val!(ObjString::take_string(name)));
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+
+        self.emit_bytes(OpCode::Import as u8, constant);
+        self.emit_byte(OpCode::Pop as u8);
     }
 
     // 表达式语句
@@ -523,14 +504,52 @@ impl Compiler {
         self.emit_byte(OpCode::Pop as u8);
     }
 
+    // 条件只编译成一条字面量或常量指令时去掉这条指令 返回它的真假
+    // 全局变量可能被重新赋值 不算常量
+    fn take_constant_condition(&mut self, start: usize) -> Option<bool> {
+        let chunk = self.current_chunk();
+        let condition = match chunk.code[start..] {
+            [op] if op == OpCode::True as u8 => true,
+            [op] if op == OpCode::False as u8 || op == OpCode::Nil as u8 => false,
+            [op, constant] if op == OpCode::Constant as u8 => {
+                !is_falsey(chunk.constants.values[constant as usize])
+            }
+            _ => return None,
+        };
+        chunk.truncate(start);
+        Some(condition)
+    }
+
+    // 编译一段代码 不会执行时只检查语法和作用域 生成的字节码丢掉
+    fn compile_unless_dead(&mut self, live: bool, compile: impl FnOnce(&mut CompilerCtx)) {
+        let start = self.current_chunk().count();
+        compile(self);
+        if !live {
+            self.current_chunk().truncate(start);
+        }
+    }
+
     // while 语句
     fn while_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
         // 循环起点
-        let loop_start = current_chunk().count();
+        let loop_start = self.current_chunk().count();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
+        // 条件是常量时不生成条件跳转 为假时整个循环都不生成
+        if let Some(condition) = self.take_constant_condition(loop_start) {
+            if !condition {
+                self.warning_at(&keyword, "Loop body is never executed.");
+            }
+            self.compile_unless_dead(condition, |ctx| {
+                ctx.statement();
+                ctx.emit_loop(loop_start as i32);
+            });
+            return;
+        }
+
         // 如果为false直接跳到下面的pop
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
         self.emit_byte(OpCode::Pop as u8);
@@ -545,14 +564,14 @@ impl Compiler {
 
     // 返回语句
     fn return_statement(&mut self) {
-        if current().type_ == FunctionType::Script {
+        if self.current().type_ == FunctionType::Script {
             self.error("Can't return from top-level code.");
         }
 
         if self.match_(TokenType::Semicolon) {
             self.emit_return();
         } else {
-            if current().type_ == FunctionType::Initializer {
+            if self.current().type_ == FunctionType::Initializer {
                 self.error("Can't return a value from an initializer.");
             }
 
@@ -565,9 +584,19 @@ impl Compiler {
     // if 语句
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let start = self.current_chunk().count();
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
+        // 条件是常量时只生成会执行的分支 不需要跳转
+        if let Some(condition) = self.take_constant_condition(start) {
+            self.compile_unless_dead(condition, |ctx| ctx.statement());
+            if self.match_(TokenType::Else) {
+                self.compile_unless_dead(!condition, |ctx| ctx.statement());
+            }
+            return;
+        }
+
         // then 分支跳转点
         let then_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
         // 如果为false 这个 pop不会被执行  会执行下面的pop
@@ -604,22 +633,29 @@ impl Compiler {
             self.expression_statement();
         }
         // 循环起点
-        let mut loop_start = current_chunk().count() as i32;
-        // for的第二语句  表达式语句
+        let mut loop_start = self.current_chunk().count() as i32;
+        let body_start = loop_start as usize;
+        // for的第二语句  表达式语句 常量条件为真时和省略一样 为假时只保留初始化语句
         let mut exit_jump = -1;
+        let mut live = true;
         if !self.match_(TokenType::Semicolon) {
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
 
-            // Jump out of the loop if the condition is false.
-            exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8) as i32;
-            self.emit_byte(OpCode::Pop as u8); // Condition.
+            match self.take_constant_condition(body_start) {
+                Some(condition) => live = condition,
+                None => {
+                    // Jump out of the loop if the condition is false.
+                    exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8) as i32;
+                    self.emit_byte(OpCode::Pop as u8); // Condition.
+                }
+            }
         }
 
         // for的第三语句 增量子句
         if !self.match_(TokenType::RightParen) {
             let body_jump = self.emit_jump(OpCode::Jump as u8);
-            let increment_start = current_chunk().count() as i32;
+            let increment_start = self.current_chunk().count() as i32;
             self.expression();
             self.emit_byte(OpCode::Pop as u8);
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
@@ -638,6 +674,9 @@ impl Compiler {
             self.patch_jump(exit_jump as usize);
             self.emit_byte(OpCode::Pop as u8);
         }
+        if !live {
+            self.current_chunk().truncate(body_start);
+        }
 
         self.end_scope();
     }
@@ -646,7 +685,7 @@ impl Compiler {
     fn emit_loop(&mut self, loop_start: i32) {
         self.emit_byte(OpCode::Loop as u8);
 
-        let offset = (current_chunk().count() - loop_start as usize) + 2;
+        let offset = (self.current_chunk().count() - loop_start as usize) + 2;
         if offset > u16::MAX as usize {
             self.error("Loop body too large.");
         }
@@ -672,21 +711,80 @@ impl Compiler {
     }
 
     fn call(&mut self, _can_assign: bool) {
+        let callee = self.current().callee.take();
         let arg_count = self.argument_list();
-        self.emit_bytes(OpCode::Call as u8, arg_count);
+        match callee {
+            Some(Callee::Intrinsic(intrinsic)) if intrinsic.arity() as usize == arg_count => {
+                self.emit_bytes(OpCode::CallIntrinsic as u8, intrinsic as u8);
+                self.emit_byte(arg_count as u8);
+            }
+            _ if arg_count > u8::MAX as usize => {
+                self.emit_byte(OpCode::CallWide as u8);
+                self.emit_short(arg_count);
+            }
+            Some(Callee::Local) => self.emit_bytes(OpCode::CallLocal as u8, arg_count as u8),
+            Some(Callee::Upvalue) => self.emit_bytes(OpCode::CallUpvalue as u8, arg_count as u8),
+            _ => self.emit_bytes(OpCode::Call as u8, arg_count as u8),
+        }
+    }
+
+    // 方法调用 参数多于255个时用两字节参数数的指令
+    fn emit_invoke(&mut self, op: OpCode, wide: OpCode, name: u8, arg_count: usize) {
+        if arg_count > u8::MAX as usize {
+            self.emit_bytes(wide as u8, name);
+            self.emit_short(arg_count);
+        } else {
+            self.emit_bytes(op as u8, name);
+            self.emit_byte(arg_count as u8);
+        }
+    }
+
+    fn emit_short(&mut self, value: usize) {
+        self.emit_bytes((value >> 8) as u8, value as u8);
+    }
+
+    // 列表字面量 [a, b, c]
+    fn list(&mut self, _can_assign: bool) {
+        let mut item_count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if item_count == 255 {
+                    self.error("Can't have more than 255 items in a list literal.");
+                }
+                item_count += 1;
+                if !self.match_(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(OpCode::BuildList as u8, item_count as u8);
+    }
+
+    // 下标访问 a[i] 或下标赋值 a[i] = v
+    fn subscript(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SetIndex as u8);
+        } else {
+            self.emit_byte(OpCode::GetIndex as u8);
+        }
     }
 
     fn dot(&mut self, can_assign: bool) {
         self.consume(TokenType::Identifier, "Expect property name after '.'.");
-        let name = self.identifier_constant(&vm().parser.previous);
+        let name = self.identifier_constant(&self.parser.previous.clone());
 
         if can_assign && self.match_(TokenType::Equal) {
             self.expression();
             self.emit_bytes(OpCode::SetProperty as u8, name);
         } else if self.match_(TokenType::LeftParen) {
             let arg_count = self.argument_list();
-            self.emit_bytes(OpCode::Invoke as u8, name);
-            self.emit_byte(arg_count);
+            self.emit_invoke(OpCode::Invoke, OpCode::InvokeWide, name, arg_count);
         } else {
             self.emit_bytes(OpCode::GetProperty as u8, name);
         }
@@ -694,7 +792,7 @@ impl Compiler {
 
     // 一元表达式
     fn unary(&mut self, _can_assign: bool) {
-        let operator_type = vm().parser.previous.type_;
+        let operator_type = self.parser.previous.type_;
 
         // Compile the operand.
         self.parse_precedence(Precedence::Unary);
@@ -703,13 +801,14 @@ impl Compiler {
         match operator_type {
             TokenType::Bang => self.emit_byte(OpCode::Not as u8),
             TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
-            _ => return, // Unreachable.
+            TokenType::Tilde => self.emit_byte(OpCode::BitNot as u8),
+            _ => (), // Unreachable.
         }
     }
 
     // 二元表达式
     fn binary(&mut self, _can_assign: bool) {
-        let operator_type = vm().parser.previous.type_;
+        let operator_type = self.parser.previous.type_;
         let rule = get_rule(operator_type);
         self.parse_precedence((rule.precedence as i32 + 1).into());
 
@@ -724,26 +823,34 @@ impl Compiler {
             TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
             TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
             TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
-            _ => return, // Unreachable.
+            TokenType::Ampersand => self.emit_byte(OpCode::BitAnd as u8),
+            TokenType::Pipe => self.emit_byte(OpCode::BitOr as u8),
+            TokenType::Caret => self.emit_byte(OpCode::BitXor as u8),
+            TokenType::LessLess => self.emit_byte(OpCode::ShiftLeft as u8),
+            TokenType::GreaterGreater => self.emit_byte(OpCode::ShiftRight as u8),
+            _ => (), // Unreachable.
         }
     }
 
     // 标识符表达式
     fn variable(&mut self, can_assign: bool) {
-        self.named_variable(&vm().parser.previous, can_assign);
+        self.named_variable(&self.parser.previous.clone(), can_assign);
     }
 
     // 字符串表达式
     fn string(&mut self, _can_assign: bool) {
         self.emit_constant(obj_val!(ObjString::take_string(
-            vm().parser.previous.message.clone()
+            self.parser.previous.string_value().into()
         )));
     }
 
     // 数字表达式
     fn number(&mut self, _can_assign: bool) {
-        let value = vm().parser.previous.message.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(value));
+        match self.parser.previous.lexeme.parse::<f64>() {
+            Ok(value) if value.is_finite() => self.emit_constant(Value::Number(value)),
+            Ok(_) => self.error("Number literal is too large."),
+            Err(_) => self.error("Invalid number literal."),
+        }
     }
 
     // 逻辑与
@@ -758,11 +865,11 @@ impl Compiler {
 
     // 字符表达式
     fn literal(&mut self, _can_assign: bool) {
-        match vm().parser.previous.type_ {
+        match self.parser.previous.type_ {
             TokenType::False => self.emit_byte(OpCode::False as u8),
             TokenType::Nil => self.emit_byte(OpCode::Nil as u8),
             TokenType::True => self.emit_byte(OpCode::True as u8),
-            _ => return, // Unreachable.
+            _ => (), // Unreachable.
         }
     }
 
@@ -778,24 +885,45 @@ impl Compiler {
         self.patch_jump(end_jump);
     }
 
+    // 条件表达式 cond ? a : b 右结合 a ? b : c ? d : e 即 a ? b : (c ? d : e)
+    fn conditional(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::Conditional);
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+
+        let end_jump = self.emit_jump(OpCode::Jump as u8);
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(end_jump);
+    }
+
     // 父类
     fn super_(&mut self, _can_assign: bool) {
-        if vm().class_compiler.is_null() {
+        if self.class_compiler.is_null() {
             self.error("Can't use 'super' outside of a class.");
-        } else if !unsafe { (*vm().class_compiler).has_superclass } {
+        } else if !unsafe { (*self.class_compiler).has_superclass } {
             self.error("Can't use 'super' in a class with no superclass.");
         }
 
         self.consume(TokenType::Dot, "Expect '.' after 'super'.");
         self.consume(TokenType::Identifier, "Expect superclass method name.");
-        let name = self.identifier_constant(&vm().parser.previous);
+        let name = self.identifier_constant(&self.parser.previous.clone());
 
         self.named_variable(&synthetic_token("this"), false);
         if self.match_(TokenType::LeftParen) {
             let arg_count = self.argument_list();
             self.named_variable(&synthetic_token("super"), false);
-            self.emit_bytes(OpCode::SuperInvoke as u8, name);
-            self.emit_byte(arg_count);
+            self.emit_invoke(
+                OpCode::SuperInvoke,
+                OpCode::SuperInvokeWide,
+                name,
+                arg_count,
+            );
         } else {
             self.named_variable(&synthetic_token("super"), false);
             self.emit_bytes(OpCode::GetSuper as u8, name);
@@ -804,7 +932,7 @@ impl Compiler {
 
     // this局部变量
     fn this(&mut self, _can_assign: bool) {
-        if vm().class_compiler.is_null() {
+        if self.class_compiler.is_null() {
             self.error("Can't use 'this' outside of a class.");
             return;
         }
@@ -817,37 +945,55 @@ impl Compiler {
         self.emit_bytes(OpCode::Constant as u8, b);
     }
 
+    // 进入一层嵌套 过深时报错并跳过一个token 防止递归下降耗尽栈空间
+    // 返回true时调用方结束后要把深度减回去
+    fn enter_nesting(&mut self) -> bool {
+        if self.parser.depth >= MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            self.advance();
+            return false;
+        }
+        self.parser.depth += 1;
+        true
+    }
+
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.enter_nesting() {
+            return;
+        }
         self.advance();
         // 获取上一格token的前缀表达式 为null的话错误
-        let prefix_rule = get_rule(vm().parser.previous.type_).prefix;
-        if let None = prefix_rule {
+        let Some(prefix_rule) = get_rule(self.parser.previous.type_).prefix else {
             self.error("Expect expression.");
+            self.parser.depth -= 1;
             return;
-        }
+        };
         // 执行前缀表达式  传入等号的优先级表示是否能赋值
         let can_assign = precedence as u8 <= Precedence::Assignment as u8;
-        prefix_rule.unwrap()(self, can_assign);
+        prefix_rule(self, can_assign);
         // 获取当前token优先级 比较传递进的优先级 传递小于等于当前的话 执行中缀表达式
-        while precedence as u8 <= get_rule(vm().parser.current.type_).precedence as u8 {
+        while precedence as u8 <= get_rule(self.parser.current.type_).precedence as u8 {
             self.advance();
-            let infix_rule = get_rule(vm().parser.previous.type_).infix;
-            infix_rule.unwrap()(self, can_assign);
+            if let Some(infix_rule) = get_rule(self.parser.previous.type_).infix {
+                infix_rule(self, can_assign);
+            }
         }
 
         // 可以赋值且后接等号
         if can_assign && self.match_(TokenType::Equal) {
             self.error("Invalid assignment target.");
         }
+        self.parser.depth -= 1;
     }
 
-    fn argument_list(&mut self) -> u8 {
+    // 参数数超过255时调用方改用两字节参数数的指令
+    fn argument_list(&mut self) -> usize {
         let mut arg_count = 0;
-        if !check(TokenType::RightParen) {
+        if !self.check(TokenType::RightParen) {
             loop {
                 self.expression();
-                if arg_count == 255 {
-                    self.error("Can't have more than 255 arguments.")
+                if arg_count == u16::MAX as usize {
+                    self.error("Can't have more than 65535 arguments.")
                 }
                 arg_count += 1;
                 if !self.match_(TokenType::Comma) {
@@ -860,8 +1006,25 @@ impl Compiler {
     }
 
     fn var_declaration(&mut self) {
+        // REPL 顶层的 var 声明为会话的局部变量 算出初始值后才加入 初始值中可以使用同名的旧变量
+        if self.repl_locals.is_some()
+            && self.current().type_ == FunctionType::Script
+            && self.current().scope_depth == 0
+        {
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            let name = self.parser.previous.clone();
+            self.var_initializer();
+            self.add_repl_local(&name);
+            return;
+        }
+
         let global = self.parse_variable("Expect variable name.");
+        self.var_initializer();
+        self.define_variable(global);
+    }
 
+    // 变量的初始值 没有时为 nil
+    fn var_initializer(&mut self) {
         if self.match_(TokenType::Equal) {
             self.expression();
         } else {
@@ -871,38 +1034,48 @@ impl Compiler {
             TokenType::Semicolon,
             "Expect ';' after variable declaration.",
         );
+    }
 
-        self.define_variable(global);
+    // REPL 会话的局部变量 作用域深度为0 不会被弹出 一直有效到会话结束
+    fn add_repl_local(&mut self, name: &Token) {
+        let count = self.current().local_count;
+        self.add_local(name);
+        if self.current().local_count > count {
+            self.current().locals[count].depth = 0;
+            self.open_local_info(count);
+        }
     }
 
     // 函数声明
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
-        mark_initialized();
+        self.declare_global("Function");
+        self.mark_initialized();
         self.function(FunctionType::Function);
         self.define_variable(global);
     }
 
     fn class_declaration(&mut self) {
         self.consume(TokenType::Identifier, "Expect class name.");
-        let class_name = vm().parser.previous.clone();
-        let name_constant = self.identifier_constant(&vm().parser.previous);
+        let class_name = self.parser.previous.clone();
+        let name_constant = self.identifier_constant(&self.parser.previous.clone());
         self.declare_variable();
+        self.declare_global("Class");
 
         self.emit_bytes(OpCode::Class as u8, name_constant);
         self.define_variable(name_constant);
 
         let mut class_compiler = ClassCompiler::new();
         class_compiler.has_superclass = false;
-        class_compiler.enclosing = vm().class_compiler;
-        vm().class_compiler = &mut class_compiler as *mut ClassCompiler;
+        class_compiler.enclosing = self.class_compiler;
+        self.class_compiler = &mut class_compiler as *mut ClassCompiler;
 
         // 继承
         if self.match_(TokenType::Less) {
             self.consume(TokenType::Identifier, "Expect superclass name.");
             self.variable(false);
 
-            if identifiers_equal(&class_name, &vm().parser.previous) {
+            if identifiers_equal(&class_name, &self.parser.previous) {
                 self.error("A class can't inherit from itself.");
             }
 
@@ -910,14 +1083,14 @@ impl Compiler {
             self.add_local(&synthetic_token("super"));
             self.define_variable(0);
 
-            self.named_variable(&class_name, false);
+            self.load_variable(&class_name, false, false);
             self.emit_byte(OpCode::Inherit as u8);
             class_compiler.has_superclass = true;
         }
 
-        self.named_variable(&class_name, false);
+        self.load_variable(&class_name, false, false);
         self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
-        while !check(TokenType::RightBrace) && !check(TokenType::Eof) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
             self.method();
         }
         self.consume(TokenType::RightBrace, "Expect '}' after class body.");
@@ -927,7 +1100,7 @@ impl Compiler {
             self.end_scope();
         }
 
-        vm().class_compiler = class_compiler.enclosing;
+        self.class_compiler = class_compiler.enclosing;
     }
 
     // 解析变量
@@ -935,15 +1108,15 @@ impl Compiler {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
-        if current().scope_depth > 0 {
+        if self.current().scope_depth > 0 {
             return 0;
         }
 
-        return self.identifier_constant(&vm().parser.previous);
+        self.identifier_constant(&self.parser.previous.clone())
     }
 
     fn emit_return(&mut self) {
-        if let FunctionType::Initializer = current().type_ {
+        if let FunctionType::Initializer = self.current().type_ {
             self.emit_bytes(OpCode::GetLocal as u8, 0);
         } else {
             self.emit_byte(OpCode::Nil as u8);
@@ -954,28 +1127,51 @@ impl Compiler {
     // 结束编译
     fn end_compiler(&mut self) -> *mut ObjFunction {
         self.emit_return();
-        let function = current().function;
+        let function = self.current().function;
+        let previous = &self.parser.previous;
+        unsafe { (*function).span.end = previous.start + previous.length };
+        // 有语法错误时字节码不完整 栈效应可能对不上 反正不会执行
+        let arity = unsafe { (*function).arity };
+        let max_stack = match cfg::max_stack_depth(self.current_chunk(), arity + 1) {
+            Ok(max_stack) => max_stack,
+            Err(_) if self.parser.had_error => 0,
+            Err(offset) => panic!("unbalanced stack effects at offset {}", offset),
+        };
+        unsafe { (*function).max_stack = max_stack };
+        // 函数最外层的局部变量一直有效到函数结束
+        let end = self.current_chunk().code.len();
+        for local in self.current_chunk().locals.iter_mut() {
+            local.end = local.end.min(end);
+        }
 
-        #[cfg(feature = "debug_print_code")]
-        if !vm().parser.had_error {
-            let name;
-            if unsafe { (*function).name.is_null() } {
-                name = "<script>"
-            } else {
-                unsafe {
-                    name = (*(*function).name).chars.as_str();
-                }
-            }
-            current_chunk().disassemble_chunk(name);
+        if self.print_code && !self.parser.had_error {
+            self.current_chunk()
+                .disassemble_chunk(unsafe { (*function).display_name() });
+        }
+        if self.print_stats && !self.parser.had_error {
+            debug::print_stats(unsafe { &*function }, self.current().max_locals);
         }
 
         // 编译结束还原 上个编译器
-        vm().current_compiler = current().enclosing;
+        self.current = self.current().enclosing;
         function
     }
 
     fn block(&mut self) {
-        while !check(TokenType::RightBrace) && !check(TokenType::Eof) {
+        let mut returned = false;
+        let mut warned = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            // return 之后的语句不可达 每个块只警告一次
+            if returned && !warned {
+                self.warning_at(
+                    &self.parser.current.clone(),
+                    "Unreachable code after 'return'.",
+                );
+                warned = true;
+            }
+            if self.check(TokenType::Return) {
+                returned = true;
+            }
             self.declaration();
         }
 
@@ -984,19 +1180,21 @@ impl Compiler {
 
     // 函数定义
     fn function(&mut self, type_: FunctionType) {
-        let compiler = Compiler::new(type_);
+        let compiler = Compiler::new(self, type_);
         self.begin_scope();
         // 函数参数
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
-        if !check(TokenType::RightParen) {
+        if !self.check(TokenType::RightParen) {
             loop {
-                unsafe { (*current().function).arity += 1 };
-                if unsafe { (*current().function).arity } > 255 {
+                unsafe { (*self.current().function).arity += 1 };
+                if unsafe { (*self.current().function).arity } > 255 {
                     self.error_at_current("Can't have more than 255 parameters.");
                 }
                 let constant = self.parse_variable("Expect parameter name.");
+                let name = self.parser.previous.lexeme.clone();
+                unsafe { (*self.current().function).params.push(name) };
                 self.define_variable(constant);
-                if self.match_(TokenType::Comma) {
+                if !self.match_(TokenType::Comma) {
                     break;
                 }
             }
@@ -1004,8 +1202,11 @@ impl Compiler {
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         self.block();
+        self.warn_unused_locals(1);
 
-        let function = self.end_compiler();
+        // 结束编译后函数不再被 ctx.current 引用 放进外层常量表之前先根住
+        let scope = HandleScope::new();
+        let function = scope.root(self.end_compiler()).get();
         let b = self.make_constant(obj_val!(function));
         self.emit_bytes(OpCode::Closure as u8, b);
 
@@ -1025,10 +1226,10 @@ impl Compiler {
 
     fn method(&mut self) {
         self.consume(TokenType::Identifier, "Expect method name.");
-        let constant = self.identifier_constant(&vm().parser.previous);
+        let constant = self.identifier_constant(&self.parser.previous.clone());
 
         let mut type_ = FunctionType::Method;
-        if vm().parser.previous.message == "init".to_string() {
+        if self.parser.previous.lexeme == "init" {
             type_ = FunctionType::Initializer;
         }
         self.function(type_);
@@ -1036,19 +1237,24 @@ impl Compiler {
     }
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        self.load_variable(name, can_assign, true);
+    }
+
+    // is_reference 为false时不算作读取 用于类声明内部加载类自身
+    fn load_variable(&mut self, name: &Token, can_assign: bool, is_reference: bool) {
         let get_op: u8;
         let set_op: u8;
-        let mut arg = self.resolve_local(current(), &name);
+        let mut arg = self.resolve_local(self.current(), name);
         if arg != -1 {
             get_op = OpCode::GetLocal as u8;
             set_op = OpCode::SetLocal as u8;
         } else {
-            arg = self.resolve_upvalue(current(), &name);
+            arg = self.resolve_upvalue(self.current(), name);
             if arg != -1 {
                 get_op = OpCode::GetUpvalue as u8;
                 set_op = OpCode::SetUpvalue as u8;
             } else {
-                arg = self.identifier_constant(&name) as i32;
+                arg = self.identifier_constant(name) as i32;
                 get_op = OpCode::GetGlobal as u8;
                 set_op = OpCode::SetGlobal as u8;
             }
@@ -1058,8 +1264,98 @@ impl Compiler {
         if can_assign && self.match_(TokenType::Equal) {
             self.expression();
             self.emit_bytes(set_op, arg as u8);
+            if set_op == OpCode::SetGlobal as u8 {
+                let name_string = as_string!(self.current_chunk().constants.values[arg as usize]);
+                self.parser.global_writes.push((name.clone(), name_string));
+            }
         } else {
             self.emit_bytes(get_op, arg as u8);
+            if is_reference {
+                self.mark_read(name, get_op, arg);
+            }
+            // 直接调用的变量 由 call 生成专用指令 全局变量只有名字是内联函数时才需要
+            if self.check(TokenType::LeftParen) {
+                self.current().callee = match get_op {
+                    op if op == OpCode::GetLocal as u8 => Some(Callee::Local),
+                    op if op == OpCode::GetUpvalue as u8 => Some(Callee::Upvalue),
+                    _ => Intrinsic::from_name(&name.lexeme).map(Callee::Intrinsic),
+                };
+            }
+        }
+    }
+
+    // 记录变量被读取 提升值在捕获时已经标记
+    fn mark_read(&mut self, name: &Token, get_op: u8, arg: i32) {
+        if get_op == OpCode::GetLocal as u8 {
+            self.current().locals[arg as usize].is_read = true;
+        } else if get_op == OpCode::GetGlobal as u8 {
+            self.parser.global_reads.insert(name.lexeme.clone());
+        }
+    }
+
+    // 记录顶层声明的函数或类 编译结束时检查是否被使用
+    fn declare_global(&mut self, kind: &'static str) {
+        if self.current().scope_depth == 0 {
+            let name = self.parser.previous.clone();
+            self.parser.global_declarations.push((name, kind));
+        }
+    }
+
+    // 警告从 from 开始到栈顶之间从未被读取的局部变量 参数和下划线开头的名字除外
+    // 有语法错误时局部变量表中可能留着错误恢复前的记号 不再检查
+    fn warn_unused_locals(&mut self, from: usize) {
+        if self.parser.had_error || self.parser.panic_mode {
+            return;
+        }
+        let arity = unsafe { (*self.current().function).arity };
+        for i in from.max(arity + 1)..self.current().local_count {
+            let name = self.current().locals[i].name.clone();
+            if self.current().locals[i].is_read
+                || self.current().locals[i].is_captured
+                || name.lexeme.is_empty()
+                || name.lexeme.starts_with('_')
+                || name.lexeme == "super"
+            {
+                continue;
+            }
+            self.warning_at(
+                &name,
+                &format!("Local variable '{}' is never read.", name.lexeme),
+            );
+        }
+    }
+
+    // 警告从未被读取的顶层函数和类
+    fn warn_unused_globals(&mut self) {
+        let declarations = std::mem::take(&mut self.parser.global_declarations);
+        for (name, kind) in declarations {
+            if !name.lexeme.starts_with('_') && !self.parser.global_reads.contains(&name.lexeme) {
+                self.warning_at(&name, &format!("{} '{}' is never used.", kind, name.lexeme));
+            }
+        }
+    }
+
+    // 给源码中没有定义 运行时也还不存在的全局变量赋值 运行到这里必然报错
+    // 默认给出警告 严格模式下是编译错误 有导入时模块可能定义任何名字 不做检查
+    fn check_global_writes(&mut self) {
+        let writes = std::mem::take(&mut self.parser.global_writes);
+        if self.parser.had_error || self.parser.has_import {
+            return;
+        }
+        for (name, name_string) in writes {
+            if self.parser.global_defines.contains(&name.lexeme)
+                || vm().globals.get(name_string).is_some()
+                || vm().builtins.get(name_string).is_some()
+            {
+                continue;
+            }
+            let message = format!("Assignment to undeclared global '{}'.", name.lexeme);
+            if self.strict_globals {
+                self.error_at(&name, &message);
+                self.parser.panic_mode = false;
+            } else {
+                self.warning_at(&name, &message);
+            }
         }
     }
 
@@ -1070,7 +1366,7 @@ impl Compiler {
         let local = self.resolve_local(unsafe { &mut (*compiler.enclosing) }, name);
         if local != -1 {
             unsafe {
-                (*compiler.enclosing).locals[local as usize].is_captured = true;
+                (&mut (*compiler.enclosing).locals)[local as usize].is_captured = true;
             }
             return self.add_upvalue(compiler, local as u8, true);
         }
@@ -1080,7 +1376,7 @@ impl Compiler {
             return self.add_upvalue(compiler, upvalue as u8, false);
         }
 
-        return -1;
+        -1
     }
 
     fn add_upvalue(&mut self, compiler: &mut Compiler, index: u8, is_local: bool) -> i32 {
@@ -1122,15 +1418,19 @@ impl Compiler {
             i -= 1;
         }
 
-        return -1;
+        -1
     }
 
     fn define_variable(&mut self, global: u8) {
-        if current().scope_depth > 0 {
-            mark_initialized();
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
             return;
         }
         self.emit_bytes(OpCode::DefineGlobal as u8, global);
+        let name = as_string!(self.current_chunk().constants.values[global as usize]);
+        self.parser
+            .global_defines
+            .insert(unsafe { (*name).chars.to_string() });
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -1139,7 +1439,9 @@ impl Compiler {
     }
 
     fn emit_byte(&self, byte: u8) {
-        current_chunk().write_chunk(byte, vm().parser.previous.line);
+        let previous = &self.parser.previous;
+        self.current_chunk()
+            .write_chunk(byte, previous.line, previous.column);
     }
 
     // 写入跳转分支 使用两个字节占位符做操作数
@@ -1147,12 +1449,12 @@ impl Compiler {
         self.emit_byte(instruction);
         self.emit_byte(0xff);
         self.emit_byte(0xff);
-        current_chunk().count() - 2
+        self.current_chunk().count() - 2
     }
 
     fn patch_jump(&mut self, offset: usize) {
         // -offset得到 字节指令的位置  -2 再得到then语句的位置
-        let jump = current_chunk().count() - offset - 2;
+        let jump = self.current_chunk().count() - offset - 2;
 
         // 最大只能跳转两个字节的字节码
         if jump > u16::MAX as usize {
@@ -1160,21 +1462,21 @@ impl Compiler {
         }
 
         // 回写需要跳过的大小
-        current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
-        current_chunk().code[offset + 1] = (jump & 0xff) as u8;
+        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
     }
 
     fn declare_variable(&mut self) {
-        if current().scope_depth == 0 {
+        if self.current().scope_depth == 0 {
             return;
         }
 
-        let name = &vm().parser.previous;
+        let name = &self.parser.previous.clone();
 
-        let mut i = (current().local_count - 1) as i32;
+        let mut i = (self.current().local_count - 1) as i32;
         while i >= 0 {
-            let local = &current().locals[i as usize];
-            if local.depth != -1 && local.depth < current().scope_depth as i32 {
+            let local = &self.current().locals[i as usize];
+            if local.depth != -1 && local.depth < self.current().scope_depth as i32 {
                 break;
             }
 
@@ -1188,24 +1490,29 @@ impl Compiler {
     }
 
     fn add_local(&mut self, name: &Token) {
-        if current().local_count == UINT8_COUNT {
+        if self.current().local_count == UINT8_COUNT {
             self.error("Too many local variables in function.");
             return;
         }
 
-        let local = &mut current().locals[current().local_count];
-        current().local_count += 1;
+        let local = &mut self.current().locals[self.current().local_count];
+        self.current().local_count += 1;
+        self.current().max_locals = self.current().max_locals.max(self.current().local_count);
         local.name = name.clone();
         local.depth = -1;
         local.is_captured = false;
+        local.is_read = false;
     }
 
     fn identifier_constant(&mut self, name: &Token) -> u8 {
-        self.make_constant(obj_val!(ObjString::take_string(name.message.clone())))
+        self.make_constant(obj_val!(ObjString::take_string(name.lexeme.clone())))
     }
 
+    // 常量在加入常量表之前只被驻留表弱引用 先根住 和 clox 加常量时压栈一样
     fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = current_chunk().add_constant(value);
+        let scope = HandleScope::new();
+        scope.root_value(value);
+        let constant = self.current_chunk().add_constant(value);
         if constant > u8::MAX as usize {
             self.error("Too many constants in one chunk.");
             return 0;
@@ -1215,18 +1522,19 @@ impl Compiler {
     }
 
     fn synchronize(&mut self) {
-        vm().parser.panic_mode = false;
+        self.parser.panic_mode = false;
 
-        while vm().parser.current.type_ != TokenType::Eof {
-            if vm().parser.previous.type_ == TokenType::Semicolon {
+        while self.parser.current.type_ != TokenType::Eof {
+            if self.parser.previous.type_ == TokenType::Semicolon {
                 return;
             }
-            match vm().parser.current.type_ {
+            match self.parser.current.type_ {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
+                | TokenType::Import
                 | TokenType::While
                 | TokenType::Print
                 | TokenType::Return => return,
@@ -1238,42 +1546,76 @@ impl Compiler {
     }
 
     fn begin_scope(&self) {
-        current().scope_depth += 1;
+        self.current().scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
-        current().scope_depth -= 1;
+        self.current().scope_depth -= 1;
 
-        while current().local_count > 0
-            && current().locals[current().local_count - 1].depth as usize > current().scope_depth
+        let mut first = self.current().local_count;
+        while first > 0
+            && self.current().locals[first - 1].depth as usize > self.current().scope_depth
         {
-            // 被捕获的需要推送到闭包
-            if current().locals[current().local_count - 1].is_captured {
-                self.emit_byte(OpCode::CloseUpvalue as u8);
-            } else {
-                self.emit_byte(OpCode::Pop as u8);
-            }
-            current().local_count -= 1;
+            first -= 1;
+        }
+        self.warn_unused_locals(first);
+
+        // 一次弹出作用域中的所有局部变量 其中有被捕获的就一起关闭提升值
+        let count = self.current().local_count - first;
+        let captured = (first..self.current().local_count)
+            .any(|local| self.current().locals[local].is_captured);
+        for local in (first..self.current().local_count).rev() {
+            self.close_local_info(local);
+        }
+        self.current().local_count = first;
+        match (count, captured) {
+            (0, _) => {}
+            (1, true) => self.emit_byte(OpCode::CloseUpvalue as u8),
+            (1, false) => self.emit_byte(OpCode::Pop as u8),
+            (_, true) => self.emit_bytes(OpCode::CloseUpvalueN as u8, count as u8),
+            (_, false) => self.emit_bytes(OpCode::PopN as u8, count as u8),
         }
     }
 
-    pub fn compile(&mut self) -> *mut ObjFunction {
+    // 编译整个脚本 失败时返回全部诊断(含警告) 成功时警告留在 diagnostics 中
+    // 返回的函数没有被根住 调用方要在下次分配前保存好
+    pub fn compile(&mut self) -> Result<*mut ObjFunction, Vec<Diag>> {
+        // 编译中的函数经由上下文标记 上下文在编译期间不能移动
+        let enclosing = std::mem::replace(&mut vm().compiling, self as *mut CompilerCtx);
+        let _script = Compiler::new(self, FunctionType::Script);
+        // REPL 会话的局部变量已经在栈上 当作顶层函数的参数
+        if let Some(names) = self.repl_locals.clone() {
+            for name in &names {
+                self.add_repl_local(&synthetic_token(name));
+            }
+            unsafe { (*self.current().function).arity = names.len() };
+        }
         self.advance();
 
         while !self.match_(TokenType::Eof) {
             self.declaration();
         }
+        if self.warn_unused_globals && !self.parser.had_error {
+            self.warn_unused_globals();
+        }
+        self.check_global_writes();
 
+        if self.repl_locals.is_some() {
+            let script = self.current();
+            let names = (1..script.local_count).map(|i| script.locals[i].name.lexeme.clone());
+            self.repl_locals = Some(names.collect());
+        }
         let function = self.end_compiler();
-        if vm().parser.had_error {
-            null_mut()
+        vm().compiling = enclosing;
+        if self.parser.had_error || (self.deny_warnings && self.parser.had_warning) {
+            Err(std::mem::take(&mut self.diagnostics))
         } else {
-            function
+            Ok(function)
         }
     }
 
     fn consume(&mut self, type_: TokenType, message: &str) {
-        if vm().parser.current.type_ == type_ {
+        if self.parser.current.type_ == type_ {
             self.advance();
             return;
         }
@@ -1282,35 +1624,175 @@ impl Compiler {
     }
 
     fn error_at_current(&mut self, message: &str) {
-        self.error_at(&vm().parser.current.clone(), message);
+        self.error_at(&self.parser.current.clone(), message);
     }
 
     fn error(&mut self, message: &str) {
-        self.error_at(&vm().parser.previous.clone(), message);
+        self.error_at(&self.parser.previous.clone(), message);
     }
 
     fn error_at(&mut self, token: &Token, message: &str) {
-        vm().parser.panic_mode = true;
-
-        eprint!("[line {}] Error", token.line);
+        // 异常模式中的后续错误多是连锁反应 不再报告
+        if self.parser.panic_mode {
+            return;
+        }
+        self.parser.panic_mode = true;
+        self.parser.had_error = true;
 
-        if token.type_ == TokenType::Eof {
-            eprint!(" at end");
+        let location = if token.type_ == TokenType::Eof {
+            " at end".to_string()
         } else if let TokenType::Error = token.type_ {
-            // Nothing.
+            String::new()
         } else {
-            eprint!(
-                " at '{}'",
-                String::from_utf8(
-                    vm().scanner.as_ref().unwrap().source.as_bytes()
-                        [token.start..token.start + token.length]
-                        .to_vec()
-                )
-                .unwrap()
-            );
-        }
+            format!(" at '{}'", token.lexeme)
+        };
+        self.report(Severity::Error, token, location, message);
+    }
+
+    // 编译警告 不进入异常模式 --deny-warnings 时视为编译失败
+    fn warning_at(&mut self, token: &Token, message: &str) {
+        self.parser.had_warning = true;
+        let location = format!(" at '{}'", token.lexeme);
+        self.report(Severity::Warning, token, location, message);
+    }
+
+    fn report(&mut self, severity: Severity, token: &Token, location: String, message: &str) {
+        self.diagnostics.push(Diag {
+            severity,
+            message: message.into(),
+            location,
+            line: token.line,
+            start: token.start,
+            length: token.length,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{drop_vm, init_vm};
+
+    // 编译只需要虚拟机分配对象 诊断由上下文收集 不会打印
+    #[test]
+    fn compile_collects_diagnostics() {
+        init_vm();
+        let mut ctx = CompilerCtx::new("var a = 1 +;\nprint a\n".into(), None);
+        let diagnostics = ctx.compile().unwrap_err();
+        let errors: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(ctx.source(), None, false))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "[line 1] Error at ';': Expect expression.\n",
+                "[line 3] Error at end: Expect ';' after value.\n",
+            ]
+        );
 
-        eprintln!(": {}", message);
-        vm().parser.had_error = true;
+        let mut ctx = CompilerCtx::new("fun f(a) { return a; }\n".into(), None);
+        assert!(ctx.compile().is_ok());
+        assert_eq!(ctx.diagnostics.len(), 1);
+        assert_eq!(ctx.diagnostics[0].message, "Function 'f' is never used.");
+
+        ctx = CompilerCtx::new("fun f(a) { return a; }\n".into(), Some("f.lox"));
+        ctx.deny_warnings = true;
+        assert!(ctx.compile().is_err());
+        drop_vm();
+    }
+
+    // 未读取的参数不警告 有语法错误时不检查未使用的局部变量
+    #[test]
+    fn unused_local_warnings_skip_parameters_and_errors() {
+        init_vm();
+        let warnings = |source: &str| {
+            let mut ctx = CompilerCtx::new(source.into(), None);
+            ctx.warn_unused_globals = false;
+            let diagnostics = match ctx.compile() {
+                Ok(_) => std::mem::take(&mut ctx.diagnostics),
+                Err(diagnostics) => diagnostics,
+            };
+            diagnostics
+                .iter()
+                .filter(|diagnostic| matches!(diagnostic.severity, Severity::Warning))
+                .map(|diagnostic| diagnostic.message.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            warnings("fun each(item, index) { var unused = 1; var _skip = 2; }\n"),
+            ["Local variable 'unused' is never read."]
+        );
+        assert!(warnings("fun f(() {}\n").is_empty());
+        assert!(warnings("{ var ( = 1; }\n").is_empty());
+        assert!(warnings("fun g() { var a = 1; var b = a + (; }\n").is_empty());
+        drop_vm();
+    }
+
+    // 超过255个参数时改用两字节参数数的指令 栈深度也按实际参数数计算
+    #[test]
+    fn wide_calls_past_255_arguments() {
+        init_vm();
+        let args = vec!["nil"; 300].join(", ");
+        let source = format!("clock({args});\nclass A {{ f() {{ this.g({args}); }} }}\n");
+        let mut ctx = CompilerCtx::new(source, None);
+        let script = ctx.compile().unwrap();
+        let chunk = unsafe { &(*script).chunk };
+        assert!(chunk.code.contains(&(OpCode::CallWide as u8)));
+        assert!(unsafe { (*script).max_stack } > 300);
+        drop_vm();
+    }
+
+    // 常量条件不生成条件跳转 不会执行的分支不生成代码
+    #[test]
+    fn constant_conditions_drop_dead_branches() {
+        init_vm();
+        let source = "if (false) print \"dead\"; else print \"live\";\n\
+                      while (false) print \"never\";\n";
+        let mut ctx = CompilerCtx::new(source.into(), None);
+        let script = ctx.compile().unwrap();
+        let code = unsafe { &(*script).chunk.code };
+        assert!(!code.contains(&(OpCode::JumpIfFalse as u8)));
+        assert_eq!(
+            code.iter()
+                .filter(|&&byte| byte == OpCode::Print as u8)
+                .count(),
+            1
+        );
+        drop_vm();
+    }
+
+    // 作用域结束时局部变量一次弹出 有被捕获的就一起关闭提升值
+    #[test]
+    fn end_scope_batches_pops() {
+        init_vm();
+        let source = "{ var a = 1; var b = 2; print a + b; }\n\
+                      { var c = 3; var d = 4; fun f() { return c; } print f() + d; }\n";
+        let mut ctx = CompilerCtx::new(source.into(), None);
+        let script = ctx.compile().unwrap();
+        let code = unsafe { &(*script).chunk.code };
+        let pops = [OpCode::PopN as u8, 2];
+        let closes = [OpCode::CloseUpvalueN as u8, 3];
+        assert!(code.windows(2).any(|pair| pair == pops));
+        assert!(code.windows(2).any(|pair| pair == closes));
+        drop_vm();
+    }
+
+    // 给未声明的全局变量赋值 默认警告 严格模式下报错 后面才定义的不算
+    #[test]
+    fn assignments_to_undeclared_globals() {
+        init_vm();
+        let source = "fun f() { later = 1; missing = 2; }\nvar later;\nf();\n";
+        let mut ctx = CompilerCtx::new(source.into(), None);
+        assert!(ctx.compile().is_ok());
+        let messages: Vec<&str> = ctx.diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["Assignment to undeclared global 'missing'."]);
+
+        let mut ctx = CompilerCtx::new(source.into(), None);
+        ctx.strict_globals = true;
+        let diagnostics = ctx.compile().unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(matches!(diagnostics[0].severity, Severity::Error));
+        drop_vm();
     }
 }
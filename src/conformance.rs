@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::cli;
+
+// 按 craftinginterpreters 测试集的注释约定运行目录下的所有 .lox 文件
+//   // expect: 输出              标准输出中应有这一行
+//   // expect runtime error: 信息 以运行时错误退出 标准错误中应有这条信息
+//   // Error ...                  编译错误 标准错误中应有 "[line 当前行] Error ..."
+//   // [line N] Error ...         编译错误 行号由注释给出
+// 子目录名作为用例的分类(对应书中的章节和语言特性) 最后按分类汇总
+// 每个用例在单独的子进程中运行 调试特性打印的轨迹也在标准输出中 所以只要求期望的行按顺序出现
+pub fn run_suite(dir: &Path) -> io::Result<bool> {
+    let mut files = vec![];
+    collect_tests(dir, &mut files)?;
+    files.sort();
+
+    let exe = env::current_exe()?;
+    let mut categories: BTreeMap<String, (usize, Vec<(PathBuf, String)>)> = BTreeMap::new();
+    for file in &files {
+        let relative = file.strip_prefix(dir).unwrap_or(file);
+        let category = match relative.parent().and_then(|parent| parent.iter().next()) {
+            Some(category) => category.to_string_lossy().into_owned(),
+            None => ".".into(),
+        };
+        let entry = categories.entry(category).or_default();
+        entry.0 += 1;
+        if let Err(reason) = run_test(&exe, file) {
+            entry.1.push((relative.to_path_buf(), reason));
+        }
+    }
+
+    let mut failed = 0;
+    for (category, (total, failures)) in &categories {
+        println!(
+            "{:<24} {}/{} passed",
+            category,
+            total - failures.len(),
+            total
+        );
+        for (file, reason) in failures {
+            println!("  FAIL {}: {}", file.display(), reason);
+        }
+        failed += failures.len();
+    }
+    println!("{} of {} tests passed", files.len() - failed, files.len());
+    Ok(failed == 0)
+}
+
+fn collect_tests(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_tests(&path, files)?;
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// 用例中的期望
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    runtime_error: Option<String>,
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations::default();
+    for (i, line) in source.lines().enumerate() {
+        let Some((_, comment)) = line.split_once("// ") else {
+            continue;
+        };
+        if let Some(output) = comment.strip_prefix("expect: ") {
+            expectations.output.push(output.into());
+        } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+            expectations.runtime_error = Some(message.into());
+        } else if comment.starts_with("Error") {
+            expectations
+                .compile_errors
+                .push(format!("[line {}] {}", i + 1, comment));
+        } else if let Some(error) = comment.strip_prefix("[line ") {
+            // 其他实现专用的 [java line N] 之类的不检查
+            expectations.compile_errors.push(format!("[line {}", error));
+        } else if let Some(error) = comment.strip_prefix("[c line ") {
+            expectations.compile_errors.push(format!("[line {}", error));
+        }
+    }
+    expectations
+}
+
+// 运行一个用例 不符合期望时返回原因
+fn run_test(exe: &Path, path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let expectations = parse_expectations(&source);
+    let output = Command::new(exe)
+        .arg(path)
+        .output()
+        .map_err(|error| error.to_string())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let expected_status = if !expectations.compile_errors.is_empty() {
+        cli::EXIT_COMPILE
+    } else if expectations.runtime_error.is_some() {
+        cli::EXIT_RUNTIME
+    } else {
+        0
+    };
+    let status = output.status.code().unwrap_or(-1);
+    if status != expected_status {
+        return Err(format!(
+            "expected exit code {} but got {}",
+            expected_status, status
+        ));
+    }
+
+    let mut lines = stdout.lines();
+    for expected in &expectations.output {
+        if !lines.any(|line| line == expected) {
+            return Err(format!("missing output '{}'", expected));
+        }
+    }
+    let errors: Vec<&str> = stderr.lines().collect();
+    for expected in expectations
+        .compile_errors
+        .iter()
+        .chain(&expectations.runtime_error)
+    {
+        if !errors.contains(&expected.as_str()) {
+            return Err(format!("missing error '{}'", expected));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::{collections::BTreeMap, fmt::Write, ptr::null};
+
+use crate::{
+    as_function,
+    heap::escape_json,
+    object::{ObjFunction, ObjType},
+    value::as_obj,
+};
+
+// 没有文件名的源码(-e 的代码) 在报告中的名字
+const NO_FILE: &str = "<script>";
+
+// 源码行覆盖率 每个文件中有指令的行及其执行次数
+// 只统计登记过的函数 前导和原生函数不算在内
+pub struct Coverage {
+    files: BTreeMap<String, BTreeMap<usize, u64>>,
+    last: (*const ObjFunction, usize, usize), // 上一条指令的函数、偏移和行号
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            files: BTreeMap::new(),
+            last: (null(), 0, 0),
+        }
+    }
+
+    // 登记函数及其嵌套函数中所有有指令的行 从未执行的行也要出现在报告中
+    pub fn add_function(&mut self, function: *mut ObjFunction) {
+        let chunk = unsafe { &(*function).chunk };
+        let file = chunk.file.as_deref().unwrap_or(NO_FILE);
+        let lines = self.files.entry(file.to_string()).or_default();
+        for &line in &chunk.lines {
+            if line > 0 {
+                lines.entry(line).or_insert(0);
+            }
+        }
+        for &constant in &chunk.constants.values {
+            if constant.is_obj_type(ObjType::Function) {
+                self.add_function(as_function!(constant));
+            }
+        }
+    }
+
+    // 执行 offset 处的指令 进入新的一行或跳回前面时计一次
+    // 同一行上接连执行的指令只算一次
+    pub fn hit(&mut self, function: *mut ObjFunction, offset: usize) {
+        let chunk = unsafe { &(*function).chunk };
+        let line = chunk.lines[offset];
+        let (last_function, last_offset, last_line) = self.last;
+        self.last = (function, offset, line);
+        if last_function == function && last_line == line && last_offset < offset {
+            return;
+        }
+        let file = chunk.file.as_deref().unwrap_or(NO_FILE);
+        if let Some(count) = self
+            .files
+            .get_mut(file)
+            .and_then(|lines| lines.get_mut(&line))
+        {
+            *count += 1;
+        }
+    }
+
+    // lcov 的跟踪文件格式 genhtml 等工具可以直接读取
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::from("TN:\n");
+        for (file, lines) in &self.files {
+            writeln!(out, "SF:{}", file).unwrap();
+            for (line, count) in lines {
+                writeln!(out, "DA:{},{}", line, count).unwrap();
+            }
+            writeln!(out, "LF:{}", lines.len()).unwrap();
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            writeln!(out, "LH:{}", hit).unwrap();
+            writeln!(out, "end_of_record").unwrap();
+        }
+        out
+    }
+
+    // 文件名到 行号: 执行次数 的 JSON 对象
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n");
+        for (i, (file, lines)) in self.files.iter().enumerate() {
+            let lines: Vec<String> = lines
+                .iter()
+                .map(|(line, count)| format!("\"{}\": {}", line, count))
+                .collect();
+            let separator = if i + 1 < self.files.len() { "," } else { "" };
+            writeln!(
+                out,
+                "  \"{}\": {{{}}}{}",
+                escape_json(file),
+                lines.join(", "),
+                separator
+            )
+            .unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+}
@@ -1,134 +1,217 @@
+use std::{collections::HashMap, fmt::Write};
+
 use crate::{
     as_function,
-    chunk::{Chunk, OpCode},
-    object::ObjFunction,
+    chunk::{Chunk, OpCode, Operands},
+    intrinsic::Intrinsic,
+    object::{ObjFunction, ObjType},
     value::as_obj,
+    vm::UINT8_COUNT,
 };
 
+// 把函数的反汇编写入 out 并递归反汇编常量表中嵌套的函数(闭包、方法)
+pub fn write_function(out: &mut String, function: *mut ObjFunction) {
+    let function = unsafe { function.as_ref().unwrap() };
+    function
+        .chunk
+        .write_disassembly(out, function.display_name());
+
+    for value in &function.chunk.constants.values {
+        if value.is_obj_type(ObjType::Function) {
+            writeln!(out).unwrap();
+            write_function(out, as_function!(*value));
+        }
+    }
+}
+
+// 打印函数的字节码块统计 各项后面是编译器允许的上限 locals 是同时存在的局部变量数
+pub fn print_stats(function: &ObjFunction, locals: usize) {
+    let chunk = &function.chunk;
+    println!("== stats {} ==", function.display_name());
+    println!("bytecode   {} bytes", chunk.count());
+    println!(
+        "constants  {} / {}",
+        chunk.constants.values.len(),
+        UINT8_COUNT
+    );
+    println!("max stack  {} slots", function.max_stack);
+    println!("locals     {} / {}", locals, UINT8_COUNT);
+    println!("upvalues   {} / {}", function.upvalue_count, UINT8_COUNT);
+
+    // 按出现次数从多到少 次数相同时按指令名
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut offset = 0;
+    while offset < chunk.count() {
+        let op: OpCode = chunk.code[offset].into();
+        *counts.entry(op.info().name).or_default() += 1;
+        offset += chunk.instruction_len(offset);
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    for (name, count) in counts {
+        println!("  {:<20} {}", name, count);
+    }
+}
+
 impl Chunk {
     pub fn disassemble_chunk(&self, name: &str) {
-        println!("== {} ==", name); // 打印字节码块名
+        let mut out = String::new();
+        self.write_disassembly(&mut out, name);
+        print!("{}", out);
+    }
+
+    // 字节码块的反汇编文本 指令、常量表和行号表
+    pub fn write_disassembly(&self, out: &mut String, name: &str) {
+        writeln!(out, "== {} ==", name).unwrap(); // 字节码块名
 
         // 遍历字节码块中的字节码
         let mut offset = 0;
-        loop {
-            if offset >= self.count() {
-                break;
+        while offset < self.count() {
+            offset = self.write_numbered_instruction(out, offset);
+        }
+
+        self.write_constants(out);
+        self.write_lines(out);
+    }
+
+    // 常量表 序号、类型、值
+    fn write_constants(&self, out: &mut String) {
+        writeln!(out, "-- constants --").unwrap();
+        for (index, value) in self.constants.values.iter().enumerate() {
+            writeln!(out, "{:04} {:<12} {}", index, value.type_name(), value).unwrap();
+        }
+    }
+
+    // 行号表 连续同一行的字节合并为一个偏移区间
+    fn write_lines(&self, out: &mut String) {
+        writeln!(out, "-- lines --").unwrap();
+        let mut start = 0;
+        while start < self.lines.len() {
+            let line = self.lines[start];
+            let mut end = start;
+            while end + 1 < self.lines.len() && self.lines[end + 1] == line {
+                end += 1;
             }
-            offset = self.disassemble_instruction(offset);
+            writeln!(out, "{:04}-{:04} line {}", start, end, line).unwrap();
+            start = end + 1;
         }
     }
 
     pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        let mut offset = offset;
-
-        print!("{:04} ", offset);
-        print!("{:4} ", self.lines[offset]);
-
-        let instruction = self.code[offset];
-        let instruction: OpCode = instruction.into();
-        match instruction {
-            OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::Nil => self.simple_instruction("OP_NIL", offset),
-            OpCode::True => self.simple_instruction("OP_TRUE", offset),
-            OpCode::False => self.simple_instruction("OP_FALSE", offset),
-            OpCode::Pop => self.simple_instruction("OP_POP", offset),
-            OpCode::GetLocal => self.byte_instruction("OP_GET_LOCAL", offset),
-            OpCode::SetLocal => self.byte_instruction("OP_SET_LOCAL", offset),
-            OpCode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
-            OpCode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-            OpCode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset),
-            OpCode::GetUpvalue => self.byte_instruction("OP_GET_UPVALUE", offset),
-            OpCode::SetUpvalue => self.byte_instruction("OP_SET_UPVALUE", offset),
-            OpCode::GetProperty => self.constant_instruction("OP_GET_PROPERTY", offset),
-            OpCode::SetProperty => self.constant_instruction("OP_SET_PROPERTY", offset),
-            OpCode::GetSuper => self.constant_instruction("OP_GET_SUPER", offset),
-            OpCode::Equal => self.simple_instruction("OP_EQUAL", offset),
-            OpCode::Greater => self.simple_instruction("OP_GREATER", offset),
-            OpCode::Less => self.simple_instruction("OP_LESS", offset),
-            OpCode::Add => self.simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::Not => self.simple_instruction("OP_NOT", offset),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::Print => self.simple_instruction("OP_PRINT", offset),
-            OpCode::Jump => self.jump_instruction("OP_JUMP", 1, offset),
-            OpCode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset),
-            OpCode::Loop => self.jump_instruction("OP_LOOP", -1, offset),
-            OpCode::Call => self.byte_instruction("OP_CALL", offset),
-            OpCode::Invoke => self.invoke_instruction("OP_INVOKE", offset),
-            OpCode::SuperInvoke => self.invoke_instruction("OP_SUPER_INVOKE", offset),
-            OpCode::Closure => {
-                offset += 1;
-                let constant = self.code[offset];
-                offset += 1;
-                print!("{:<16} {:>4} ", "OP_CLOSURE", constant);
-                self.constants.values[constant as usize].print();
-                println!("");
-                let function = as_function!(self.constants.values[constant as usize]);
-                for _ in unsafe { 0..(*function).upvalue_count } {
-                    let is_local = self.code[offset];
-                    offset += 1;
-                    let index = self.code[offset];
-                    offset += 1;
-                    println!(
-                        "{:04}      |                     {} {}",
-                        offset - 2,
-                        if is_local != 0 { "local" } else { "upvalue" },
-                        index
-                    );
-                }
-                offset
+        let mut out = String::new();
+        let next = self.write_numbered_instruction(&mut out, offset);
+        print!("{}", out);
+        next
+    }
+
+    // 带偏移和行号的一条指令 返回下一条指令的偏移
+    fn write_numbered_instruction(&self, out: &mut String, offset: usize) -> usize {
+        write!(out, "{:04} ", offset).unwrap();
+        write!(out, "{:4} ", self.lines[offset]).unwrap();
+        self.write_instruction(out, offset);
+
+        offset + self.instruction_len(offset)
+    }
+
+    // 把一条指令(不含偏移和行号)的反汇编文本写入out 每行以换行结尾
+    pub fn write_instruction(&self, out: &mut String, offset: usize) {
+        let instruction: OpCode = self.code[offset].into();
+        let info = instruction.info();
+        match info.operands {
+            Operands::None => self.simple_instruction(out, info.name),
+            Operands::Byte => self.byte_instruction(out, info.name, offset),
+            Operands::Short => self.short_instruction(out, info.name, offset),
+            Operands::Constant => self.constant_instruction(out, info.name, offset),
+            Operands::Jump => self.jump_instruction(out, info.name, 1, offset),
+            Operands::Loop => self.jump_instruction(out, info.name, -1, offset),
+            Operands::Invoke | Operands::WideInvoke => {
+                self.invoke_instruction(out, info.name, offset)
             }
-            OpCode::CloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE", offset),
-            OpCode::Return => self.simple_instruction("OP_RETURN", offset),
-            OpCode::Class => self.constant_instruction("OP_CLASS", offset),
-            OpCode::Inherit => self.simple_instruction("OP_INHERIT", offset),
-            OpCode::Method => self.constant_instruction("OP_METHOD", offset),
+            Operands::Intrinsic => self.intrinsic_instruction(out, info.name, offset),
+            Operands::Closure => self.closure_instruction(out, info.name, offset),
         }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{} ", name);
-        return offset + 1;
+    fn simple_instruction(&self, out: &mut String, name: &str) {
+        writeln!(out, "{} ", name).unwrap();
     }
 
     // 字节指令 打印出slot的偏移量
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+    fn byte_instruction(&self, out: &mut String, name: &str, offset: usize) {
         let slot = self.code[offset + 1];
-        println!("{:<16} {:>4}", name, slot);
-        offset + 2
+        writeln!(out, "{:<16} {:>4}", name, slot).unwrap();
+    }
+
+    fn short_instruction(&self, out: &mut String, name: &str, offset: usize) {
+        let value = ((self.code[offset + 1] as u16) << 8) | self.code[offset + 2] as u16;
+        writeln!(out, "{:<16} {:>4}", name, value).unwrap();
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    fn constant_instruction(&self, out: &mut String, name: &str, offset: usize) {
         let constant = self.code[offset + 1];
-        print!("{:<16} {:>4} '", name, constant);
-        self.constants.values[constant as usize].print();
-        println!("'");
-        offset + 2
+        let value = self.constants.values[constant as usize];
+        writeln!(out, "{:<16} {:>4} '{}'", name, constant, value).unwrap();
     }
 
     // 跳转指令 操作数为两个字节
-    fn jump_instruction(&self, name: &str, sign: i32, offset: usize) -> usize {
+    fn jump_instruction(&self, out: &mut String, name: &str, sign: i32, offset: usize) {
         let mut jump = (self.code[offset + 1] as u16) << 8;
         jump |= self.code[offset + 2] as u16;
-        println!(
+        writeln!(
+            out,
             "{:<16} {:>4} -> {}",
             name,
             offset,
-            offset + 3 + (sign * jump as i32) as usize
-        );
-        offset + 3
+            offset as i32 + 3 + sign * jump as i32
+        )
+        .unwrap();
+    }
+
+    fn intrinsic_instruction(&self, out: &mut String, name: &str, offset: usize) {
+        let id = self.code[offset + 1];
+        let arg_count = self.code[offset + 2];
+        let intrinsic = Intrinsic::from_byte(id).map_or("?", Intrinsic::name);
+        writeln!(
+            out,
+            "{:<16} ({} args) {:>4} '{}'",
+            name, arg_count, id, intrinsic
+        )
+        .unwrap();
     }
 
     // 解释执行字节码块
-    fn invoke_instruction(&self, name: &str, offset: usize) -> usize {
+    fn invoke_instruction(&self, out: &mut String, name: &str, offset: usize) {
         let constant = self.code[offset + 1];
-        let arg_count = self.code[offset + 2];
-        print!("{:<16} ({} args) {:>4} '", name, arg_count, constant);
-        self.constants.values[constant as usize].print();
-        println!("'");
-        offset + 3
+        let op: OpCode = self.code[offset].into();
+        let arg_count = op.info().operands.arg_count(|i| self.code[offset + 1 + i]);
+        let value = self.constants.values[constant as usize];
+        writeln!(
+            out,
+            "{:<16} ({} args) {:>4} '{}'",
+            name, arg_count, constant, value
+        )
+        .unwrap();
+    }
+
+    // 闭包指令 常量之后跟着每个提升值的描述
+    fn closure_instruction(&self, out: &mut String, name: &str, offset: usize) {
+        let constant = self.code[offset + 1];
+        let value = self.constants.values[constant as usize];
+        writeln!(out, "{:<16} {:>4} {}", name, constant, value).unwrap();
+        let function = as_function!(value);
+        let mut offset = offset + 2;
+        for _ in unsafe { 0..(*function).upvalue_count } {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            writeln!(
+                out,
+                "{:04}      |                     {} {}",
+                offset,
+                if is_local != 0 { "local" } else { "upvalue" },
+                index
+            )
+            .unwrap();
+            offset += 2;
+        }
     }
 }
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use crate::{
+    as_buffer, as_instance, as_list,
+    handle::HandleScope,
+    is_buffer, is_instance, is_list, is_string, obj_val,
+    object::{Obj, ObjBuffer, ObjInstance, ObjList, ObjType},
+    value::{as_obj, Value},
+    vm::values_equal,
+};
+
+// 深比较 实例逐字段、列表逐元素递归比较 缓冲区比较内容
+// visited 记录正在比较的对象对 再次遇到时视为相等 以此处理循环引用
+pub fn deep_equal(a: Value, b: Value, visited: &mut Vec<(*mut Obj, *mut Obj)>) -> bool {
+    if values_equal(a, b) {
+        return true;
+    }
+
+    if is_buffer!(a) && is_buffer!(b) {
+        return unsafe { (*as_buffer!(a)).bytes == (*as_buffer!(b)).bytes };
+    }
+
+    let both_lists = is_list!(a) && is_list!(b);
+    let both_instances = is_instance!(a) && is_instance!(b);
+    if !both_lists && !both_instances {
+        return false;
+    }
+
+    let pair = (as_obj(a), as_obj(b));
+    if visited.contains(&pair) {
+        return true;
+    }
+    visited.push(pair);
+
+    if both_lists {
+        let (a, b) = unsafe { (&(*as_list!(a)).items, &(*as_list!(b)).items) };
+        return a.len() == b.len() && a.iter().zip(b).all(|(a, b)| deep_equal(*a, *b, visited));
+    }
+
+    let (a, b) = unsafe { (&*as_instance!(a), &*as_instance!(b)) };
+    if a.class != b.class {
+        return false;
+    }
+    let (fields_a, fields_b) = unsafe { (&(*a.fields).map, &(*b.fields).map) };
+    if fields_a.len() != fields_b.len() {
+        return false;
+    }
+    fields_a.iter().all(|(key, value)| match fields_b.get(key) {
+        Some(other) => deep_equal(*value, *other, visited),
+        None => false,
+    })
+}
+
+// 深拷贝 实例逐字段、列表逐元素递归复制 缓冲区复制内容 其他值(字符串、函数、类等)按引用共享
+// copies 记录原对象到副本的映射 保证循环引用和共享引用在副本中保持同样的结构
+pub fn deep_clone(
+    value: Value,
+    copies: &mut HashMap<*mut Obj, *mut Obj>,
+    scope: &HandleScope,
+) -> Value {
+    if !is_instance!(value) && !is_list!(value) && !is_buffer!(value) {
+        return value;
+    }
+    if let Some(copy) = copies.get(&as_obj(value)) {
+        return Value::Object(*copy);
+    }
+
+    if is_buffer!(value) {
+        let bytes = unsafe { (*as_buffer!(value)).bytes.clone() };
+        let copy = scope.root(ObjBuffer::new(bytes)).get();
+        copies.insert(as_obj(value), copy as *mut Obj);
+        return obj_val!(copy);
+    }
+
+    // 副本在填充内容前就要根住 后续分配可能触发GC
+    if is_list!(value) {
+        let original = as_list!(value);
+        let copy = scope.root(ObjList::new(vec![])).get();
+        copies.insert(as_obj(value), copy as *mut Obj);
+
+        let items = unsafe { (*original).items.clone() };
+        for item in items {
+            let item = deep_clone(item, copies, scope);
+            unsafe { (*copy).items.push(item) };
+        }
+        return obj_val!(copy);
+    }
+
+    let original = as_instance!(value);
+    let copy = scope
+        .root(ObjInstance::new(unsafe { (*original).class }))
+        .get();
+    copies.insert(as_obj(value), copy as *mut Obj);
+
+    let fields: Vec<_> = unsafe { (*(*original).fields).map.clone().into_iter().collect() };
+    for (key, field) in fields {
+        let field = deep_clone(field, copies, scope);
+        unsafe { (*(*copy).fields).set(key, field) };
+    }
+
+    obj_val!(copy)
+}
+
+// 单行表示的最大宽度 超过时每个元素占一行
+const INLINE_WIDTH: usize = 72;
+
+// 可读的多行表示 嵌套的列表和实例逐层缩进 放得进一行的写在一行里
+// 字符串带引号 实例的字段按名字排序
+// path 记录从根到当前位置的对象 再次遇到时是循环引用 写成 [...] 或 类名 {...}
+pub fn inspect(value: Value, path: &mut Vec<*mut Obj>) -> String {
+    if is_string!(value) {
+        return format!("\"{}\"", value);
+    }
+    if !is_list!(value) && !is_instance!(value) {
+        return value.to_string();
+    }
+
+    let object = as_obj(value);
+    if is_list!(value) {
+        if path.contains(&object) {
+            return "[...]".into();
+        }
+        path.push(object);
+        let items = unsafe { &(*as_list!(value)).items }
+            .iter()
+            .map(|item| inspect(*item, path))
+            .collect();
+        path.pop();
+        return layout("[", "]", "", items);
+    }
+
+    let instance = as_instance!(value);
+    let class = unsafe { &(*(*(*instance).class).name).chars };
+    if path.contains(&object) {
+        return format!("{} {{...}}", class);
+    }
+    path.push(object);
+    let mut fields: Vec<_> = unsafe { (*(*instance).fields).map.iter() }
+        .map(|(name, field)| (unsafe { &(**name).chars }, *field))
+        .collect();
+    fields.sort_by(|a, b| a.0.cmp(b.0));
+    let items = fields
+        .into_iter()
+        .map(|(name, field)| format!("{}: {}", name, inspect(field, path)))
+        .collect();
+    path.pop();
+    layout(&format!("{} {{", class), "}", " ", items)
+}
+
+// 元素都是单行且总宽度够时写成一行 否则每个元素一行 缩进两格
+// pad 是单行时括号内侧的空白
+fn layout(open: &str, close: &str, pad: &str, items: Vec<String>) -> String {
+    if items.is_empty() {
+        return format!("{}{}", open, close);
+    }
+    let inline = items.join(", ");
+    if !inline.contains('\n')
+        && open.len() + inline.len() + close.len() + 2 * pad.len() <= INLINE_WIDTH
+    {
+        return format!("{}{}{}{}{}", open, pad, inline, pad, close);
+    }
+    let mut out = format!("{}\n", open);
+    let last = items.len() - 1;
+    for (i, item) in items.iter().enumerate() {
+        let item = if i < last {
+            format!("{},", item)
+        } else {
+            item.clone()
+        };
+        for line in item.lines() {
+            out.push_str("  ");
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.push_str(close);
+    out
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Write,
+    io::{self, IsTerminal},
+};
+
+// 终端颜色
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// 编译器给出的一条诊断 编译结束后由调用方输出
+#[derive(Debug)]
+pub struct Diag {
+    pub severity: Severity,
+    pub message: String,
+    pub location: String, // 纯文本格式中的位置 如 " at 'x'" 或 " at end"
+    pub line: usize,
+    pub start: usize, // 记号在源码中的字节偏移
+    pub length: usize,
+}
+
+impl Diag {
+    // 默认是 craftinginterpreters 的格式 color 时是带源码片段的彩色格式
+    pub fn render(&self, source: &str, file: Option<&str>, color: bool) -> String {
+        if color {
+            return format!(
+                "{}\n{}",
+                header(self.severity, &self.message),
+                snippet(
+                    self.severity,
+                    file,
+                    self.line,
+                    source,
+                    self.start,
+                    self.length,
+                    true
+                )
+            );
+        }
+        let label = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        format!(
+            "[line {}] {}{}: {}\n",
+            self.line, label, self.location, self.message
+        )
+    }
+}
+
+// 诊断的去处 编译诊断和运行时错误都交给 vm().error_sink
+// 默认写到标准错误 嵌入时换成别的实现即可收集诊断或转给编辑器
+pub trait ErrorSink: Send {
+    fn report(&mut self, severity: Severity, text: &str); // text 是渲染好的一条诊断 以换行结尾
+}
+
+// 默认的去处 写到标准错误
+pub struct StderrSink;
+
+impl ErrorSink for StderrSink {
+    fn report(&mut self, _severity: Severity, text: &str) {
+        eprint!("{}", text);
+    }
+}
+
+// 编译过的源文件 运行时错误据此给出出错的源码片段
+// 编译诊断和运行时错误用同一个 snippet 输出
+#[derive(Default)]
+pub struct Sources {
+    files: HashMap<String, String>,
+}
+
+impl Sources {
+    pub fn add(&mut self, file: &str, source: &str) {
+        self.files.insert(file.into(), source.into());
+    }
+
+    // 文件的源码和第 line 行第 column 列(从1开始按字符计)的字节偏移
+    pub fn locate(&self, file: &str, line: usize, column: usize) -> Option<(&str, usize)> {
+        let source = self.files.get(file)?;
+        let line_start = if line <= 1 {
+            0
+        } else {
+            source.match_indices('\n').nth(line - 2)?.0 + 1
+        };
+        let offset = source[line_start..]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+            .map_or(source.len(), |(i, _)| line_start + i);
+        Some((source, offset))
+    }
+}
+
+// 解析 --color 的取值 auto 时标准错误是终端且没有设置 NO_COLOR 才使用颜色
+pub fn parse_color(value: &str) -> Option<bool> {
+    match value {
+        "always" => Some(true),
+        "never" => Some(false),
+        "auto" => Some(io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()),
+        _ => None,
+    }
+}
+
+// 彩色的标题行 如 "error: Undefined variable 'a'."
+pub fn header(severity: Severity, message: &str) -> String {
+    let (color, label) = match severity {
+        Severity::Error => (RED, "error"),
+        Severity::Warning => (YELLOW, "warning"),
+    };
+    format!("{}{}{}: {}{}{}", color, label, RESET, BOLD, message, RESET)
+}
+
+// 出错的源码行 用 ^ 标出 source[start..start + length]
+// 知道源文件时位置写成 文件:行:列 color 只添加样式 内容不变
+pub fn snippet(
+    severity: Severity,
+    file: Option<&str>,
+    line: usize,
+    source: &str,
+    start: usize,
+    length: usize,
+    color: bool,
+) -> String {
+    let paint = |code: &'static str| if color { code } else { "" };
+    let (blue, reset) = (paint(BLUE), paint(RESET));
+    let caret = match severity {
+        Severity::Error => paint(RED),
+        Severity::Warning => paint(YELLOW),
+    };
+    // 偏移量可能落在多字节字符中间或超出末尾
+    let mut start = start.min(source.len());
+    while !source.is_char_boundary(start) {
+        start -= 1;
+    }
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let text = source[line_start..line_end].trim_end_matches('\r');
+
+    // 列号和下划线长度按字符计 跨行的记号只标到行尾
+    let column = source[line_start..start].chars().count();
+    let span_end = (start + length).min(line_end);
+    let span = source
+        .get(start..span_end)
+        .map_or(0, |span| span.chars().count())
+        .max(1);
+
+    let number = line.to_string();
+    let gutter = " ".repeat(number.len());
+    let mut out = String::new();
+    let location = match file {
+        Some(file) => format!("{}:{}:{}", file, line, column + 1),
+        None => format!("line {}, column {}", line, column + 1),
+    };
+    writeln!(out, "{}{}--> {}{}", gutter, blue, reset, location).unwrap();
+    writeln!(out, "{} {}|{}", gutter, blue, reset).unwrap();
+    writeln!(out, "{}{} |{} {}", blue, number, reset, text).unwrap();
+    writeln!(
+        out,
+        "{} {}|{} {}{}{}{}",
+        gutter,
+        blue,
+        reset,
+        " ".repeat(column),
+        caret,
+        "^".repeat(span),
+        reset
+    )
+    .unwrap();
+    out
+}
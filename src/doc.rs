@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::scanner::{Scanner, Token, TokenType};
+
+// 从源码生成 Markdown 文档
+// 顶层的函数、类和类中的方法各占一节 紧挨在声明前面的 `///` 注释作为说明
+// 只看记号不编译 有语法错误的文件也能生成 只是可能漏掉声明
+pub fn document(title: &str, source: &str) -> String {
+    let docs = doc_comments(source);
+    let tokens: Vec<Token> = Scanner::new(source.into()).collect();
+    let mut out = format!("# {}\n", title);
+
+    let mut depth = 0;
+    let mut class: Option<String> = None; // 正在读的类名
+    let mut class_body = false; // 是否在类体的花括号里
+    for (i, token) in tokens.iter().enumerate() {
+        let previous_line = if i == 0 { 0 } else { tokens[i - 1].line };
+        let next = |n: usize| tokens.get(i + n).map(|token| token.type_);
+        match token.type_ {
+            TokenType::LeftBrace => {
+                depth += 1;
+                if depth == 1 && class.is_some() {
+                    class_body = true;
+                }
+            }
+            TokenType::RightBrace => {
+                depth -= 1;
+                if depth == 0 && class_body {
+                    class = None;
+                    class_body = false;
+                }
+            }
+            TokenType::Fun if depth == 0 && next(1) == Some(TokenType::Identifier) => {
+                let name = &tokens[i + 1].lexeme;
+                out.push_str(&format!("\n## {}({})\n", name, params(&tokens[i + 2..])));
+                push_doc(&mut out, &docs, previous_line, token.line);
+            }
+            TokenType::Class if depth == 0 && next(1) == Some(TokenType::Identifier) => {
+                let name = tokens[i + 1].lexeme.clone();
+                let mut heading = format!("class {}", name);
+                if next(2) == Some(TokenType::Less) && next(3) == Some(TokenType::Identifier) {
+                    heading.push_str(&format!(" < {}", tokens[i + 3].lexeme));
+                }
+                out.push_str(&format!("\n## {}\n", heading));
+                push_doc(&mut out, &docs, previous_line, token.line);
+                class = Some(name);
+            }
+            TokenType::Identifier
+                if depth == 1 && class_body && next(1) == Some(TokenType::LeftParen) =>
+            {
+                let class = class.as_deref().unwrap_or_default();
+                out.push_str(&format!(
+                    "\n### {}.{}({})\n",
+                    class,
+                    token.lexeme,
+                    params(&tokens[i + 1..])
+                ));
+                push_doc(&mut out, &docs, previous_line, token.line);
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+// 每个 `///` 注释行的行号和去掉注释符后的内容 `////` 开头的是普通注释
+fn doc_comments(source: &str) -> HashMap<usize, String> {
+    let mut docs = HashMap::new();
+    for (i, line) in source.lines().enumerate() {
+        let Some(text) = line.trim_start().strip_prefix("///") else {
+            continue;
+        };
+        if text.starts_with('/') {
+            continue;
+        }
+        docs.insert(i + 1, text.strip_prefix(' ').unwrap_or(text).to_string());
+    }
+    docs
+}
+
+// 声明前的文档注释 要和声明之间没有空行 且都在上一个记号之后
+fn push_doc(out: &mut String, docs: &HashMap<usize, String>, previous_line: usize, line: usize) {
+    let mut lines = vec![];
+    let mut n = line - 1;
+    while n > previous_line {
+        match docs.get(&n) {
+            Some(text) => lines.push(text.as_str()),
+            None => break,
+        }
+        n -= 1;
+    }
+    if lines.is_empty() {
+        return;
+    }
+    lines.reverse();
+    out.push('\n');
+    for text in lines {
+        out.push_str(text);
+        out.push('\n');
+    }
+}
+
+// 从左括号开始的参数列表 用逗号连接参数名
+fn params(tokens: &[Token]) -> String {
+    let mut names = vec![];
+    for token in tokens.iter().skip(1) {
+        match token.type_ {
+            TokenType::Identifier => names.push(token.lexeme.as_str()),
+            TokenType::Comma => {}
+            _ => break,
+        }
+    }
+    names.join(", ")
+}
@@ -0,0 +1,45 @@
+// rslox examples 附带的示例程序 源码编译进二进制 按名字运行
+
+pub struct Example {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub source: &'static str,
+}
+
+pub const EXAMPLES: &[Example] = &[
+    Example {
+        name: "closures",
+        description: "Functions that capture variables from their scope",
+        source: include_str!("examples/closures.lox"),
+    },
+    Example {
+        name: "classes",
+        description: "Fields, methods, initializers, inheritance and super",
+        source: include_str!("examples/classes.lox"),
+    },
+    Example {
+        name: "fib",
+        description: "Fibonacci numbers, recursive and iterative, timed with clock()",
+        source: include_str!("examples/fib.lox"),
+    },
+    Example {
+        name: "game",
+        description: "A tiny game loop: a hunter chases a fox along a path",
+        source: include_str!("examples/game.lox"),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Example> {
+    EXAMPLES.iter().find(|example| example.name == name)
+}
+
+// 不带名字时列出所有示例
+pub fn list() -> String {
+    let mut out = String::from("Examples:\n");
+    for example in EXAMPLES {
+        out.push_str(&format!("  {:<10}{}\n", example.name, example.description));
+    }
+    out.push_str("\nRun one with 'rslox examples <name>', or print its annotated source\n");
+    out.push_str("with 'rslox examples <name> --source'.");
+    out
+}
@@ -0,0 +1,22 @@
+use crate::{
+    is_instance,
+    object::{Arity, ObjType},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_finalize_natives() {
+    vm().define_native("setFinalizer", Arity::Exact(2), set_finalizer_native);
+}
+
+// setFinalizer(instance, fn) 实例被回收时调用 fn(instance) 传入nil取消
+// fn 本身是GC根 它引用的对象不会被回收 方法可以传 Class.method 而不是 instance.method
+fn set_finalizer_native(_arg_count: usize, args: *mut Value) -> Value {
+    let (instance, finalizer) = unsafe { (*args, *args.add(1)) };
+    if !is_instance!(instance) {
+        vm().native_error("setFinalizer() expects an instance.".into());
+        return Value::Nil;
+    }
+    vm().set_finalizer(as_obj(instance), finalizer);
+    Value::Nil
+}
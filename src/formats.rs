@@ -0,0 +1,203 @@
+use crate::{
+    as_list, as_string,
+    handle::HandleScope,
+    is_list, is_string, memory, obj_val,
+    object::{Arity, Obj, ObjList, ObjString, ObjType},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_format_natives() {
+    vm().define_native("csvParse", Arity::Exact(1), csv_parse_native);
+    vm().define_native("csvStringify", Arity::Exact(1), csv_stringify_native);
+    vm().define_native("iniParse", Arity::Exact(1), ini_parse_native);
+}
+
+// 取出文本参数 不是字符串时报告运行时错误
+fn text_arg(name: &str, value: Value) -> Option<&'static str> {
+    if !is_string!(value) {
+        vm().native_error(format!("{}() expects a string.", name));
+        return None;
+    }
+    let string = as_string!(value);
+    Some(unsafe { &(*string).chars })
+}
+
+// 新建列表并放进 parent 中 返回的列表经由 parent 可达
+fn push_list(parent: *mut ObjList) -> *mut ObjList {
+    let list = ObjList::new(vec![]);
+    unsafe { (*parent).items.push(obj_val!(list)) };
+    list
+}
+
+fn push_string(list: *mut ObjList, s: &str) {
+    let string = obj_val!(ObjString::take_string(s.into()));
+    unsafe { (*list).items.push(string) };
+}
+
+// 按 RFC 4180 拆分 CSV 引号中的字段可以含逗号、换行和写成 "" 的引号
+// 返回行的列表 末尾的换行不产生空行
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false; // 在引号中
+    let mut pending = false; // 当前行已经有内容 遇到末尾时要收尾
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        pending = true;
+        match c {
+            '"' => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+                pending = false;
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("csvParse() found an unterminated quoted field.".into());
+    }
+    if pending {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+// csvParse(text) 解析 CSV 文本 返回由字符串列表组成的行列表
+fn csv_parse_native(_arg_count: usize, args: *mut Value) -> Value {
+    let Some(text) = text_arg("csvParse", unsafe { *args }) else {
+        return Value::Nil;
+    };
+    let rows = match parse_csv(text) {
+        Ok(rows) => rows,
+        Err(message) => {
+            vm().native_error(message);
+            return Value::Nil;
+        }
+    };
+    // 拆出的字段在对象以外又占了一份和文本差不多大的内存
+    memory::note_external(text.len());
+
+    let scope = HandleScope::new();
+    let result = scope.root(ObjList::new(vec![])).get();
+    for fields in rows {
+        let row = push_list(result);
+        for field in fields {
+            push_string(row, &field);
+        }
+        scope.gc_point();
+    }
+    obj_val!(result)
+}
+
+// 含逗号、引号或换行的字段加上引号 其中的引号写两遍
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// csvStringify(rows) 把行的列表写成 CSV 文本 字段按 print 的形式输出 每行以换行结尾
+fn csv_stringify_native(_arg_count: usize, args: *mut Value) -> Value {
+    let rows = unsafe { *args };
+    if !is_list!(rows) {
+        vm().native_error("csvStringify() expects a list of rows.".into());
+        return Value::Nil;
+    }
+    let mut out = String::new();
+    for row in unsafe { &(*as_list!(rows)).items } {
+        if !is_list!(*row) {
+            vm().native_error("csvStringify() expects each row to be a list.".into());
+            return Value::Nil;
+        }
+        let fields: Vec<String> = unsafe { &(*as_list!(*row)).items }
+            .iter()
+            .map(|field| csv_field(&field.to_string()))
+            .collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    obj_val!(ObjString::take_string(out))
+}
+
+// INI 中的一节 节名和其中的键值对
+type IniSection = (String, Vec<(String, String)>);
+
+// 解析 INI 文本 返回 (节名, [(键, 值)]) 第一个节之前的键放在名为空字符串的节中
+// ; 和 # 开头的行是注释 键和值两边的空白去掉
+fn parse_ini(text: &str) -> Result<Vec<IniSection>, String> {
+    let mut sections = vec![(String::new(), vec![])];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let Some(name) = name.strip_suffix(']') else {
+                return Err(format!("iniParse() expects ']' on line {}.", i + 1));
+            };
+            sections.push((name.trim().to_string(), vec![]));
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!(
+                "iniParse() expects 'key = value' on line {}.",
+                i + 1
+            ));
+        };
+        let entries = &mut sections.last_mut().unwrap().1;
+        entries.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    // 没有用到的无名节不返回
+    if sections[0].1.is_empty() {
+        sections.remove(0);
+    }
+    Ok(sections)
+}
+
+// iniParse(text) 解析 INI 文本 返回 [[节名, [[键, 值], ...]], ...]
+fn ini_parse_native(_arg_count: usize, args: *mut Value) -> Value {
+    let Some(text) = text_arg("iniParse", unsafe { *args }) else {
+        return Value::Nil;
+    };
+    let sections = match parse_ini(text) {
+        Ok(sections) => sections,
+        Err(message) => {
+            vm().native_error(message);
+            return Value::Nil;
+        }
+    };
+
+    let scope = HandleScope::new();
+    let result = scope.root(ObjList::new(vec![])).get();
+    for (name, entries) in sections {
+        let section = push_list(result);
+        push_string(section, &name);
+        let list = push_list(section);
+        for (key, value) in entries {
+            let entry = push_list(list);
+            push_string(entry, &key);
+            push_string(entry, &value);
+        }
+    }
+    obj_val!(result)
+}
@@ -0,0 +1,68 @@
+use std::marker::PhantomData;
+
+use crate::{
+    memory, obj_val,
+    object::{Obj, Object},
+    value::Value,
+    vm::vm,
+};
+
+// 句柄作用域
+// 作用域内创建的句柄都登记在 vm().handles 中作为GC根 作用域析构时统一注销
+// 原生函数在分配对象(可能触发GC)之前 应把手上持有的对象都登记为句柄
+pub struct HandleScope {
+    base: usize, // 进入作用域时根栈的高度
+}
+
+impl HandleScope {
+    pub fn new() -> HandleScope {
+        HandleScope {
+            base: vm().handles.len(),
+        }
+    }
+
+    // 登记对象为GC根 返回的句柄不能活得比作用域更久
+    pub fn root<T: Object>(&self, object: *mut T) -> Handle<'_, T> {
+        vm().handles.push(obj_val!(object));
+        Handle {
+            object,
+            _scope: PhantomData,
+        }
+    }
+
+    // 登记任意值为GC根 用于原生函数暂存的值
+    pub fn root_value(&self, value: Value) {
+        vm().handles.push(value);
+    }
+
+    // 原生函数的长循环中调用 有待执行的回收时立即执行 不用等到返回虚拟机后的安全点
+    // 手上持有的对象都要已经登记在作用域中
+    pub fn gc_point(&self) {
+        if vm().gc_requested {
+            memory::collect_garbage();
+        }
+    }
+}
+
+impl Drop for HandleScope {
+    fn drop(&mut self) {
+        vm().handles.truncate(self.base);
+    }
+}
+
+// 被根住的对象句柄 生命周期绑定在创建它的作用域上
+#[derive(Clone, Copy)]
+pub struct Handle<'s, T: Object> {
+    object: *mut T,
+    _scope: PhantomData<&'s HandleScope>,
+}
+
+impl<T: Object> Handle<'_, T> {
+    pub fn get(&self) -> *mut T {
+        self.object
+    }
+
+    pub fn value(&self) -> Value {
+        obj_val!(self.object)
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    as_buffer, as_string, is_buffer, is_string, obj_val,
+    object::{Arity, Obj, ObjBuffer, ObjString, ObjType},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_hash_natives() {
+    vm().define_native("crc32", Arity::Exact(1), crc32_native);
+    #[cfg(feature = "sha256")]
+    vm().define_native("sha256", Arity::Exact(1), sha256_native);
+}
+
+// 取出要计算哈希的字节 字符串按 UTF-8 编码 缓冲区直接使用
+fn bytes_arg(name: &str, value: Value) -> Option<&'static [u8]> {
+    if is_string!(value) {
+        let string = as_string!(value);
+        Some(unsafe { (*string).chars.as_bytes() })
+    } else if is_buffer!(value) {
+        let buffer = as_buffer!(value);
+        Some(unsafe { &(*buffer).bytes })
+    } else {
+        vm().native_error(format!("{}() expects a string or buffer.", name));
+        None
+    }
+}
+
+fn hex_string(bytes: &[u8]) -> Value {
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    obj_val!(ObjString::take_string(hex))
+}
+
+// CRC-32 (IEEE 802.3) 逐位计算 不需要查找表
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+
+// crc32(s) 字符串或缓冲区的 CRC-32 8位十六进制
+fn crc32_native(_arg_count: usize, args: *mut Value) -> Value {
+    match bytes_arg("crc32", unsafe { *args }) {
+        Some(bytes) => hex_string(&crc32(bytes).to_be_bytes()),
+        None => Value::Nil,
+    }
+}
+
+#[cfg(feature = "sha256")]
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// SHA-256 (FIPS 180-4)
+#[cfg(feature = "sha256")]
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    // 补一个 1 位和若干 0 位 最后 8 字节是消息的位数
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((bytes.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+// sha256(s) 字符串或缓冲区的 SHA-256 64位十六进制
+#[cfg(feature = "sha256")]
+fn sha256_native(_arg_count: usize, args: *mut Value) -> Value {
+    match bytes_arg("sha256", unsafe { *args }) {
+        Some(bytes) => hex_string(&sha256(bytes)),
+        None => Value::Nil,
+    }
+}
@@ -0,0 +1,159 @@
+use std::{fmt::Write, fs, mem::size_of};
+
+use crate::{
+    as_string, is_string,
+    memory::{collect_garbage, for_each_reference},
+    object::{
+        Arity, Obj, ObjBoundMethod, ObjBuffer, ObjBuilder, ObjClass, ObjClosure, ObjFunction,
+        ObjInstance, ObjList, ObjNative, ObjString, ObjType, ObjUnboundMethod, ObjUpvalue,
+        ObjWeakRef,
+    },
+    table::Table,
+    value::Value,
+    vm::vm,
+};
+
+pub fn define_heap_natives() {
+    vm().define_native("heapDump", Arity::Exact(1), heap_dump_native);
+    vm().audit_native("heapDump");
+}
+
+// heapDump(path) 把当前堆写入 path
+fn heap_dump_native(_arg_count: usize, args: *mut Value) -> Value {
+    let path = unsafe { *args };
+    if !is_string!(path) {
+        vm().native_error("heapDump() expects a path string.".into());
+        return Value::Nil;
+    }
+    let path = as_string!(path);
+    if let Err(error) = fs::write(unsafe { (*path).chars.as_str() }, heap_dump()) {
+        vm().native_error(format!("Could not write heap dump: {}.", error));
+    }
+    Value::Nil
+}
+
+// 对象占用的字节数 对象本身加上它独占的 Rust 堆内存(字符串、Vec、HashMap)的估计
+fn object_size(object: *mut Obj) -> usize {
+    let table_size = |table: *mut Table| {
+        if table.is_null() {
+            0
+        } else {
+            size_of::<Table>()
+                + unsafe { (*table).map.capacity() } * size_of::<(*mut ObjString, Value)>()
+        }
+    };
+    unsafe {
+        match (*object).type_ {
+            ObjType::BoundMethod => size_of::<ObjBoundMethod>(),
+            ObjType::Class => {
+                size_of::<ObjClass>() + table_size((*(object as *mut ObjClass)).methods)
+            }
+            ObjType::Closure => {
+                let closure = &*(object as *mut ObjClosure);
+                size_of::<ObjClosure>() + closure.upvalue_count * size_of::<*mut ObjUpvalue>()
+            }
+            ObjType::Function => {
+                let chunk = &(*(object as *mut ObjFunction)).chunk;
+                size_of::<ObjFunction>()
+                    + chunk.code.capacity()
+                    + chunk.lines.capacity() * size_of::<usize>()
+                    + chunk.columns.capacity() * size_of::<usize>()
+                    + chunk.constants.values.capacity() * size_of::<Value>()
+            }
+            ObjType::Instance => {
+                size_of::<ObjInstance>() + table_size((*(object as *mut ObjInstance)).fields)
+            }
+            ObjType::Native => size_of::<ObjNative>(),
+            ObjType::String => {
+                size_of::<ObjString>() + (*(object as *mut ObjString)).chars.capacity()
+            }
+            ObjType::Upvalue => size_of::<ObjUpvalue>(),
+            ObjType::List => {
+                size_of::<ObjList>()
+                    + (*(object as *mut ObjList)).items.capacity() * size_of::<Value>()
+            }
+            ObjType::Buffer => {
+                size_of::<ObjBuffer>() + (*(object as *mut ObjBuffer)).bytes.capacity()
+            }
+            ObjType::Builder => {
+                size_of::<ObjBuilder>() + (*(object as *mut ObjBuilder)).chars.capacity()
+            }
+            ObjType::UnboundMethod => size_of::<ObjUnboundMethod>(),
+            ObjType::WeakRef => size_of::<ObjWeakRef>(),
+        }
+    }
+}
+
+// 只给名字短小的对象输出可读的描述 列表、实例等的打印形式可能很长
+fn object_label(object: *mut Obj) -> Option<String> {
+    match unsafe { (*object).type_ } {
+        ObjType::String
+        | ObjType::Class
+        | ObjType::Closure
+        | ObjType::Function
+        | ObjType::Native
+        | ObjType::BoundMethod
+        | ObjType::UnboundMethod
+        | ObjType::Instance => Some(format!("{}", unsafe { &*object })),
+        _ => None,
+    }
+}
+
+pub fn escape_json(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// 先回收垃圾 再把所有存活对象的类型、大小和引用写成 JSON
+// 对象以地址作为 id 引用列表中的 id 都能在 objects 中找到
+pub fn heap_dump() -> String {
+    collect_garbage();
+
+    let mut out = String::new();
+    writeln!(out, "{{").unwrap();
+    writeln!(out, "  \"bytes_allocated\": {},", vm().bytes_allocated).unwrap();
+    writeln!(out, "  \"objects\": [").unwrap();
+    let mut object = vm().objects;
+    let mut first = true;
+    while !object.is_null() {
+        let type_name = Value::Object(object).type_name();
+        let mut references = vec![];
+        for_each_reference(object, &mut |child| {
+            if !child.is_null() {
+                references.push(format!("\"{:p}\"", child));
+            }
+        });
+
+        if !first {
+            writeln!(out, ",").unwrap();
+        }
+        first = false;
+        write!(
+            out,
+            "    {{\"id\": \"{:p}\", \"type\": \"{}\", \"size\": {}",
+            object,
+            type_name,
+            object_size(object)
+        )
+        .unwrap();
+        if let Some(label) = object_label(object) {
+            write!(out, ", \"label\": \"{}\"", escape_json(&label)).unwrap();
+        }
+        write!(out, ", \"references\": [{}]}}", references.join(", ")).unwrap();
+
+        object = unsafe { (*object).next };
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "  ]").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
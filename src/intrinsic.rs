@@ -0,0 +1,113 @@
+use crate::{
+    as_list, as_string, is_list, is_string,
+    object::{Arity, ObjList, ObjString, ObjType},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+// 编译器认得的常用原生函数 直接调用它们时生成 OP_CALL_INTRINSIC
+// 执行时全局变量仍是原来的原生函数才走快速路径 被覆盖或参数类型不对时退回普通调用
+#[derive(Clone, Copy)]
+pub enum Intrinsic {
+    Abs,
+    Floor,
+    Sqrt,
+    Len,
+}
+
+const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic::Abs,
+    Intrinsic::Floor,
+    Intrinsic::Sqrt,
+    Intrinsic::Len,
+];
+
+impl Intrinsic {
+    pub fn from_byte(byte: u8) -> Option<Intrinsic> {
+        INTRINSICS.get(byte as usize).copied()
+    }
+
+    pub fn from_name(name: &str) -> Option<Intrinsic> {
+        INTRINSICS
+            .iter()
+            .copied()
+            .find(|intrinsic| intrinsic.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Intrinsic::Abs => "abs",
+            Intrinsic::Floor => "floor",
+            Intrinsic::Sqrt => "sqrt",
+            Intrinsic::Len => "len",
+        }
+    }
+
+    // 目前都只接受一个参数
+    pub fn arity(self) -> u8 {
+        1
+    }
+
+    // 快速路径 参数类型不对时返回None 由原生函数报告错误
+    pub fn apply(self, arg: Value) -> Option<Value> {
+        match (self, arg) {
+            (Intrinsic::Abs, Value::Number(n)) => Some(Value::Number(n.abs())),
+            (Intrinsic::Floor, Value::Number(n)) => Some(Value::Number(n.floor())),
+            (Intrinsic::Sqrt, Value::Number(n)) => Some(Value::Number(n.sqrt())),
+            (Intrinsic::Len, _) if is_list!(arg) => {
+                Some(Value::Number(unsafe { (*as_list!(arg)).items.len() } as f64))
+            }
+            (Intrinsic::Len, _) if is_string!(arg) => {
+                let string = as_string!(arg);
+                Some(Value::Number(
+                    unsafe { (*string).chars.chars().count() } as f64
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn define_intrinsic_natives() {
+    vm().define_native("abs", Arity::Exact(1), abs_native);
+    vm().define_native("floor", Arity::Exact(1), floor_native);
+    vm().define_native("sqrt", Arity::Exact(1), sqrt_native);
+}
+
+// 记下每个内联函数对应的原生函数对象 要在定义完所有原生函数之后调用
+// 这些对象同时在内置表中 不需要另外标记
+pub fn register_intrinsics() {
+    for intrinsic in INTRINSICS {
+        let name = ObjString::take_string(intrinsic.name().into());
+        let native = *vm()
+            .builtins
+            .get(name)
+            .expect("intrinsic must be defined as a native");
+        vm().intrinsics.push(native);
+    }
+}
+
+fn number_arg(name: &str, args: *mut Value) -> Option<f64> {
+    match unsafe { *args } {
+        Value::Number(n) => Some(n),
+        _ => {
+            vm().native_error(format!("{}() expects a number.", name));
+            None
+        }
+    }
+}
+
+// abs(x) 绝对值
+fn abs_native(_arg_count: usize, args: *mut Value) -> Value {
+    number_arg("abs", args).map_or(Value::Nil, |n| Value::Number(n.abs()))
+}
+
+// floor(x) 不大于 x 的最大整数
+fn floor_native(_arg_count: usize, args: *mut Value) -> Value {
+    number_arg("floor", args).map_or(Value::Nil, |n| Value::Number(n.floor()))
+}
+
+// sqrt(x) 平方根 负数得到 NaN
+fn sqrt_native(_arg_count: usize, args: *mut Value) -> Value {
+    number_arg("sqrt", args).map_or(Value::Nil, |n| Value::Number(n.sqrt()))
+}
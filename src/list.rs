@@ -0,0 +1,265 @@
+use std::cmp::Ordering;
+
+use crate::{
+    as_buffer, as_builder, as_list, as_string,
+    handle::HandleScope,
+    is_buffer, is_builder, is_list, is_string, obj_val,
+    object::{Arity, Obj, ObjBuffer, ObjBuilder, ObjList, ObjString, ObjType},
+    value::{as_obj, Value},
+    vm::{is_falsey, vm},
+};
+
+pub fn define_list_natives() {
+    vm().define_native("len", Arity::Exact(1), len_native);
+    vm().define_native("push", Arity::Exact(2), push_native);
+    vm().define_native("slice", Arity::Exact(3), slice_native);
+    vm().define_native("sort", Arity::Range(1, 2), sort_native);
+    vm().define_native("map", Arity::Exact(2), map_native);
+    vm().define_native("filter", Arity::Exact(2), filter_native);
+    vm().define_native("reduce", Arity::Exact(3), reduce_native);
+}
+
+// 取出列表参数 不是列表时报告运行时错误
+fn list_arg(name: &str, value: Value) -> Option<*mut ObjList> {
+    if !is_list!(value) {
+        vm().native_error(format!("{}() expects a list.", name));
+        return None;
+    }
+    Some(as_list!(value))
+}
+
+// len(x) 列表元素个数、缓冲区字节数、字符串或构建器中的字符数
+fn len_native(_arg_count: usize, args: *mut Value) -> Value {
+    let value = unsafe { *args };
+    if is_list!(value) {
+        Value::Number(unsafe { (*as_list!(value)).items.len() } as f64)
+    } else if is_buffer!(value) {
+        Value::Number(unsafe { (*as_buffer!(value)).bytes.len() } as f64)
+    } else if is_builder!(value) {
+        Value::Number(unsafe { (*as_builder!(value)).chars.chars().count() } as f64)
+    } else if is_string!(value) {
+        let string = as_string!(value);
+        Value::Number(unsafe { (*string).chars.chars().count() } as f64)
+    } else {
+        vm().native_error("len() expects a list, buffer, builder or string.".into());
+        Value::Nil
+    }
+}
+
+// push(list, value) 追加到列表末尾
+fn push_native(_arg_count: usize, args: *mut Value) -> Value {
+    if let Some(list) = list_arg("push", unsafe { *args }) {
+        unsafe { (*list).items.push(*args.add(1)) };
+    }
+    Value::Nil
+}
+
+// 把切片边界转换为整数 要求 0 <= start <= end <= len
+fn slice_range(start: Value, end: Value, len: usize) -> Option<(usize, usize)> {
+    match (start, end) {
+        (Value::Number(start), Value::Number(end))
+            if start.fract() == 0.0
+                && end.fract() == 0.0
+                && 0.0 <= start
+                && start <= end
+                && end <= len as f64 =>
+        {
+            Some((start as usize, end as usize))
+        }
+        _ => {
+            vm().native_error("Slice range out of bounds.".into());
+            None
+        }
+    }
+}
+
+// slice(x, start, end) 复制列表、缓冲区或字符串中 [start, end) 的部分
+fn slice_native(_arg_count: usize, args: *mut Value) -> Value {
+    let (value, start, end) = unsafe { (*args, *args.add(1), *args.add(2)) };
+    if is_list!(value) {
+        let items = unsafe { &(*as_list!(value)).items };
+        match slice_range(start, end, items.len()) {
+            Some((start, end)) => obj_val!(ObjList::new(items[start..end].to_vec())),
+            None => Value::Nil,
+        }
+    } else if is_buffer!(value) {
+        let bytes = unsafe { &(*as_buffer!(value)).bytes };
+        match slice_range(start, end, bytes.len()) {
+            Some((start, end)) => obj_val!(ObjBuffer::new(bytes[start..end].to_vec())),
+            None => Value::Nil,
+        }
+    } else if is_string!(value) {
+        let string = as_string!(value);
+        let chars = unsafe { &(*string).chars };
+        match slice_range(start, end, chars.chars().count()) {
+            Some((start, end)) => {
+                let sliced = chars.chars().skip(start).take(end - start).collect();
+                obj_val!(ObjString::take_string(sliced))
+            }
+            None => Value::Nil,
+        }
+    } else {
+        vm().native_error("slice() expects a list, buffer or string.".into());
+        Value::Nil
+    }
+}
+
+// 没有比较函数时 数字和字符串按自然顺序比较
+fn natural_order(a: Value, b: Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.partial_cmp(&b),
+        _ if is_string!(a) && is_string!(b) => {
+            let (a, b) = (as_string!(a), as_string!(b));
+            Some(unsafe { (*a).chars.cmp(&(*b).chars) })
+        }
+        _ => None,
+    }
+}
+
+// 比较两个元素 a 是否应该排在 b 之后 出错时返回None
+fn comes_after(comparator: Option<Value>, a: Value, b: Value) -> Option<bool> {
+    match comparator {
+        Some(comparator) => match vm().call_function(comparator, &[a, b])? {
+            Value::Number(n) => Some(n > 0.0),
+            _ => {
+                vm().native_error("Comparator must return a number.".into());
+                None
+            }
+        },
+        None => match natural_order(a, b) {
+            Some(ordering) => Some(ordering == Ordering::Greater),
+            None => {
+                vm().native_error(
+                    "sort() without a comparator can only compare numbers or strings.".into(),
+                );
+                None
+            }
+        },
+    }
+}
+
+// 稳定的归并排序 比较函数可能调用 Lox 代码而且可能出错 所以不用标准库的排序
+fn merge_sort(items: &mut Vec<Value>, comparator: Option<Value>) -> Option<()> {
+    if items.len() <= 1 {
+        return Some(());
+    }
+    let mut right = items.split_off(items.len() / 2);
+    merge_sort(items, comparator)?;
+    merge_sort(&mut right, comparator)?;
+
+    let left = std::mem::take(items);
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        if comes_after(comparator, left[i], right[j])? {
+            items.push(right[j]);
+            j += 1;
+        } else {
+            items.push(left[i]);
+            i += 1;
+        }
+    }
+    items.extend_from_slice(&left[i..]);
+    items.extend_from_slice(&right[j..]);
+    Some(())
+}
+
+// sort(list) 或 sort(list, comparator) 原地排序并返回列表
+// comparator(a, b) 返回负数表示 a 在前 正数表示 b 在前
+fn sort_native(arg_count: usize, args: *mut Value) -> Value {
+    let list = match list_arg("sort", unsafe { *args }) {
+        Some(list) => list,
+        None => return Value::Nil,
+    };
+    let comparator = if arg_count == 2 {
+        Some(unsafe { *args.add(1) })
+    } else {
+        None
+    };
+
+    // 比较函数可能修改原列表 排序用的副本中的元素都要根住
+    let scope = HandleScope::new();
+    let mut items = unsafe { (*list).items.clone() };
+    for item in &items {
+        scope.root_value(*item);
+    }
+    if merge_sort(&mut items, comparator).is_none() {
+        return Value::Nil;
+    }
+    unsafe { (*list).items = items };
+
+    // 比较函数递归较深时栈会扩容 args 指向的旧栈已经释放
+    obj_val!(list)
+}
+
+// map(list, fn) 返回由 fn(item) 组成的新列表
+fn map_native(_arg_count: usize, args: *mut Value) -> Value {
+    let list = match list_arg("map", unsafe { *args }) {
+        Some(list) => list,
+        None => return Value::Nil,
+    };
+    let function = unsafe { *args.add(1) };
+
+    let scope = HandleScope::new();
+    let result = scope.root(ObjList::new(vec![])).get();
+    let mut i = 0;
+    // 回调可能修改原列表 每次都重新检查长度
+    while i < unsafe { (*list).items.len() } {
+        let item = unsafe { (&(*list).items)[i] };
+        match vm().call_function(function, &[item]) {
+            Some(value) => unsafe { (*result).items.push(value) },
+            None => return Value::Nil,
+        }
+        i += 1;
+    }
+
+    obj_val!(result)
+}
+
+// filter(list, fn) 返回 fn(item) 为真的元素组成的新列表
+fn filter_native(_arg_count: usize, args: *mut Value) -> Value {
+    let list = match list_arg("filter", unsafe { *args }) {
+        Some(list) => list,
+        None => return Value::Nil,
+    };
+    let function = unsafe { *args.add(1) };
+
+    let scope = HandleScope::new();
+    let result = scope.root(ObjList::new(vec![])).get();
+    let mut i = 0;
+    while i < unsafe { (*list).items.len() } {
+        let item = unsafe { (&(*list).items)[i] };
+        match vm().call_function(function, &[item]) {
+            Some(keep) if !is_falsey(keep) => unsafe { (*result).items.push(item) },
+            Some(_) => {}
+            None => return Value::Nil,
+        }
+        i += 1;
+    }
+
+    obj_val!(result)
+}
+
+// reduce(list, fn, initial) 依次计算 acc = fn(acc, item)
+fn reduce_native(_arg_count: usize, args: *mut Value) -> Value {
+    let list = match list_arg("reduce", unsafe { *args }) {
+        Some(list) => list,
+        None => return Value::Nil,
+    };
+    let function = unsafe { *args.add(1) };
+
+    let scope = HandleScope::new();
+    let mut accumulator = unsafe { *args.add(2) };
+    let mut i = 0;
+    while i < unsafe { (*list).items.len() } {
+        let item = unsafe { (&(*list).items)[i] };
+        accumulator = match vm().call_function(function, &[accumulator, item]) {
+            Some(value) => value,
+            None => return Value::Nil,
+        };
+        // 中间结果只有这里持有 需要根住
+        scope.root_value(accumulator);
+        i += 1;
+    }
+
+    accumulator
+}
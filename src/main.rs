@@ -1,61 +1,481 @@
+mod buffer;
+mod builder;
+mod cfg;
 mod chunk;
+mod cli;
 mod compiler;
+mod conformance;
+mod coverage;
 mod debug;
+mod deep;
+mod diagnostic;
+mod doc;
+mod examples;
+mod finalize;
+mod formats;
+mod handle;
+mod hash;
+mod heap;
+mod intrinsic;
+mod list;
 mod memory;
+mod module;
 mod object;
+mod output;
+mod path;
+mod plugin;
+mod provider;
+mod reflect;
 mod scanner;
+mod serialize;
+#[cfg(feature = "small_strings")]
+mod small_string;
 mod table;
 mod value;
 mod vm;
+mod weak;
+use cli::Command;
+use coverage::Coverage;
+use handle::HandleScope;
+use object::{Arity, Obj, ObjList, ObjString};
+use scanner::{Scanner, TokenType};
 use std::{
     env, fs,
     io::{self, Write},
+    path::Path,
     process,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
-use vm::{vm, InterpretResult};
+use value::Value;
+use vm::{vm, InterpretResult, Script, WatchTarget};
+
+fn main() {
+    let mut options = match cli::parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("{}", cli::USAGE);
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
+    match options.command {
+        Command::Help => {
+            println!("{}", cli::help());
+            return;
+        }
+        Command::Version => {
+            println!("rslox {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        // 只读源码中的记号 不需要初始化虚拟机
+        Command::Doc(ref path) => match fs::read_to_string(path) {
+            Ok(source) => {
+                let title = Path::new(path).file_name().unwrap_or_default();
+                print!("{}", doc::document(&title.to_string_lossy(), &source));
+                return;
+            }
+            Err(error) => {
+                eprintln!("Could not read '{}': {}", path, error);
+                process::exit(cli::EXIT_IO);
+            }
+        },
+        Command::Examples(None) => {
+            println!("{}", examples::list());
+            return;
+        }
+        Command::Examples(Some(ref name)) if examples::find(name).is_none() => {
+            eprintln!("Unknown example '{}'.", name);
+            eprintln!("{}", examples::list());
+            process::exit(cli::EXIT_USAGE);
+        }
+        Command::Examples(Some(ref name)) if options.source => {
+            print!("{}", examples::find(name).unwrap().source);
+            return;
+        }
+        // 每个用例在子进程中运行 不需要初始化虚拟机
+        Command::Test(ref dir) => match conformance::run_suite(Path::new(dir)) {
+            Ok(true) => return,
+            Ok(false) => process::exit(cli::EXIT_TEST_FAILED),
+            Err(error) => {
+                eprintln!("Could not run tests in '{}': {}", dir, error);
+                process::exit(cli::EXIT_IO);
+            }
+        },
+        _ => {}
+    }
 
-fn main() -> io::Result<()> {
     vm::init_vm();
+    vm().color = options
+        .color
+        .unwrap_or_else(|| diagnostic::parse_color("auto").unwrap());
+    vm().deny_warnings = options.deny_warnings;
+    vm().strict_math = options.strict_math;
+    vm().strict_globals = options.strict;
+    vm().debug_info = options.debug_info;
+    vm().module_cache = !options.no_cache;
+    vm().trace |= options.trace;
+    for name in &options.watch_globals {
+        vm().watch(WatchTarget::Global(name.clone()));
+    }
+    for &slot in &options.watch_locals {
+        vm().watch(WatchTarget::Local(slot));
+    }
+    vm().timeout = options.timeout;
+    if let Some(depth) = options.max_call_depth {
+        vm().set_max_call_depth(depth);
+    }
+    vm().output.buffered = !options.unbuffered;
+    vm().print_code |= options.dump_bytecode;
+    vm().print_stats = options.stats;
+    if options.coverage.is_some() {
+        vm().coverage = Some(Coverage::new());
+    }
+    vm().define_native("args", Arity::Exact(0), args_native);
+    // 先于 LOX_PATH 搜索
+    for dir in &options.paths {
+        vm().module_path.push(dir.into());
+    }
+    for path in &options.plugins {
+        if let Err(message) = plugin::load_plugin(path) {
+            eprintln!("Could not load plugin '{}': {}", path, message);
+            process::exit(cli::EXIT_IO);
+        }
+    }
+    // 可重现的运行 时钟和随机数不再依赖外部环境
+    if options.deterministic {
+        vm().provider = Box::new(provider::DeterministicProvider::new(0));
+    }
+    // 把时钟、随机数的取值录制到日志 或者按日志回放
+    let log = if let Some(path) = &options.record {
+        let inner = std::mem::replace(
+            &mut vm().provider,
+            Box::new(provider::DeterministicProvider::new(0)),
+        );
+        Some((
+            path,
+            provider::RecordingProvider::new(inner, path)
+                .map(|recorder| vm().provider = Box::new(recorder)),
+        ))
+    } else {
+        options.replay.as_ref().map(|path| {
+            (
+                path,
+                provider::ReplayProvider::new(path).map(|replay| vm().provider = Box::new(replay)),
+            )
+        })
+    };
+    if let Some((path, Err(message))) = log {
+        eprintln!("Could not open log '{}': {}", path, message);
+        process::exit(cli::EXIT_IO);
+    }
+    // 每次GC的停顿和内存变化写到标准错误
+    if options.gc_log {
+        vm().subscribe_gc(Box::new(memory::print_gc_event));
+    }
 
-    let args: Vec<String> = env::args().collect();
+    // 需要审计的原生函数调用写到标准错误 只记录不拒绝
+    if options.audit_log {
+        vm().on_audit(Box::new(|name, args| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            eprintln!("audit {}({})", name, args.join(", "));
+            true
+        }));
+    }
 
-    if args.len() == 1 {
-        repl()?;
-    } else if args.len() == 2 {
-        run_file(&args[1])?;
-    } else {
-        eprintln!("Usage: clox [path]");
-        process::exit(64);
+    if !options.no_prelude {
+        vm::load_prelude();
+    }
+
+    // 只统计脚本本身的执行 不含编译
+    if options.time && matches!(options.command, Command::Run(_) | Command::Eval(_)) {
+        let timer = Arc::new(Mutex::new(None));
+        let start = timer.clone();
+        vm().on_start(Box::new(move |_| {
+            *start.lock().unwrap() = Some(Timer::start())
+        }));
+        vm().on_exit(Box::new(move |_, _| {
+            if let Some(timer) = timer.lock().unwrap().take() {
+                timer.report();
+            }
+        }));
+    }
+
+    let args = std::mem::take(&mut options.script_args);
+    let result = match &options.command {
+        Command::Repl => repl().map(|_| 0),
+        Command::Run(path) => run_file(path, options.check, args),
+        Command::Eval(code) => Ok(run_source(code.clone(), None, options.check, args)),
+        Command::Disasm(path) => disasm_file(path).map(|_| 0),
+        Command::Cfg(path) => cfg_file(path).map(|_| 0),
+        Command::Examples(Some(name)) => {
+            let example = examples::find(name).unwrap();
+            let file = format!("examples/{}.lox", example.name);
+            Ok(run_source(example.source.into(), Some(&file), false, args))
+        }
+        Command::Compile(path, out) => compile_file(path, out, !options.no_source_map).map(|_| 0),
+        Command::Help
+        | Command::Version
+        | Command::Doc(_)
+        | Command::Test(_)
+        | Command::Examples(None) => {
+            unreachable!()
+        }
+    };
+    let status = match result {
+        Ok(status) => status,
+        Err(error) => {
+            eprintln!("{}", error);
+            cli::EXIT_IO
+        }
+    };
+    if let Some(path) = &options.heap_dump {
+        if let Err(error) = fs::write(path, heap::heap_dump()) {
+            eprintln!("Could not write heap dump '{}': {}", path, error);
+        }
+    }
+    if let (Some(path), Some(coverage)) = (&options.coverage, &vm().coverage) {
+        let report = if path.ends_with(".json") {
+            coverage.to_json()
+        } else {
+            coverage.to_lcov()
+        };
+        if let Err(error) = fs::write(path, report) {
+            eprintln!("Could not write coverage '{}': {}", path, error);
+        }
+    }
+    if status != 0 {
+        process::exit(status);
     }
 
     vm::drop_vm();
-    Ok(())
+}
+
+// :time 和 --time 的统计 从开始到报告期间的耗时、执行的指令数和分配的字节数
+struct Timer {
+    start: Instant,
+    instructions: u64,
+    allocated: usize,
+}
+
+impl Timer {
+    fn start() -> Timer {
+        Timer {
+            start: Instant::now(),
+            instructions: vm().instruction_count,
+            allocated: vm().total_allocated,
+        }
+    }
+
+    fn report(&self) {
+        eprintln!(
+            "time: {:.3} ms, instructions: {}, allocated: {} bytes",
+            self.start.elapsed().as_secs_f64() * 1000.0,
+            vm().instruction_count - self.instructions,
+            vm().total_allocated - self.allocated
+        );
+    }
+}
+
+// 输入是否还没写完 括号没有闭合或字符串没有结束时继续读下一行
+fn is_incomplete(source: &str) -> bool {
+    let mut depth = 0;
+    for token in Scanner::new(source.into()) {
+        match token.type_ {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth -= 1,
+            TokenType::Error if token.lexeme == "Unterminated string." => return true,
+            _ => {}
+        }
+    }
+    depth > 0
 }
 
 fn repl() -> io::Result<()> {
-    let mut line = String::new();
+    // 每段输入单独编译 顶层声明大多在后面的输入才会用到
+    // 顶层的 var 是会话的局部变量 函数和类仍是全局变量
+    vm().warn_unused_globals = false;
+    // 重新定义的函数和类替换掉旧的 已经存下的引用也跟着更新
+    vm().hot_reload = true;
+    // 会话中的定义都放在单独的领域中 :reset 时换一个新的领域
+    let mut realm = vm().create_realm();
+    vm().enter_realm(&realm);
+
+    let mut input = String::new();
     loop {
-        print!("> ");
+        print!("{}", if input.is_empty() { "> " } else { "... " });
         io::stdout().flush()?;
-        let result = io::stdin().read_line(&mut line)?;
+        let result = io::stdin().read_line(&mut input)?;
         if result == 0 {
             break;
         }
 
-        vm().interpret(line.clone());
-        line.clear();
+        // 清除本次会话中的所有定义和已导入的模块
+        if input.trim() == ":reset" {
+            realm = vm().create_realm();
+            vm().enter_realm(&realm);
+            vm().loaded_modules.clear();
+            vm().reset_repl_locals();
+            input.clear();
+            continue;
+        }
+        // 多行的函数、类定义读完整后一起编译
+        if is_incomplete(&input) {
+            continue;
+        }
+        // :isolated code 在全新的环境中执行 看不到也不影响会话中的定义
+        if let Some(code) = input.trim().strip_prefix(":isolated") {
+            vm().interpret_isolated(code.trim().to_string());
+            input.clear();
+            continue;
+        }
+        // :time expr 打印表达式的值和求值的开销 也可以跟语句
+        if let Some(code) = input.trim().strip_prefix(":time") {
+            let code = code.trim();
+            // 以关键字或花括号开头的是语句 原样执行
+            let statement = Scanner::new(code.into()).peek().is_some_and(|token| {
+                matches!(
+                    token.type_,
+                    TokenType::Var
+                        | TokenType::Fun
+                        | TokenType::Class
+                        | TokenType::Print
+                        | TokenType::If
+                        | TokenType::While
+                        | TokenType::For
+                        | TokenType::Return
+                        | TokenType::Import
+                        | TokenType::LeftBrace
+                )
+            });
+            let source = if statement {
+                code.to_string()
+            } else {
+                format!("print {};", code.trim_end_matches(';'))
+            };
+            let timer = Timer::start();
+            vm().interpret_repl(source);
+            timer.report();
+            input.clear();
+            continue;
+        }
+
+        vm().interpret_repl(input.clone());
+        input.clear();
     }
 
     Ok(())
 }
 
-fn run_file(path: &str) -> io::Result<()> {
+// 只编译不执行 打印所有函数的字节码
+fn disasm_file(path: &str) -> io::Result<()> {
     let source = fs::read_to_string(path)?;
-    let result = vm().interpret(source);
+    vm().print_code = false;
+    let Ok(text) = vm().compile_to_text(source) else {
+        process::exit(cli::EXIT_COMPILE);
+    };
+
+    print!("{}", text);
+    Ok(())
+}
+
+// 只编译不执行 以 Graphviz DOT 格式输出每个函数的控制流图
+fn cfg_file(path: &str) -> io::Result<()> {
+    let source = fs::read_to_string(path)?;
+    vm().print_code = false;
+    let Ok(script) = vm().compile(source) else {
+        process::exit(cli::EXIT_COMPILE);
+    };
+
+    print!("{}", cfg::function_to_dot(script.function()));
+    Ok(())
+}
+
+// 只编译不执行 把字节码写入 .loxb 文件 可以直接运行或被导入
+// 带源码映射时运行时错误的栈回溯能指回源文件中的位置
+fn compile_file(path: &str, out: &str, source_map: bool) -> io::Result<()> {
+    let source = fs::read_to_string(path)?;
+    vm().print_code = false;
+    let Ok(script) = vm().compile_with_file(source, Some(path)) else {
+        process::exit(cli::EXIT_COMPILE);
+    };
+
+    let file = source_map.then_some(path);
+    fs::write(out, serialize::serialize(script.function(), file))
+}
+
+// args() 命令行中脚本之后的参数组成的列表 REPL 中为空列表
+fn args_native(_arg_count: usize, _args: *mut Value) -> Value {
+    let args = vm().context::<Vec<String>>().cloned().unwrap_or_default();
+    let scope = HandleScope::new();
+    let list = scope.root(ObjList::new(vec![])).get();
+    for arg in args {
+        let arg = obj_val!(ObjString::take_string(arg));
+        unsafe { (*list).items.push(arg) };
+    }
+    obj_val!(list)
+}
+
+// 返回进程退出码 check 时只编译不执行 args 作为执行上下文传给 args()
+fn run_file(path: &str, check: bool, mut args: Vec<String>) -> io::Result<i32> {
+    // 主脚本所在目录最先搜索 主脚本本身不会被再次导入
+    let path = Path::new(path);
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let dir = if dir.as_os_str().is_empty() {
+        ".".into()
+    } else {
+        dir
+    };
+    vm().module_path.insert(0, dir);
+    vm().loaded_modules
+        .insert(path.canonicalize().unwrap_or(path.to_path_buf()));
+
+    let bytes = fs::read(path).map_err(|error| {
+        io::Error::new(
+            error.kind(),
+            format!("Could not read '{}': {}", path.display(), error),
+        )
+    })?;
+    if serialize::is_bytecode(&bytes) {
+        return Ok(match serialize::deserialize(&bytes) {
+            Ok(_) if check => 0,
+            Ok(function) => exit_status(vm().run_with_context(&Script::new(function), &mut args)),
+            Err(message) => {
+                eprintln!("{}", message);
+                cli::EXIT_COMPILE
+            }
+        });
+    }
+    match String::from_utf8(bytes) {
+        Ok(source) => Ok(run_source(
+            source,
+            Some(&path.to_string_lossy()),
+            check,
+            args,
+        )),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Could not read '{}': not valid UTF-8.", path.display()),
+        )),
+    }
+}
+
+// 编译并执行源码 返回进程退出码 file 为源码所在的文件
+fn run_source(source: String, file: Option<&str>, check: bool, mut args: Vec<String>) -> i32 {
+    match vm().compile_with_file(source, file) {
+        Ok(_) if check => 0,
+        Ok(script) => exit_status(vm().run_with_context(&script, &mut args)),
+        Err(_) => cli::EXIT_COMPILE,
+    }
+}
 
+fn exit_status(result: InterpretResult) -> i32 {
     match result {
-        InterpretResult::CompileError => process::exit(65),
-        InterpretResult::RuntimeError => process::exit(70),
-        _ => Ok(()),
+        InterpretResult::Ok => 0,
+        InterpretResult::CompileError => cli::EXIT_COMPILE,
+        InterpretResult::RuntimeError => cli::EXIT_RUNTIME,
+        InterpretResult::Timeout => cli::EXIT_TIMEOUT,
+        // run 会一直执行到结束
+        InterpretResult::Yielded => unreachable!(),
     }
 }
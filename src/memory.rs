@@ -1,26 +1,86 @@
 use crate::{
     is_obj, obj_val,
     object::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString,
-        ObjType, ObjUpvalue, Object,
+        Obj, ObjBoundMethod, ObjBuffer, ObjBuilder, ObjClass, ObjClosure, ObjFunction, ObjInstance,
+        ObjList, ObjNative, ObjString, ObjType, ObjUnboundMethod, ObjUpvalue, ObjWeakRef, Object,
     },
     table::Table,
-    value::{as_obj, Value, ValueArray},
+    value::{as_obj, Value},
     vm::vm,
 };
-use std::{alloc::Layout, ptr::null_mut};
+use std::{
+    alloc::Layout,
+    ptr::{null_mut, NonNull},
+    time::{Duration, Instant},
+};
 
 static GC_HEAP_GROW_FACTOR: usize = 2;
 
+// 垃圾回收的遥测事件 通过 vm().subscribe_gc 订阅
+// 监听器在回收过程中被调用 不能分配 Lox 对象
+pub enum GcEvent {
+    Start {
+        bytes_allocated: usize,
+    },
+    End {
+        bytes_before: usize,
+        bytes_after: usize,
+        next_gc: usize,
+        pause: Duration,      // 本次回收的停顿时长
+        allocation_rate: f64, // 上次回收结束以来的分配速率 字节/秒
+    },
+}
+
+// --gc-log 每个事件一行 key=value 便于其他工具解析
+pub fn print_gc_event(event: &GcEvent) {
+    match *event {
+        GcEvent::Start { bytes_allocated } => {
+            eprintln!("gc start bytes={}", bytes_allocated)
+        }
+        GcEvent::End {
+            bytes_before,
+            bytes_after,
+            next_gc,
+            pause,
+            allocation_rate,
+        } => eprintln!(
+            "gc end before={} after={} next={} pause_us={} rate={:.0}",
+            bytes_before,
+            bytes_after,
+            next_gc,
+            pause.as_micros(),
+            allocation_rate
+        ),
+    }
+}
+
+pub type GcListener = Box<dyn FnMut(&GcEvent) + Send>;
+
+fn emit_gc_event(event: GcEvent) {
+    for listener in vm().gc_listeners.iter_mut() {
+        listener(&event);
+    }
+}
+
 pub fn allocate_obj<T: Object>(type_: ObjType) -> *mut T {
     let raw_ptr = allocate::<T>(1);
     unsafe {
         let obj_ptr = raw_ptr as *mut Obj;
         (*obj_ptr).type_ = type_;
         (*obj_ptr).is_marked = false;
-        (*obj_ptr).next = null_mut();
+        // 挂到对象链表上 以便清扫阶段回收
+        (*obj_ptr).next = vm().objects;
+        vm().objects = obj_ptr;
     }
 
+    #[cfg(feature = "debug_log_gc")]
+    println!(
+        "{:p} allocate {} for {}",
+        raw_ptr,
+        std::mem::size_of::<T>(),
+        type_ as i32
+    );
+
     raw_ptr
 }
 
@@ -28,51 +88,122 @@ pub fn allocate<T>(size: usize) -> *mut T {
     let size_of = std::mem::size_of::<T>();
     let add_size = size_of * size;
     vm().bytes_allocated += add_size;
+    vm().total_allocated += add_size;
+    vm().allocation_count += 1;
 
     #[cfg(feature = "debug_stress_gc")]
     collect_garbage();
 
-    if vm().bytes_allocated > vm().next_gc {
+    if vm().bytes_allocated > vm().next_gc || vm().gc_requested {
         collect_garbage();
     }
+    // 零大小的分配不能交给分配器
+    if add_size == 0 {
+        return NonNull::dangling().as_ptr();
+    }
     unsafe {
         let layout = Layout::from_size_align(add_size, std::mem::align_of::<T>()).unwrap();
         std::alloc::alloc(layout) as *mut T
     }
 }
 
+// 原生函数分配了对象以外的大块内存(如缓冲区的字节)后登记
+// 这部分内存不计入 bytes_allocated 加上它超过阈值时请求回收 由安全点、下一次分配或 HandleScope::gc_point 执行
+pub fn note_external(bytes: usize) {
+    vm().external_bytes += bytes;
+    if vm().bytes_allocated + vm().external_bytes > vm().next_gc {
+        vm().gc_requested = true;
+    }
+}
+
 pub fn dealloc<T>(ptr: *mut T, size: usize) {
     let size_of = std::mem::size_of::<T>();
+    vm().bytes_allocated -= size_of * size;
+    if size_of * size == 0 {
+        return;
+    }
     let layout = Layout::from_size_align(size_of * size, std::mem::align_of::<T>()).unwrap();
     unsafe { std::alloc::dealloc(ptr as *mut u8, layout) };
 }
 
-fn collect_garbage() {
-    let before: usize;
-    #[cfg(feature = "debug_log_gc")]
-    {
-        println!("-- gc begin");
-        before = vm().bytes_allocated;
+// 先析构对象持有的 Rust 资源(String/Vec/HashMap) 再释放内存
+fn drop_and_dealloc<T>(ptr: *mut T) {
+    if ptr.is_null() {
+        return;
     }
+    unsafe { std::ptr::drop_in_place(ptr) };
+    dealloc(ptr, 1);
+}
+
+pub fn collect_garbage() {
+    let start = Instant::now();
+    let before = vm().bytes_allocated;
+    vm().gc_count += 1;
+    vm().gc_requested = false;
+    vm().external_bytes = 0;
+    let elapsed = start.duration_since(vm().last_gc_end).as_secs_f64();
+    let allocated = before.saturating_sub(vm().bytes_after_gc);
+    emit_gc_event(GcEvent::Start {
+        bytes_allocated: before,
+    });
 
     mark_roots();
     trace_references();
-    table_remove_white(&mut vm().strings);
+    queue_finalizers();
+    vm().strings.remove_white();
+    clear_weak_refs();
     sweep();
 
     vm().next_gc = vm().bytes_allocated * GC_HEAP_GROW_FACTOR;
 
-    #[cfg(feature = "debug_log_gc")]
-    {
-        println!("-- gc end");
-        println!(
-            "   collected {} bytes (from {} to {}) next at {}",
-            before - vm().bytes_allocated,
-            before,
-            vm().bytes_allocated,
-            vm().next_gc,
-        );
+    let end = Instant::now();
+    vm().last_gc_end = end;
+    vm().bytes_after_gc = vm().bytes_allocated;
+    emit_gc_event(GcEvent::End {
+        bytes_before: before,
+        bytes_after: vm().bytes_allocated,
+        next_gc: vm().next_gc,
+        pause: end - start,
+        allocation_rate: if elapsed > 0.0 {
+            allocated as f64 / elapsed
+        } else {
+            0.0
+        },
+    });
+}
+
+// 有终结器的对象不可达时 取消登记并排队执行终结器
+// 对象及其引用的对象在本轮复活 终结器执行之后的GC才会真正回收
+fn queue_finalizers() {
+    let unreached: Vec<*mut Obj> = vm()
+        .finalizers
+        .keys()
+        .filter(|&&object| unsafe { !(*object).is_marked })
+        .copied()
+        .collect();
+    if unreached.is_empty() {
+        return;
     }
+    for object in unreached {
+        let finalizer = vm().finalizers.remove(&object).unwrap();
+        vm().pending_finalizers
+            .push((finalizer, Value::Object(object)));
+        mark_object(object);
+    }
+    trace_references();
+}
+
+// 清扫前处理弱引用 目标未被标记的置空 弱引用自身也将被回收的从登记表中移除
+fn clear_weak_refs() {
+    vm().weak_refs.retain(|&weak| unsafe {
+        if !(*(weak as *mut Obj)).is_marked {
+            return false;
+        }
+        if !(*weak).target.is_null() && !(*(*weak).target).is_marked {
+            (*weak).target = null_mut();
+        }
+        true
+    });
 }
 
 // 清扫
@@ -114,50 +245,41 @@ fn free_object(object: *mut Obj) {
         ObjType::Class => {
             let class: *mut ObjClass = object as *mut ObjClass;
             unsafe {
-                dealloc::<Table>((*class).methods, 1);
+                drop_and_dealloc::<Table>((*class).methods);
             }
             dealloc::<ObjClass>(object as *mut ObjClass, 1);
         }
         ObjType::Closure => {
             let closure = object as *mut ObjClosure;
             unsafe {
-                dealloc::<ObjUpvalue>(*(*closure).upvalues, (*closure).upvalue_count);
+                dealloc::<*mut ObjUpvalue>((*closure).upvalues, (*closure).upvalue_count);
             }
             dealloc::<ObjClosure>(object as *mut ObjClosure, 1);
         }
         ObjType::Function => {
-            dealloc::<ObjFunction>(object as *mut ObjFunction, 1);
+            drop_and_dealloc::<ObjFunction>(object as *mut ObjFunction);
         }
         ObjType::Instance => {
             let instance = object as *mut ObjInstance;
-            dealloc::<Table>(unsafe { instance.as_ref().unwrap().fields }, 1);
+            drop_and_dealloc::<Table>(unsafe { instance.as_ref().unwrap().fields });
             dealloc::<ObjInstance>(object as *mut ObjInstance, 1);
         }
         ObjType::Native => dealloc::<ObjNative>(object as *mut ObjNative, 1),
         ObjType::String => {
-            dealloc::<ObjString>(object as *mut ObjString, 1);
+            drop_and_dealloc::<ObjString>(object as *mut ObjString);
         }
         ObjType::Upvalue => dealloc::<ObjUpvalue>(object as *mut ObjUpvalue, 1),
-    }
-}
-
-fn table_remove_white(table: *mut Table) {
-    unsafe {
-        for (key, value) in &table.as_ref().unwrap().map {
-            if !key.is_null() && !key.as_ref().unwrap().obj.is_marked {
-                table.as_mut().unwrap().remove(key.clone());
-            }
-            mark_object(key.clone() as *mut Obj);
-            mark_value(value.clone());
-        }
+        ObjType::List => drop_and_dealloc::<ObjList>(object as *mut ObjList),
+        ObjType::Buffer => drop_and_dealloc::<ObjBuffer>(object as *mut ObjBuffer),
+        ObjType::Builder => drop_and_dealloc::<ObjBuilder>(object as *mut ObjBuilder),
+        ObjType::UnboundMethod => dealloc::<ObjUnboundMethod>(object as *mut ObjUnboundMethod, 1),
+        ObjType::WeakRef => dealloc::<ObjWeakRef>(object as *mut ObjWeakRef, 1),
     }
 }
 
 // 跟踪对象
 fn trace_references() {
-    while vm().gray_stack.len() > 0 {
-        let object = vm().gray_stack[vm().gray_stack.len() as usize];
-        vm().gray_stack.pop();
+    while let Some(object) = vm().gray_stack.pop() {
         blacken_object(object);
     }
 }
@@ -171,60 +293,79 @@ fn blacken_object(object: *mut Obj) {
         println!();
     }
 
+    for_each_reference(object, &mut |child| mark_object(child));
+}
+
+// 遍历对象直接引用的其他对象 GC标记和堆转储共用 可能出现空指针
+pub fn for_each_reference(object: *mut Obj, visit: &mut dyn FnMut(*mut Obj)) {
+    let mut visit_value = |value: Value| {
+        if is_obj!(value) {
+            visit(as_obj(value));
+        }
+    };
+    fn visit_table(table: *mut Table, visit_value: &mut dyn FnMut(Value)) {
+        if table.is_null() {
+            return;
+        }
+        for (key, value) in unsafe { &(*table).map } {
+            visit_value(obj_val!(*key));
+            visit_value(*value);
+        }
+    }
+
     match unsafe { (*object).type_ } {
         ObjType::BoundMethod => {
-            let bound = object as *mut ObjBoundMethod;
-            let bound = unsafe { bound.as_ref().unwrap() };
-            mark_value(bound.receiver);
-            mark_object(bound.method as *mut Obj);
+            let bound = unsafe { &*(object as *mut ObjBoundMethod) };
+            visit_value(bound.receiver);
+            visit_value(obj_val!(bound.method));
         }
         ObjType::Class => {
-            let class = object as *mut ObjClass;
-            let class = unsafe { class.as_ref().unwrap() };
-            mark_object(class.name as *mut Obj);
-            mark_table(class.methods);
+            let class = unsafe { &*(object as *mut ObjClass) };
+            visit_value(obj_val!(class.name));
+            if !class.superclass.is_null() {
+                visit_value(obj_val!(class.superclass));
+            }
+            visit_table(class.methods, &mut visit_value);
         }
         ObjType::Closure => {
-            let closure = object as *mut ObjClosure;
-            let closure = unsafe { closure.as_ref().unwrap() };
-            mark_object(closure.function as *mut Obj);
+            let closure = unsafe { &*(object as *mut ObjClosure) };
+            visit_value(obj_val!(closure.function));
             for i in 0..closure.upvalue_count {
-                mark_object(unsafe { closure.upvalues.add(i) } as *mut Obj);
+                visit_value(obj_val!(unsafe { *closure.upvalues.add(i) }));
             }
         }
         ObjType::Function => {
-            let function = object as *mut ObjFunction;
-            let function = unsafe { function.as_ref().unwrap() };
-            mark_object(function.name as *mut Obj);
-            mark_array(&function.chunk.constants);
+            let function = unsafe { &*(object as *mut ObjFunction) };
+            visit_value(obj_val!(function.name));
+            for value in &function.chunk.constants.values {
+                visit_value(*value);
+            }
         }
         ObjType::Instance => {
-            let instance = object as *mut ObjInstance;
-            let instance = unsafe { instance.as_ref().unwrap() };
-            mark_object(instance.class as *mut Obj);
-            mark_table(instance.fields);
+            let instance = unsafe { &*(object as *mut ObjInstance) };
+            visit_value(obj_val!(instance.class));
+            visit_table(instance.fields, &mut visit_value);
         }
-        ObjType::Upvalue => unsafe { mark_value((*(object as *mut ObjUpvalue)).closed) },
-        ObjType::Native | ObjType::String => {}
-    }
-}
-
-// 标记数组
-fn mark_array(array: &ValueArray) {
-    for i in 0..array.count() {
-        mark_value(array.values[i]);
+        ObjType::Upvalue => unsafe { visit_value((*(object as *mut ObjUpvalue)).closed) },
+        ObjType::List => {
+            for item in unsafe { &(*(object as *mut ObjList)).items } {
+                visit_value(*item);
+            }
+        }
+        ObjType::Native => unsafe { visit_value(obj_val!((*(object as *mut ObjNative)).name)) },
+        ObjType::UnboundMethod => unsafe {
+            visit_value(obj_val!((*(object as *mut ObjUnboundMethod)).method))
+        },
+        // 弱引用不算引用目标
+        ObjType::String | ObjType::Buffer | ObjType::Builder | ObjType::WeakRef => {}
     }
 }
 
 // 标记根对象
 fn mark_roots() {
     // 标记虚拟机栈
-    let mut slot = &mut vm().stack as *mut Value;
-    while slot < vm().stack_top {
-        unsafe {
-            mark_value(*slot);
-            slot = slot.add(1);
-        }
+    for i in 0..vm().stack_top {
+        mark_value(vm().stack[i]);
     }
 
     // 闭包
@@ -241,14 +382,47 @@ fn mark_roots() {
         }
     }
 
+    // 原生函数持有的句柄
+    for i in 0..vm().handles.len() {
+        mark_value(vm().handles[i]);
+    }
+
     // 全局变量
     mark_table(&mut vm().globals);
+    mark_table(&mut vm().builtins);
+    for globals in vm().realms.values_mut() {
+        mark_table(globals);
+    }
+    // 终结器
+    for finalizer in vm().finalizers.values() {
+        mark_value(*finalizer);
+    }
+    for (finalizer, object) in vm().pending_finalizers.clone() {
+        mark_value(finalizer);
+        mark_value(object);
+    }
+    // 内置类型的原生方法
+    for methods in vm().builtin_methods.values_mut() {
+        mark_table(methods);
+    }
+    // 编译好等待执行的脚本
+    for function in vm().scripts.clone() {
+        mark_object(function as *mut Obj);
+    }
     mark_compiler_roots();
     mark_object(vm().init_string as *mut Obj);
+    // 单字符字符串缓存
+    for string in vm().char_strings {
+        mark_object(string as *mut Obj);
+    }
 }
 
+// 编译中的函数及其常量表 局部变量和提升值只记录 Token 不持有对象
 fn mark_compiler_roots() {
-    let mut compiler = vm().current_compiler;
+    if vm().compiling.is_null() {
+        return;
+    }
+    let mut compiler = unsafe { (*vm().compiling).current };
     while !compiler.is_null() {
         mark_object(unsafe { compiler.as_ref().unwrap().function } as *mut Obj);
         compiler = unsafe { compiler.as_ref().unwrap().enclosing };
@@ -273,7 +447,7 @@ fn mark_object(object: *mut Obj) {
     {
         print!("{:p} mark ", object);
         obj_val!(object).print();
-        println!("");
+        println!();
     }
 
     unsafe {
@@ -284,8 +458,11 @@ fn mark_object(object: *mut Obj) {
 }
 
 fn mark_table(table: *mut Table) {
+    if table.is_null() {
+        return;
+    }
     for (key, value) in unsafe { &table.as_ref().unwrap().map } {
-        mark_object(key.clone() as *mut Obj);
-        mark_value(value.clone());
+        mark_object(*key as *mut Obj);
+        mark_value(*value);
     }
 }
@@ -0,0 +1,142 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{object::ObjFunction, serialize, vm::vm};
+
+// 搜索路径 脚本所在目录、--path 指定的目录 最后是 LOX_PATH 环境变量中的目录
+pub fn search_path() -> Vec<PathBuf> {
+    let mut path = vm().module_path.clone();
+    if let Some(lox_path) = env::var_os("LOX_PATH") {
+        path.extend(env::split_paths(&lox_path));
+    }
+    path
+}
+
+// 解析模块名
+// 带目录或扩展名的按路径直接使用 裸模块名(import "math")在搜索路径中依次查找 math.lox 和 math.loxb
+pub fn resolve_module(name: &str) -> Option<PathBuf> {
+    if name.contains('/') || name.ends_with(".lox") || name.ends_with(".loxb") {
+        let path = PathBuf::from(name);
+        return path.is_file().then_some(path);
+    }
+
+    for dir in search_path() {
+        for extension in ["lox", "loxb"] {
+            let path = dir.join(format!("{}.{}", name, extension));
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+// FNV-1a 64位哈希
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// 编译缓存目录 $XDG_CACHE_HOME/rslox 或 ~/.cache/rslox
+fn cache_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("rslox")),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache/rslox")),
+    }
+}
+
+// 源码对应的缓存文件 以解释器版本、影响编译的选项、模块路径和源码内容的哈希命名 源码或解释器变化后自然失效
+// 命中缓存时不再编译 会让编译失败的选项(--deny-warnings、--strict)也要算进去
+// 字节码中记着源文件名 同样内容的不同文件不能共用缓存
+fn cache_file(path: &Path, source: &[u8]) -> Option<PathBuf> {
+    let mut key = env!("CARGO_PKG_VERSION").as_bytes().to_vec();
+    key.push(0);
+    key.push(vm().debug_info as u8);
+    key.push(vm().deny_warnings as u8);
+    key.push(vm().strict_globals as u8);
+    key.extend_from_slice(path.to_string_lossy().as_bytes());
+    key.push(0);
+    key.extend_from_slice(source);
+    cache_dir().map(|dir| dir.join(format!("{:016x}.loxb", fnv1a(&key))))
+}
+
+// 读取模块 预编译字节码直接恢复 源码先查编译缓存 未命中时编译并写入缓存
+pub fn load_module(path: &Path) -> Result<*mut ObjFunction, String> {
+    let bytes = fs::read(path).map_err(|error| error.to_string())?;
+    if serialize::is_bytecode(&bytes) {
+        return serialize::deserialize(&bytes);
+    }
+
+    // 缓存命中会跳过编译 需要打印字节码或统计时直接编译
+    let cache = if vm().module_cache && !vm().print_code && !vm().print_stats {
+        cache_file(path, &bytes)
+    } else {
+        None
+    };
+    if let Some(cached) = cache.as_ref().and_then(|file| fs::read(file).ok()) {
+        // 缓存损坏时退回重新编译
+        if let Ok(function) = serialize::deserialize(&cached) {
+            return Ok(function);
+        }
+    }
+
+    let source = String::from_utf8(bytes).map_err(|error| error.to_string())?;
+    // 模块的顶层声明通常给导入方使用
+    let warn_unused_globals = vm().warn_unused_globals;
+    vm().warn_unused_globals = false;
+    let source_file = path.to_string_lossy();
+    let warnings = vm().warning_count;
+    let function = vm().compile_function(source, Some(&source_file));
+    vm().warn_unused_globals = warn_unused_globals;
+
+    if function.is_null() {
+        return Err(format!("Compile error in '{}'.", path.display()));
+    }
+
+    // 有警告的模块不写缓存 否则下次命中时警告就不再出现
+    if let Some(file) = cache.filter(|_| vm().warning_count == warnings) {
+        write_cache(&file, &serialize::serialize(function, Some(&source_file)));
+    }
+    Ok(function)
+}
+
+// 先写同目录下的临时文件再改名 崩溃或并发运行不会留下截断的缓存
+// 写缓存失败不影响执行
+fn write_cache(file: &Path, bytes: &[u8]) {
+    if let Some(dir) = file.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let temp = file.with_extension(format!("loxb.{}.tmp", std::process::id()));
+    if fs::write(&temp, bytes)
+        .and_then(|_| fs::rename(&temp, file))
+        .is_err()
+    {
+        let _ = fs::remove_file(&temp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_is_written_through_a_temp_file() {
+        let dir = env::temp_dir().join(format!("rslox-write-cache-{}", std::process::id()));
+        let file = dir.join("nested").join("0123456789abcdef.loxb");
+        write_cache(&file, b"first");
+        write_cache(&file, b"second");
+        assert_eq!(fs::read(&file).unwrap(), b"second");
+        let names: Vec<_> = fs::read_dir(file.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec![file.file_name().unwrap().to_owned()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,17 +1,22 @@
 use std::{
+    cell::RefCell,
+    fmt,
     hash::Hash,
+    ops::Range,
     ptr::{self, null_mut},
 };
 
 use crate::{
     chunk::Chunk,
-    memory::{allocate, allocate_obj, dealloc},
-    table::Table,
-    value::{Value, as_obj},
+    handle::HandleScope,
+    memory::{allocate, allocate_obj, note_external},
+    plugin::PluginNativeFn,
+    table::{hash_string, Table},
+    value::{as_obj, Value},
     vm::vm,
 };
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum ObjType {
     BoundMethod = 1, // 绑定方法对象
     Class,           // 类对象
@@ -21,6 +26,11 @@ pub enum ObjType {
     Native,          // 原生函数对象
     String,          // 字符串对象
     Upvalue,         // 闭包提升值对象
+    List,            // 列表对象
+    Buffer,          // 字节缓冲区对象
+    Builder,         // 字符串构建器对象
+    UnboundMethod,   // 未绑定方法对象
+    WeakRef,         // 弱引用对象
 }
 
 #[macro_export]
@@ -102,24 +112,74 @@ macro_rules! as_upvalue {
 }
 
 #[macro_export]
-macro_rules! as_closure {
+macro_rules! is_list {
     ($val:expr) => {
-        as_obj($val) as *mut ObjClosure
+        $val.is_obj_type(ObjType::List)
     };
 }
 
-pub trait Object {
-    fn obj_type(&self) -> ObjType;
-    fn print(&mut self);
+#[macro_export]
+macro_rules! as_list {
+    ($val:expr) => {
+        as_obj($val) as *mut ObjList
+    };
+}
+
+#[macro_export]
+macro_rules! is_buffer {
+    ($val:expr) => {
+        $val.is_obj_type(ObjType::Buffer)
+    };
+}
+
+#[macro_export]
+macro_rules! as_buffer {
+    ($val:expr) => {
+        as_obj($val) as *mut ObjBuffer
+    };
+}
+
+#[macro_export]
+macro_rules! is_builder {
+    ($val:expr) => {
+        $val.is_obj_type(ObjType::Builder)
+    };
+}
+
+#[macro_export]
+macro_rules! as_builder {
+    ($val:expr) => {
+        as_obj($val) as *mut ObjBuilder
+    };
 }
 
-macro_rules! obj_val {
-    ($obj:expr) => {
-        Value::Object($obj as *mut Obj)
+#[macro_export]
+macro_rules! as_unbound_method {
+    ($val:expr) => {
+        as_obj($val) as *mut ObjUnboundMethod
     };
 }
 
+#[macro_export]
+macro_rules! as_weak_ref {
+    ($val:expr) => {
+        as_obj($val) as *mut ObjWeakRef
+    };
+}
+
+#[macro_export]
+macro_rules! as_closure {
+    ($val:expr) => {
+        as_obj($val) as *mut ObjClosure
+    };
+}
+
+pub trait Object: fmt::Display {
+    fn obj_type(&self) -> ObjType;
+}
+
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct Obj {
     pub type_: ObjType,  // 对象类型
     pub is_marked: bool, // 是否被标记
@@ -130,42 +190,41 @@ impl Object for Obj {
     fn obj_type(&self) -> ObjType {
         self.type_
     }
-    fn print(&mut self) {
-        match self.type_ {
-            ObjType::BoundMethod => {
-                unsafe { as_bound_method!(Value::Object(self)).as_mut().unwrap() }.print();
-            }
-            ObjType::Class => {
-                (unsafe { as_class!(Value::Object(self)).as_mut().unwrap() }).print();
-            }
-            ObjType::Closure => {
-                (unsafe { as_closure!(Value::Object(self)).as_mut().unwrap() }).print();
-            }
-            ObjType::Function => {
-                (unsafe { as_function!(Value::Object(self)).as_mut().unwrap() }).print();
-            }
-            ObjType::Instance => {
-                (unsafe { as_instance!(Value::Object(self)).as_mut().unwrap() }).print();
-            }
-            ObjType::Native => {
-                (unsafe { as_native!(Value::Object(self)).as_mut().unwrap() }).print();
-            }
-            ObjType::String => {
-                (unsafe { as_string!(Value::Object(self)).as_mut().unwrap() }).print();
-            }
-            ObjType::Upvalue => {
-                (unsafe { as_upvalue!(Value::Object(self)).as_mut().unwrap() }).print();
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = Value::Object(self as *const Obj as *mut Obj);
+        unsafe {
+            match self.obj_type() {
+                ObjType::BoundMethod => write!(f, "{}", *as_bound_method!(value)),
+                ObjType::Class => write!(f, "{}", *as_class!(value)),
+                ObjType::Closure => write!(f, "{}", *as_closure!(value)),
+                ObjType::Function => write!(f, "{}", *as_function!(value)),
+                ObjType::Instance => write!(f, "{}", *as_instance!(value)),
+                ObjType::Native => write!(f, "{}", *as_native!(value)),
+                ObjType::String => write!(f, "{}", *(as_obj(value) as *mut ObjString)),
+                ObjType::Upvalue => write!(f, "{}", *as_upvalue!(value)),
+                ObjType::List => write!(f, "{}", *as_list!(value)),
+                ObjType::Buffer => write!(f, "{}", *as_buffer!(value)),
+                ObjType::Builder => write!(f, "{}", *as_builder!(value)),
+                ObjType::UnboundMethod => write!(f, "{}", *as_unbound_method!(value)),
+                ObjType::WeakRef => write!(f, "{}", *as_weak_ref!(value)),
             }
         }
     }
 }
 
+#[repr(C)]
 pub struct ObjFunction {
     obj: Obj,                 // 公共对象头
     pub arity: usize,         // 参数数
     pub upvalue_count: usize, // 提升值数
     pub chunk: Chunk,         // 函数的字节码块
     pub name: *mut ObjString, // 函数名
+    pub params: Vec<String>,  // 参数名 栈回溯和调试器按名字显示参数
+    pub span: Range<usize>,   // 函数定义在源码中的字节范围 从函数名到右花括号
+    pub max_stack: usize,     // 执行时最多占用的栈槽数 含被调用者和参数
 }
 
 impl ObjFunction {
@@ -175,23 +234,24 @@ impl ObjFunction {
         unsafe {
             (*ptr).arity = 0;
             (*ptr).upvalue_count = 0;
+            (*ptr).max_stack = 0;
             (*ptr).name = null_mut();
             let chunk_ptr = &mut (*ptr).chunk;
             std::ptr::write(chunk_ptr, chunk);
+            std::ptr::write(&mut (*ptr).params, vec![]);
+            std::ptr::write(&mut (*ptr).span, 0..0);
         }
 
         ptr
     }
-}
 
-// 输出函数信息
-fn print_function(function: *mut ObjFunction) {
-    if unsafe { (*function).name.is_null() } {
-        print!("<script>");
-        return;
-    }
-    unsafe {
-        print!("<fn {}>", (*(*function).name).chars);
+    // 反汇编等调试输出使用的函数名 顶层脚本没有名字
+    pub fn display_name(&self) -> &str {
+        if self.name.is_null() {
+            "<script>"
+        } else {
+            unsafe { (*self.name).chars.as_str() }
+        }
     }
 }
 
@@ -199,23 +259,72 @@ impl Object for ObjFunction {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        print_function(self as *mut ObjFunction);
+}
+
+// 输出函数信息
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name.is_null() {
+            return write!(f, "<script>");
+        }
+        write!(f, "<fn {}>", unsafe { &(*self.name).chars })
     }
 }
 
 pub type NativeFn = fn(usize, *mut Value) -> Value;
 
+// 原生函数接受的参数个数 调用前由虚拟机检查
+#[derive(Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    Range(usize, usize), // 闭区间
+    Any,                 // 不检查 插件函数自行处理
+}
+
+impl Arity {
+    pub fn accepts(self, arg_count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => arg_count == n,
+            Arity::Range(min, max) => (min..=max).contains(&arg_count),
+            Arity::Any => true,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::Range(min, max) => write!(f, "{} to {}", min, max),
+            Arity::Any => write!(f, "any number of"),
+        }
+    }
+}
+
+// 原生函数的两种来源 内置的 Rust 函数和插件导出的 C ABI 函数
+#[derive(Clone, Copy)]
+pub enum NativeFunction {
+    Builtin(NativeFn),
+    Plugin(PluginNativeFn),
+}
+
+#[repr(C)]
 pub struct ObjNative {
-    obj: Obj,               // 公共对象头
-    pub function: NativeFn, // 原生函数指针
+    obj: Obj,                     // 公共对象头
+    pub function: NativeFunction, // 原生函数指针
+    pub name: *mut ObjString,     // 函数名 用于错误信息
+    pub arity: Arity,             // 接受的参数个数
+    pub audited: bool,            // 调用前先交给审计回调 见 VM::audit_native
 }
 
 impl ObjNative {
-    pub fn new(function: NativeFn) -> *mut ObjNative {
+    pub fn new(function: NativeFunction, name: *mut ObjString, arity: Arity) -> *mut ObjNative {
         let ptr = allocate_obj::<ObjNative>(ObjType::Native);
         unsafe {
             (*ptr).function = function;
+            (*ptr).name = name;
+            (*ptr).arity = arity;
+            (*ptr).audited = false;
         }
 
         ptr
@@ -226,41 +335,66 @@ impl Object for ObjNative {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        print!("<native fn>");
+}
+
+impl fmt::Display for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn>")
     }
 }
 
+// 字符串对象的内容 small_strings 特性打开时短字符串直接存在对象里
+// 实测并不比 String 快 所以默认不打开
+#[cfg(feature = "small_strings")]
+pub type Chars = crate::small_string::SmallString;
+#[cfg(not(feature = "small_strings"))]
+pub type Chars = String;
+
+#[repr(C)]
 pub struct ObjString {
-    pub obj: Obj,      // 公共对象头
-    pub chars: String, // 字符串
+    pub obj: Obj,     // 公共对象头
+    pub chars: Chars, // 字符串
+    pub hash: u32,    // 内容的哈希 创建时计算
 }
 
 impl ObjString {
-    pub fn new(string: String) -> *mut ObjString {
+    pub fn new(string: String, hash: u32) -> *mut ObjString {
         let ptr = allocate_obj::<ObjString>(ObjType::String);
 
         unsafe {
-            let chars_ptr = &mut (*ptr).chars as *mut String;
-            ptr::write(chars_ptr, string);
+            let chars_ptr = &mut (*ptr).chars as *mut Chars;
+            ptr::write(chars_ptr, Chars::from(string));
+            (*ptr).hash = hash;
         }
 
         ptr
     }
 
     pub fn take_string(string: String) -> *mut ObjString {
-        let new_string = ObjString::new(string);
-
-        let result = vm().strings.get_key(new_string);
-        if let Some(s) = result {
-            dealloc(new_string, 1);
-            return s;
+        // 单个 ASCII 字符的字符串走缓存 下标取字符等热点路径不用查驻留表
+        let cached = match string.as_bytes() {
+            [byte] if byte.is_ascii() => Some(*byte as usize),
+            _ => None,
+        };
+        if let Some(byte) = cached {
+            if !vm().char_strings[byte].is_null() {
+                return vm().char_strings[byte];
+            }
         }
 
-        vm().push(obj_val!(new_string));
-        vm().strings.set(new_string, Value::Nil);
-        vm().pop();
-        new_string
+        let hash = hash_string(&string);
+        let interned = match vm().strings.find(&string, hash) {
+            Some(interned) => interned,
+            None => {
+                let new_string = ObjString::new(string, hash);
+                vm().strings.insert(new_string);
+                new_string
+            }
+        };
+        if let Some(byte) = cached {
+            vm().char_strings[byte] = interned;
+        }
+        interned
     }
 }
 
@@ -268,14 +402,17 @@ impl Object for ObjString {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        print!("{}", self.chars);
+}
+
+impl fmt::Display for ObjString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.chars)
     }
 }
 
 impl Hash for ObjString {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.chars.hash(state);
+        state.write_u32(self.hash);
     }
 }
 
@@ -285,6 +422,7 @@ impl PartialEq for ObjString {
     }
 }
 
+#[repr(C)]
 pub struct ObjUpvalue {
     obj: Obj,                  // 公共对象头
     pub location: *mut Value,  // 捕获的局部变量
@@ -309,12 +447,16 @@ impl Object for ObjUpvalue {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        print!("upvalue");
+}
+
+impl fmt::Display for ObjUpvalue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "upvalue")
     }
 }
 
 // 闭包对象
+#[repr(C)]
 pub struct ObjClosure {
     obj: Obj,                           // 公共对象头
     pub function: *mut ObjFunction,     // 裸函数
@@ -346,16 +488,22 @@ impl Object for ObjClosure {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        print_function(self.function);
+}
+
+impl fmt::Display for ObjClosure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "{}", *self.function) }
     }
 }
 
 // 类对象
+#[repr(C)]
 pub struct ObjClass {
-    obj: Obj,                 // 公共对象头
-    pub name: *mut ObjString, // 类名
-    pub methods: *mut Table,  // 类方法
+    obj: Obj,                      // 公共对象头
+    pub name: *mut ObjString,      // 类名
+    pub superclass: *mut ObjClass, // 父类 没有时为空指针
+    pub methods: *mut Table,       // 类方法
+    pub frozen: bool,              // 冻结后不能再增删方法
 }
 
 impl ObjClass {
@@ -363,6 +511,14 @@ impl ObjClass {
         let ptr = allocate_obj::<ObjClass>(ObjType::Class);
         unsafe {
             (*ptr).name = name;
+            (*ptr).superclass = null_mut();
+            (*ptr).methods = null_mut();
+            (*ptr).frozen = false;
+        }
+        // 分配方法表可能触发GC 先把类对象根住
+        let scope = HandleScope::new();
+        scope.root(ptr);
+        unsafe {
             (*ptr).methods = Table::new();
         }
 
@@ -374,26 +530,35 @@ impl Object for ObjClass {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        unsafe {
-            print!("{}", (*self.name).chars);
-        }
+}
+
+impl fmt::Display for ObjClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "<class {}>", (*self.name).chars) }
     }
 }
 
 // 实例对象
+#[repr(C)]
 pub struct ObjInstance {
     obj: Obj,
     pub class: *mut ObjClass,
     pub fields: *mut Table,
+    pub frozen: bool, // 冻结后不能再设置字段
 }
 
 impl ObjInstance {
     pub fn new(class: *mut ObjClass) -> *mut ObjInstance {
         let ptr = allocate_obj::<ObjInstance>(ObjType::Instance);
-
         unsafe {
             (*ptr).class = class;
+            (*ptr).fields = null_mut();
+            (*ptr).frozen = false;
+        }
+        // 分配字段表可能触发GC 先把实例根住
+        let scope = HandleScope::new();
+        scope.root(ptr);
+        unsafe {
             (*ptr).fields = Table::new();
         }
 
@@ -405,14 +570,16 @@ impl Object for ObjInstance {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
-        unsafe {
-            print!("{} instance", (*(*self.class).name).chars);
-        }
+}
+
+impl fmt::Display for ObjInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "{} instance", (*(*self.class).name).chars) }
     }
 }
 
 // 绑定方法对象
+#[repr(C)]
 pub struct ObjBoundMethod {
     obj: Obj,
     pub receiver: Value,
@@ -435,9 +602,184 @@ impl Object for ObjBoundMethod {
     fn obj_type(&self) -> ObjType {
         self.obj.obj_type()
     }
-    fn print(&mut self) {
+}
+
+impl fmt::Display for ObjBoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "{}", *(*self.method).function) }
+    }
+}
+
+// 未绑定方法 Class.method 调用时第一个参数作为接收者
+#[repr(C)]
+pub struct ObjUnboundMethod {
+    obj: Obj,
+    pub method: *mut ObjClosure,
+}
+
+impl ObjUnboundMethod {
+    pub fn new(method: *mut ObjClosure) -> *mut ObjUnboundMethod {
+        let ptr = allocate_obj::<ObjUnboundMethod>(ObjType::UnboundMethod);
+        unsafe {
+            (*ptr).method = method;
+        }
+        ptr
+    }
+}
+
+impl Object for ObjUnboundMethod {
+    fn obj_type(&self) -> ObjType {
+        self.obj.obj_type()
+    }
+}
+
+impl fmt::Display for ObjUnboundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "{}", *(*self.method).function) }
+    }
+}
+
+// 弱引用 不阻止目标被回收 目标被回收后GC把target置空
+#[repr(C)]
+pub struct ObjWeakRef {
+    obj: Obj,
+    pub target: *mut Obj,
+}
+
+impl ObjWeakRef {
+    pub fn new(target: *mut Obj) -> *mut ObjWeakRef {
+        let ptr = allocate_obj::<ObjWeakRef>(ObjType::WeakRef);
+        unsafe {
+            (*ptr).target = target;
+        }
+        // 登记到虚拟机 GC在清扫前逐个检查目标是否存活
+        vm().weak_refs.push(ptr);
+        ptr
+    }
+}
+
+impl Object for ObjWeakRef {
+    fn obj_type(&self) -> ObjType {
+        self.obj.obj_type()
+    }
+}
+
+impl fmt::Display for ObjWeakRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<weak ref>")
+    }
+}
+
+// 列表对象
+#[repr(C)]
+pub struct ObjList {
+    obj: Obj,
+    pub items: Vec<Value>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> *mut ObjList {
+        let ptr = allocate_obj::<ObjList>(ObjType::List);
+        unsafe {
+            ptr::write(&mut (*ptr).items, items);
+        }
+
+        ptr
+    }
+}
+
+impl Object for ObjList {
+    fn obj_type(&self) -> ObjType {
+        self.obj.obj_type()
+    }
+}
+
+thread_local! {
+    // 正在输出的列表 用来发现循环引用
+    static PRINTING: RefCell<Vec<*const ObjList>> = const { RefCell::new(vec![]) };
+}
+
+impl fmt::Display for ObjList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let this = self as *const ObjList;
+        if PRINTING.with(|printing| printing.borrow().contains(&this)) {
+            return write!(f, "[...]");
+        }
+
+        PRINTING.with(|printing| printing.borrow_mut().push(this));
+        let result = (|| {
+            write!(f, "[")?;
+            for (i, item) in self.items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", item)?;
+            }
+            write!(f, "]")
+        })();
+        PRINTING.with(|printing| printing.borrow_mut().pop());
+        result
+    }
+}
+
+// 字节缓冲区对象 保存任意二进制数据
+#[repr(C)]
+pub struct ObjBuffer {
+    obj: Obj,
+    pub bytes: Vec<u8>,
+}
+
+impl ObjBuffer {
+    pub fn new(bytes: Vec<u8>) -> *mut ObjBuffer {
+        // 字节在对象以外 登记后大缓冲区也会促成回收
+        note_external(bytes.len());
+        let ptr = allocate_obj::<ObjBuffer>(ObjType::Buffer);
         unsafe {
-            print_function((*self.method).function);
+            ptr::write(&mut (*ptr).bytes, bytes);
         }
+
+        ptr
+    }
+}
+
+impl Object for ObjBuffer {
+    fn obj_type(&self) -> ObjType {
+        self.obj.obj_type()
+    }
+}
+
+impl fmt::Display for ObjBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<buffer {} bytes>", self.bytes.len())
+    }
+}
+
+// 字符串构建器对象 追加时原地增长 避免反复拼接的平方复杂度
+#[repr(C)]
+pub struct ObjBuilder {
+    obj: Obj,
+    pub chars: String,
+}
+
+impl ObjBuilder {
+    pub fn new() -> *mut ObjBuilder {
+        let ptr = allocate_obj::<ObjBuilder>(ObjType::Builder);
+        unsafe {
+            ptr::write(&mut (*ptr).chars, String::new());
+        }
+
+        ptr
+    }
+}
+
+impl Object for ObjBuilder {
+    fn obj_type(&self) -> ObjType {
+        self.obj.obj_type()
+    }
+}
+
+impl fmt::Display for ObjBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<builder {} bytes>", self.chars.len())
     }
 }
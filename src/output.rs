@@ -0,0 +1,49 @@
+use std::io::{self, BufWriter, Stdout, Write};
+
+use crate::{object::Arity, value::Value, vm::vm};
+
+// 缓冲的行数达到这个数就写出
+const FLUSH_LINES: usize = 256;
+
+// 程序的标准输出 print 语句先写入缓冲区
+// 缓冲满、执行结束、报告运行时错误或者调用 flush() 时写出
+pub struct Output {
+    writer: BufWriter<Stdout>,
+    pub buffered: bool, // 关闭时每次 print 都立即写出
+    lines: usize,       // 缓冲区中未写出的行数
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            writer: BufWriter::new(io::stdout()),
+            buffered: true,
+            lines: 0,
+        }
+    }
+
+    // 输出一个值并换行
+    pub fn print_line(&mut self, value: Value) {
+        // 标准输出关闭(如管道另一端退出)时丢弃输出
+        let _ = writeln!(self.writer, "{}", value);
+        self.lines += 1;
+        if !self.buffered || self.lines >= FLUSH_LINES {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.writer.flush();
+        self.lines = 0;
+    }
+}
+
+pub fn define_output_natives() {
+    vm().define_native("flush", Arity::Exact(0), flush_native);
+}
+
+// flush() 立即写出缓冲的输出
+fn flush_native(_arg_count: usize, _args: *mut Value) -> Value {
+    vm().output.flush();
+    Value::Nil
+}
@@ -0,0 +1,103 @@
+use std::{fs, path::Path};
+
+use crate::{
+    as_string,
+    handle::HandleScope,
+    is_string, obj_val,
+    object::{Arity, Obj, ObjList, ObjString, ObjType},
+    value::Value,
+    vm::vm,
+};
+
+pub fn define_path_natives() {
+    vm().define_native("listDir", Arity::Exact(1), list_dir_native);
+    vm().define_native("exists", Arity::Exact(1), exists_native);
+    vm().define_native("joinPath", Arity::Exact(2), join_path_native);
+    vm().define_native("basename", Arity::Exact(1), basename_native);
+    vm().define_native("dirname", Arity::Exact(1), dirname_native);
+    // 访问文件系统的要经过审计 只处理字符串的不需要
+    vm().audit_native("listDir");
+    vm().audit_native("exists");
+}
+
+// 取出路径参数 不是字符串时报告运行时错误
+fn path_arg(name: &str, value: Value) -> Option<&'static str> {
+    if !is_string!(value) {
+        vm().native_error(format!("{}() expects a path string.", name));
+        return None;
+    }
+    let string = as_string!(value);
+    Some(unsafe { &(*string).chars })
+}
+
+fn string_val(s: &str) -> Value {
+    obj_val!(ObjString::take_string(s.into()))
+}
+
+// listDir(path) 目录中的文件名列表 按名字排序 不含 . 和 ..
+fn list_dir_native(_arg_count: usize, args: *mut Value) -> Value {
+    let Some(path) = path_arg("listDir", unsafe { *args }) else {
+        return Value::Nil;
+    };
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) => {
+            vm().native_error(format!("Could not list '{}': {}.", path, error));
+            return Value::Nil;
+        }
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+
+    let scope = HandleScope::new();
+    let list = scope.root(ObjList::new(vec![])).get();
+    for name in names {
+        let name = string_val(&name);
+        unsafe { (*list).items.push(name) };
+    }
+    obj_val!(list)
+}
+
+// exists(path) 文件或目录是否存在
+fn exists_native(_arg_count: usize, args: *mut Value) -> Value {
+    match path_arg("exists", unsafe { *args }) {
+        Some(path) => Value::Boolean(Path::new(path).exists()),
+        None => Value::Nil,
+    }
+}
+
+// joinPath(a, b) 连接两段路径 b 是绝对路径时结果就是 b
+fn join_path_native(_arg_count: usize, args: *mut Value) -> Value {
+    let Some(a) = path_arg("joinPath", unsafe { *args }) else {
+        return Value::Nil;
+    };
+    let Some(b) = path_arg("joinPath", unsafe { *args.add(1) }) else {
+        return Value::Nil;
+    };
+    string_val(&Path::new(a).join(b).to_string_lossy())
+}
+
+// basename(path) 路径的最后一段 没有时为空字符串
+fn basename_native(_arg_count: usize, args: *mut Value) -> Value {
+    match path_arg("basename", unsafe { *args }) {
+        Some(path) => {
+            let name = Path::new(path).file_name().unwrap_or_default();
+            string_val(&name.to_string_lossy())
+        }
+        None => Value::Nil,
+    }
+}
+
+// dirname(path) 去掉最后一段后的路径 没有上级时为空字符串
+fn dirname_native(_arg_count: usize, args: *mut Value) -> Value {
+    match path_arg("dirname", unsafe { *args }) {
+        Some(path) => {
+            let parent = Path::new(path).parent().unwrap_or(Path::new(""));
+            string_val(&parent.to_string_lossy())
+        }
+        None => Value::Nil,
+    }
+}
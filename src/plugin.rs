@@ -0,0 +1,175 @@
+// 原生插件
+// 插件是导出 rslox_register 的动态库 加载时把 PluginApi 传给它 插件通过其中的函数注册原生函数
+// 插件与虚拟机之间只通过下面这些 #[repr(C)] 类型交互 与 Rust 内部的 Value 布局无关
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+use crate::{
+    as_string, is_string, obj_val,
+    object::{Arity, NativeFunction, Obj, ObjString, ObjType},
+    value::Value,
+    vm::vm,
+};
+
+// 插件 ABI 版本 结构体布局或语义变化时递增
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+// PluginValue.kind 的取值
+pub const PLUGIN_NIL: u32 = 0;
+pub const PLUGIN_BOOL: u32 = 1;
+pub const PLUGIN_NUMBER: u32 = 2;
+pub const PLUGIN_STRING: u32 = 3;
+pub const PLUGIN_OTHER: u32 = 4; // 其他对象 插件无法访问其内容
+
+// 插件看到的值 string 为以0结尾的 UTF-8 字符串 只在调用期间有效
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginValue {
+    pub kind: u32,
+    pub boolean: bool,
+    pub number: f64,
+    pub string: *const c_char,
+}
+
+// 插件原生函数 成功返回 true 并写入 result
+// 失败返回 false 此时 result.string 为错误信息(可以为空)
+pub type PluginNativeFn =
+    extern "C" fn(arg_count: usize, args: *const PluginValue, result: *mut PluginValue) -> bool;
+
+// 传给 rslox_register 的接口表
+#[repr(C)]
+pub struct PluginApi {
+    pub abi_version: u32,
+    pub define_native: extern "C" fn(name: *const c_char, function: PluginNativeFn),
+}
+
+// 插件入口 返回0表示注册成功
+type RegisterFn = extern "C" fn(api: *const PluginApi) -> c_int;
+
+#[cfg(unix)]
+extern "C" {
+    fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *mut c_char;
+}
+
+#[cfg(unix)]
+const RTLD_NOW: c_int = 2;
+
+#[cfg(unix)]
+fn last_dl_error() -> String {
+    let message = unsafe { dlerror() };
+    if message.is_null() {
+        "unknown error".into()
+    } else {
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+extern "C" fn define_native(name: *const c_char, function: PluginNativeFn) {
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    vm().define_native_function(&name, Arity::Any, NativeFunction::Plugin(function));
+}
+
+// 加载插件并调用其注册函数 插件库不会被卸载
+#[cfg(unix)]
+pub fn load_plugin(path: &str) -> Result<(), String> {
+    let c_path = CString::new(path).map_err(|_| "invalid path".to_string())?;
+    let handle = unsafe { dlopen(c_path.as_ptr(), RTLD_NOW) };
+    if handle.is_null() {
+        return Err(last_dl_error());
+    }
+
+    let symbol = unsafe { dlsym(handle, c"rslox_register".as_ptr()) };
+    if symbol.is_null() {
+        return Err("missing rslox_register".into());
+    }
+    let register: RegisterFn = unsafe { std::mem::transmute(symbol) };
+
+    let api = PluginApi {
+        abi_version: PLUGIN_ABI_VERSION,
+        define_native,
+    };
+    match register(&api) {
+        0 => Ok(()),
+        code => Err(format!("rslox_register returned {}", code)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn load_plugin(_path: &str) -> Result<(), String> {
+    Err("plugins are only supported on unix".into())
+}
+
+// 调用插件原生函数 负责 Value 与 PluginValue 之间的转换
+pub fn call_plugin_native(function: PluginNativeFn, arg_count: usize, args: *mut Value) -> Value {
+    // 字符串参数的副本 调用结束前保持存活
+    let mut strings = vec![];
+    let mut plugin_args = vec![];
+    for i in 0..arg_count {
+        let arg = unsafe { *args.add(i) };
+        let mut value = PluginValue {
+            kind: PLUGIN_OTHER,
+            boolean: false,
+            number: 0.0,
+            string: std::ptr::null(),
+        };
+        match arg {
+            Value::Nil => value.kind = PLUGIN_NIL,
+            Value::Boolean(b) => {
+                value.kind = PLUGIN_BOOL;
+                value.boolean = b;
+            }
+            Value::Number(n) => {
+                value.kind = PLUGIN_NUMBER;
+                value.number = n;
+            }
+            _ if is_string!(arg) => {
+                let string = as_string!(arg);
+                // 内部含0的字符串在0处截断
+                let chars = unsafe { &(*string).chars };
+                let c_string = CString::new(chars.split('\0').next().unwrap()).unwrap();
+                value.kind = PLUGIN_STRING;
+                value.string = c_string.as_ptr();
+                strings.push(c_string);
+            }
+            _ => {}
+        }
+        plugin_args.push(value);
+    }
+
+    let mut result = PluginValue {
+        kind: PLUGIN_NIL,
+        boolean: false,
+        number: 0.0,
+        string: std::ptr::null(),
+    };
+    let ok = function(arg_count, plugin_args.as_ptr(), &mut result);
+
+    let text = || {
+        if result.string.is_null() {
+            None
+        } else {
+            Some(
+                unsafe { CStr::from_ptr(result.string) }
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    };
+    if !ok {
+        let message = text().unwrap_or_else(|| "Plugin native failed.".into());
+        vm().native_error(message);
+        return Value::Nil;
+    }
+    match result.kind {
+        PLUGIN_BOOL => Value::Boolean(result.boolean),
+        PLUGIN_NUMBER => Value::Number(result.number),
+        PLUGIN_STRING => match text() {
+            Some(text) => obj_val!(ObjString::take_string(text)),
+            None => Value::Nil,
+        },
+        _ => Value::Nil,
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::Write,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+// 不确定性的来源 原生函数中的时钟、随机数都通过 vm().provider 取值
+// 替换提供者即可得到可重现的运行 或者录制/回放一次运行
+pub trait Provider: Send {
+    fn clock(&mut self) -> Result<f64, String>; // 程序启动以来的秒数
+    fn random(&mut self) -> Result<f64, String>; // [0, 1) 的随机数
+    fn seed(&mut self, seed: u64); // 重新设定随机数种子
+}
+
+// xorshift64* 伪随机数 返回 [0, 1)
+fn next_random(state: &mut u64) -> f64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    let bits = state.wrapping_mul(0x2545f4914f6cdd1d) >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+// 种子不能为0
+fn seed_state(seed: u64) -> u64 {
+    seed ^ 0x9e3779b97f4a7c15
+}
+
+// 默认提供者 真实时钟 以当前时间为随机种子
+pub struct SystemProvider {
+    start: Instant,
+    state: u64,
+}
+
+impl SystemProvider {
+    pub fn new() -> SystemProvider {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(0);
+        SystemProvider {
+            start: Instant::now(),
+            state: seed_state(seed),
+        }
+    }
+}
+
+impl Provider for SystemProvider {
+    fn clock(&mut self) -> Result<f64, String> {
+        Ok(self.start.elapsed().as_secs_f64())
+    }
+
+    fn random(&mut self) -> Result<f64, String> {
+        Ok(next_random(&mut self.state))
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.state = seed_state(seed);
+    }
+}
+
+// 确定性提供者 每次读时钟前进固定的一毫秒 随机数使用固定种子
+pub struct DeterministicProvider {
+    time: f64,
+    state: u64,
+}
+
+impl DeterministicProvider {
+    pub fn new(seed: u64) -> DeterministicProvider {
+        DeterministicProvider {
+            time: 0.0,
+            state: seed_state(seed),
+        }
+    }
+}
+
+impl Provider for DeterministicProvider {
+    fn clock(&mut self) -> Result<f64, String> {
+        self.time += 0.001;
+        Ok(self.time)
+    }
+
+    fn random(&mut self) -> Result<f64, String> {
+        Ok(next_random(&mut self.state))
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.state = seed_state(seed);
+    }
+}
+
+// 录制 把内部提供者给出的每个值按 "来源 值" 一行写入日志
+pub struct RecordingProvider {
+    inner: Box<dyn Provider>,
+    log: File,
+}
+
+impl RecordingProvider {
+    pub fn new(inner: Box<dyn Provider>, path: &str) -> Result<RecordingProvider, String> {
+        let log = File::create(path).map_err(|error| error.to_string())?;
+        Ok(RecordingProvider { inner, log })
+    }
+
+    // 每个值立即写入 运行时错误退出时日志也是完整的
+    fn record(&mut self, source: &str, value: Result<f64, String>) -> Result<f64, String> {
+        let value = value?;
+        writeln!(self.log, "{} {}", source, value).map_err(|error| error.to_string())?;
+        Ok(value)
+    }
+}
+
+impl Provider for RecordingProvider {
+    fn clock(&mut self) -> Result<f64, String> {
+        let value = self.inner.clock();
+        self.record("clock", value)
+    }
+
+    fn random(&mut self) -> Result<f64, String> {
+        let value = self.inner.random();
+        self.record("random", value)
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.inner.seed(seed);
+    }
+}
+
+// 回放 按顺序返回日志中的值 来源与录制时不一致时报错
+pub struct ReplayProvider {
+    values: VecDeque<(String, f64)>,
+}
+
+impl ReplayProvider {
+    pub fn new(path: &str) -> Result<ReplayProvider, String> {
+        let log = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        let mut values = VecDeque::new();
+        for (i, line) in log.lines().enumerate() {
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(source, value)| Some((source.to_string(), value.parse().ok()?)));
+            match parsed {
+                Some(entry) => values.push_back(entry),
+                None => return Err(format!("Invalid replay log entry on line {}.", i + 1)),
+            }
+        }
+        Ok(ReplayProvider { values })
+    }
+
+    fn next(&mut self, source: &str) -> Result<f64, String> {
+        match self.values.pop_front() {
+            Some((recorded, value)) if recorded == source => Ok(value),
+            Some((recorded, _)) => Err(format!(
+                "Replay diverged: expected {}() but the script called {}().",
+                recorded, source
+            )),
+            None => Err(format!("Replay log exhausted at {}().", source)),
+        }
+    }
+}
+
+impl Provider for ReplayProvider {
+    fn clock(&mut self) -> Result<f64, String> {
+        self.next("clock")
+    }
+
+    fn random(&mut self) -> Result<f64, String> {
+        self.next("random")
+    }
+
+    // 随机数都来自日志 种子不起作用
+    fn seed(&mut self, _seed: u64) {}
+}
@@ -0,0 +1,208 @@
+use crate::{
+    as_bound_method, as_class, as_closure, as_instance, as_string, as_unbound_method,
+    handle::HandleScope,
+    is_class, is_instance, is_string, obj_val,
+    object::{
+        Arity, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjList, ObjString, ObjType,
+        ObjUnboundMethod,
+    },
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_reflect_natives() {
+    vm().define_native("defineMethod", Arity::Exact(3), define_method_native);
+    vm().define_native("removeMethod", Arity::Exact(2), remove_method_native);
+    vm().define_native("freeze", Arity::Exact(1), freeze_native);
+    vm().define_native("isFrozen", Arity::Exact(1), is_frozen_native);
+    vm().define_native("fieldsOf", Arity::Exact(1), fields_of_native);
+    vm().define_native("removeField", Arity::Exact(2), remove_field_native);
+    vm().define_native("locals", Arity::Exact(0), locals_native);
+}
+
+// locals() 调用处仍然有效的局部变量 每项是 [名字, 值] 需要 --debug-info
+fn locals_native(_arg_count: usize, _args: *mut Value) -> Value {
+    if !vm().debug_info {
+        vm().native_error("locals() needs --debug-info.".into());
+        return Value::Nil;
+    }
+    // 原生函数没有栈帧 栈顶的帧就是调用者
+    let frame = &vm().frames[vm().frame_count - 1];
+    let function = unsafe { (*frame.closure).function };
+    let offset = frame.instruction_offset();
+
+    let scope = HandleScope::new();
+    let list = scope.root(ObjList::new(vec![])).get();
+    for local in unsafe { (*function).chunk.live_locals(offset) } {
+        let value = frame.slot(local.slot);
+        let name = scope.root(ObjString::take_string(local.name.clone())).get();
+        let pair = ObjList::new(vec![obj_val!(name), value]);
+        unsafe { (*list).items.push(obj_val!(pair)) };
+    }
+    obj_val!(list)
+}
+
+// 取出 (类, 方法名) 参数 不合法时报告运行时错误
+fn class_and_name(name: &str, args: *mut Value) -> Option<(*mut ObjClass, *mut ObjString)> {
+    let (class, method) = unsafe { (*args, *args.add(1)) };
+    if !is_class!(class) || !is_string!(method) {
+        vm().native_error(format!("{}() expects a class and a method name.", name));
+        return None;
+    }
+    let class = as_class!(class);
+    if unsafe { (*class).frozen } {
+        vm().native_error(format!("{}() cannot modify a frozen class.", name));
+        return None;
+    }
+    Some((class, as_string!(method)))
+}
+
+// 从方法引用或函数中取出方法闭包 只有类中定义的方法才能使用 this
+fn method_closure(value: Value) -> Option<*mut ObjClosure> {
+    if value.is_obj_type(ObjType::Closure) {
+        Some(as_closure!(value))
+    } else if value.is_obj_type(ObjType::UnboundMethod) {
+        Some(unsafe { (*as_unbound_method!(value)).method })
+    } else if value.is_obj_type(ObjType::BoundMethod) {
+        Some(unsafe { (*as_bound_method!(value)).method })
+    } else {
+        None
+    }
+}
+
+// 继承时方法已经复制到子类 修改类的方法后同步到各级子类
+// 子类中的方法仍是从这个类复制来的(与 old 相同)才替换 子类自己覆盖的方法保持不变
+fn replace_method(
+    class: *mut ObjClass,
+    name: *mut ObjString,
+    old: Option<Value>,
+    new: Option<Value>,
+) {
+    let methods = unsafe { &mut *(*class).methods };
+    match new {
+        Some(method) => {
+            methods.set(name, method);
+        }
+        None => methods.remove(name),
+    }
+
+    let mut object = vm().objects;
+    while !object.is_null() {
+        let value = Value::Object(object);
+        object = unsafe { (*object).next };
+        if !is_class!(value) || unsafe { (*as_class!(value)).superclass } != class {
+            continue;
+        }
+        let subclass = as_class!(value);
+        let inherited = unsafe { (*(*subclass).methods).get(name).copied() };
+        if inherited.map(as_obj) == old.map(as_obj) {
+            replace_method(subclass, name, old, new);
+        }
+    }
+}
+
+// defineMethod(class, name, fn) 给类添加或替换方法
+// fn 可以是函数 也可以是其他类的方法引用(如 Mixin.describe) 函数被调用时接收者在槽位0 但不能使用 this
+fn define_method_native(_arg_count: usize, args: *mut Value) -> Value {
+    let Some((class, name)) = class_and_name("defineMethod", args) else {
+        return Value::Nil;
+    };
+    let value = unsafe { *args.add(2) };
+    let Some(method) = method_closure(value) else {
+        vm().native_error("defineMethod() expects a function or a method.".into());
+        return Value::Nil;
+    };
+    // 普通函数不会返回实例 不能作为构造器
+    if name == vm().init_string && value.is_obj_type(ObjType::Closure) {
+        vm().native_error("defineMethod() cannot use a function as 'init'.".into());
+        return Value::Nil;
+    }
+    let old = unsafe { (*(*class).methods).get(name).copied() };
+    replace_method(class, name, old, Some(obj_val!(method)));
+    Value::Nil
+}
+
+// removeMethod(class, name) 删除类自己定义的方法 返回方法是否存在
+// 删除后类及其子类回到父类中的同名方法
+fn remove_method_native(_arg_count: usize, args: *mut Value) -> Value {
+    let Some((class, name)) = class_and_name("removeMethod", args) else {
+        return Value::Nil;
+    };
+    let superclass = unsafe { (*class).superclass };
+    let inherited = if superclass.is_null() {
+        None
+    } else {
+        unsafe { (*(*superclass).methods).get(name).copied() }
+    };
+    let old = unsafe { (*(*class).methods).get(name).copied() };
+    // 与父类中的相同说明是继承来的 不是自己定义的
+    let Some(own) = old.filter(|&method| inherited.map(as_obj) != Some(as_obj(method))) else {
+        return Value::Boolean(false);
+    };
+    replace_method(class, name, Some(own), inherited);
+    Value::Boolean(true)
+}
+
+// fieldsOf(instance) 实例当前的字段名列表 按名字排序 不含方法
+fn fields_of_native(_arg_count: usize, args: *mut Value) -> Value {
+    let value = unsafe { *args };
+    if !is_instance!(value) {
+        vm().native_error("fieldsOf() expects an instance.".into());
+        return Value::Nil;
+    }
+    let fields = unsafe { &(*(*as_instance!(value)).fields).map };
+    let mut names: Vec<*mut ObjString> = fields.keys().copied().collect();
+    names.sort_by(|a, b| unsafe { (**a).chars.cmp(&(**b).chars) });
+    // 字段名都由实例引用着 分配列表时不会被回收
+    obj_val!(ObjList::new(
+        names.into_iter().map(|name| obj_val!(name)).collect()
+    ))
+}
+
+// removeField(instance, name) 删除实例的字段 返回字段是否存在
+// 删除后读取该属性会回到类中的同名方法 没有时报告未定义属性
+fn remove_field_native(_arg_count: usize, args: *mut Value) -> Value {
+    let (value, name) = unsafe { (*args, *args.add(1)) };
+    if !is_instance!(value) || !is_string!(name) {
+        vm().native_error("removeField() expects an instance and a field name.".into());
+        return Value::Nil;
+    }
+    let instance = as_instance!(value);
+    if unsafe { (*instance).frozen } {
+        vm().native_error("removeField() cannot modify a frozen instance.".into());
+        return Value::Nil;
+    }
+    let fields = unsafe { &mut *(*instance).fields };
+    let name = as_string!(name);
+    let existed = fields.get(name).is_some();
+    fields.remove(name);
+    Value::Boolean(existed)
+}
+
+// freeze(x) 冻结实例或类 返回x
+// 冻结实例后不能设置字段 冻结类后不能增删方法 字段中引用的对象本身不受影响
+fn freeze_native(_arg_count: usize, args: *mut Value) -> Value {
+    let value = unsafe { *args };
+    if is_instance!(value) {
+        unsafe { (*as_instance!(value)).frozen = true };
+    } else if is_class!(value) {
+        unsafe { (*as_class!(value)).frozen = true };
+    } else {
+        vm().native_error("freeze() expects an instance or a class.".into());
+        return Value::Nil;
+    }
+    value
+}
+
+// isFrozen(x) 实例或类是否已冻结 其他值返回false
+fn is_frozen_native(_arg_count: usize, args: *mut Value) -> Value {
+    let value = unsafe { *args };
+    let frozen = if is_instance!(value) {
+        unsafe { (*as_instance!(value)).frozen }
+    } else if is_class!(value) {
+        unsafe { (*as_class!(value)).frozen }
+    } else {
+        false
+    };
+    Value::Boolean(frozen)
+}
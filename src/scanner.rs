@@ -1,28 +1,63 @@
+use std::collections::VecDeque;
+
+// 按需扫描的记号流 作为迭代器依次产生记号 最后一个是 Eof
+// peek/peek_nth 可以向前看任意多个记号而不取走它们
 pub struct Scanner {
     pub source: String,
     start: usize,
     current: usize,
     line: usize,
+    lookahead: VecDeque<Token>, // 已经扫描但还没有取走的记号
+    scanned_eof: bool,          // Eof 已经扫描出来 之后不再扫描
+}
+
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        self.fill(1);
+        self.lookahead.pop_front()
+    }
 }
 
 impl Scanner {
     pub fn new(source: String) -> Scanner {
         Scanner {
-            source: source,
+            source,
             start: 0,
             current: 0,
             line: 1,
+            lookahead: VecDeque::new(),
+            scanned_eof: false,
         }
     }
 
-    pub fn scan_token(&mut self) -> Token {
-        if self.is_at_end() {
-            return self.make_token(TokenType::Eof);
+    // 下一个记号 Eof 取走之后返回None
+    pub fn peek(&mut self) -> Option<&Token> {
+        self.peek_nth(0)
+    }
+
+    // 之后的第 n 个记号 peek_nth(0) 就是下一个
+    pub fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        self.fill(n + 1);
+        self.lookahead.get(n)
+    }
+
+    // 扫描到前看缓冲中有 n 个记号或者扫描到 Eof 为止
+    fn fill(&mut self, n: usize) {
+        while self.lookahead.len() < n && !self.scanned_eof {
+            let token = self.scan_token();
+            self.scanned_eof = token.type_ == TokenType::Eof;
+            self.lookahead.push_back(token);
         }
+    }
 
+    fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
-
         self.start = self.current;
+        if self.is_at_end() {
+            return self.make_token(TokenType::Eof);
+        }
 
         let c = self.advance();
         if is_alpha(c) {
@@ -37,6 +72,8 @@ impl Scanner {
             ')' => return self.make_token(TokenType::RightParen),
             '{' => return self.make_token(TokenType::LeftBrace),
             '}' => return self.make_token(TokenType::RightBrace),
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ';' => return self.make_token(TokenType::Semicolon),
             ',' => return self.make_token(TokenType::Comma),
             '.' => return self.make_token(TokenType::Dot),
@@ -44,6 +81,12 @@ impl Scanner {
             '+' => return self.make_token(TokenType::Plus),
             '/' => return self.make_token(TokenType::Slash),
             '*' => return self.make_token(TokenType::Star),
+            '&' => return self.make_token(TokenType::Ampersand),
+            '|' => return self.make_token(TokenType::Pipe),
+            '^' => return self.make_token(TokenType::Caret),
+            '~' => return self.make_token(TokenType::Tilde),
+            '?' => return self.make_token(TokenType::Question),
+            ':' => return self.make_token(TokenType::Colon),
             '!' => {
                 if self.match_('=') {
                     return self.make_token(TokenType::BangEqual);
@@ -61,6 +104,8 @@ impl Scanner {
             '<' => {
                 if self.match_('=') {
                     return self.make_token(TokenType::LessEqual);
+                } else if self.match_('<') {
+                    return self.make_token(TokenType::LessLess);
                 } else {
                     return self.make_token(TokenType::Less);
                 }
@@ -68,6 +113,8 @@ impl Scanner {
             '>' => {
                 if self.match_('=') {
                     return self.make_token(TokenType::GreaterEqual);
+                } else if self.match_('>') {
+                    return self.make_token(TokenType::GreaterGreater);
                 } else {
                     return self.make_token(TokenType::Greater);
                 }
@@ -76,15 +123,19 @@ impl Scanner {
             _ => {}
         }
 
-        return self.error_token("Unexpected character.");
+        // 非 ASCII 字符整个跳过 保证之后的切片落在字符边界上
+        while !self.is_at_end() && (self.source.as_bytes()[self.current] & 0xc0) == 0x80 {
+            self.current += 1;
+        }
+        self.error_token("Unexpected character.")
     }
 
     fn identifier(&mut self) -> Token {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
+        while is_alpha(self.peek_char()) || is_digit(self.peek_char()) {
             self.advance();
         }
         let type_ = self.identifier_type();
-        return self.make_token(type_);
+        self.make_token(type_)
     }
 
     fn identifier_type(&mut self) -> TokenType {
@@ -92,29 +143,31 @@ impl Scanner {
             'a' => return self.check_keyword(1, 2, "nd", TokenType::And),
             'c' => return self.check_keyword(1, 4, "lass", TokenType::Class),
             'e' => return self.check_keyword(1, 3, "lse", TokenType::Else),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.source.as_bytes()[self.start + 1] as char {
-                        'a' => return self.check_keyword(2, 3, "lse", TokenType::False),
-                        'o' => return self.check_keyword(2, 1, "r", TokenType::For),
-                        'u' => return self.check_keyword(2, 1, "n", TokenType::Fun),
-                        _ => {}
-                    }
+            'f' if self.current - self.start > 1 => {
+                match self.source.as_bytes()[self.start + 1] as char {
+                    'a' => return self.check_keyword(2, 3, "lse", TokenType::False),
+                    'o' => return self.check_keyword(2, 1, "r", TokenType::For),
+                    'u' => return self.check_keyword(2, 1, "n", TokenType::Fun),
+                    _ => {}
+                }
+            }
+            'i' if self.current - self.start > 1 => {
+                match self.source.as_bytes()[self.start + 1] as char {
+                    'f' => return self.check_keyword(1, 1, "f", TokenType::If),
+                    'm' => return self.check_keyword(2, 4, "port", TokenType::Import),
+                    _ => {}
                 }
             }
-            'i' => return self.check_keyword(1, 1, "f", TokenType::If),
             'n' => return self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => return self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => return self.check_keyword(1, 4, "rint", TokenType::Print),
             'r' => return self.check_keyword(1, 5, "eturn", TokenType::Return),
             's' => return self.check_keyword(1, 4, "uper", TokenType::Super),
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.source.as_bytes()[self.start + 1] as char {
-                        'h' => return self.check_keyword(2, 2, "is", TokenType::This),
-                        'r' => return self.check_keyword(2, 2, "ue", TokenType::True),
-                        _ => {}
-                    }
+            't' if self.current - self.start > 1 => {
+                match self.source.as_bytes()[self.start + 1] as char {
+                    'h' => return self.check_keyword(2, 2, "is", TokenType::This),
+                    'r' => return self.check_keyword(2, 2, "ue", TokenType::True),
+                    _ => {}
                 }
             }
             'v' => return self.check_keyword(1, 2, "ar", TokenType::Var),
@@ -132,7 +185,10 @@ impl Scanner {
         rest: &str,
         type_: TokenType,
     ) -> TokenType {
-        if self.current - self.start == start + length && self.sub_current() == rest {
+        let begin = self.start + start;
+        if self.current - self.start == start + length
+            && &self.source.as_bytes()[begin..begin + length] == rest.as_bytes()
+        {
             return type_;
         }
 
@@ -140,26 +196,26 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while is_digit(self.peek()) {
+        while is_digit(self.peek_char()) {
             self.advance();
         }
 
         // Look for a fractional part.
-        if self.peek() == '.' && is_digit(self.peek_next()) {
+        if self.peek_char() == '.' && is_digit(self.peek_next_char()) {
             // Consume the ".".
             self.advance();
 
-            while is_digit(self.peek()) {
+            while is_digit(self.peek_char()) {
                 self.advance();
             }
         }
 
-        return self.make_token(TokenType::Number);
+        self.make_token(TokenType::Number)
     }
 
     fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
+        while self.peek_char() != '"' && !self.is_at_end() {
+            if self.peek_char() == '\n' {
                 self.line += 1;
             }
             self.advance();
@@ -171,12 +227,12 @@ impl Scanner {
 
         // The closing quote.
         self.advance();
-        return self.make_token(TokenType::String);
+        self.make_token(TokenType::String)
     }
 
     fn skip_whitespace(&mut self) {
         loop {
-            let c = self.peek();
+            let c = self.peek_char();
             match c {
                 ' ' | '\r' | '\t' => {
                     self.advance();
@@ -185,14 +241,10 @@ impl Scanner {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next_char() == '/' => {
+                    // A comment goes until the end of the line.
+                    while self.peek_char() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -200,15 +252,19 @@ impl Scanner {
         }
     }
 
-    fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        return self.source.as_bytes()[self.current + 1] as char;
+    // 越过末尾时返回 '\0'
+    fn peek_next_char(&self) -> char {
+        self.source
+            .as_bytes()
+            .get(self.current + 1)
+            .map_or('\0', |&byte| byte as char)
     }
 
-    fn peek(&self) -> char {
-        return self.source.as_bytes()[self.current] as char;
+    fn peek_char(&self) -> char {
+        self.source
+            .as_bytes()
+            .get(self.current)
+            .map_or('\0', |&byte| byte as char)
     }
 
     pub fn match_(&mut self, expected: char) -> bool {
@@ -228,41 +284,48 @@ impl Scanner {
     }
 
     fn is_at_end(&self) -> bool {
-        self.current >= self.source.len() - 1
+        self.current >= self.source.len()
     }
 
     fn make_token(&self, type_: TokenType) -> Token {
         Token {
-            type_: type_,
+            type_,
             start: self.start,
             length: self.current - self.start,
             line: self.line,
-            message: self.sub_current(),
+            column: self.column(),
+            lexeme: self.sub_current(),
         }
     }
 
     fn error_token(&self, message: &str) -> Token {
         Token {
             type_: TokenType::Error,
-            start: 0,
-            length: message.len(),
+            start: self.start,
+            length: self.current - self.start,
             line: self.line,
-            message: message.into(),
+            column: self.column(),
+            lexeme: message.into(),
         }
     }
 
+    // 记号起始处的列号 从1开始按字符计
+    fn column(&self) -> usize {
+        let line_start = self.source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        self.source[line_start..self.start].chars().count() + 1
+    }
+
     fn sub_current(&self) -> String {
-        String::from_utf8((self.source.as_bytes()[self.start..self.start + self.current]).to_vec())
-            .unwrap()
+        String::from_utf8_lossy(&self.source.as_bytes()[self.start..self.current]).into_owned()
     }
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == '_'
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -271,6 +334,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -286,6 +351,14 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    LessLess,
+    GreaterGreater,
+    Question,
+    Colon,
     Identifier,
     String,
     Number,
@@ -296,6 +369,7 @@ pub enum TokenType {
     For,
     Fun,
     If,
+    Import,
     Nil,
     Or,
     Print,
@@ -315,7 +389,8 @@ pub struct Token {
     pub start: usize,
     pub length: usize,
     pub line: usize,
-    pub message: String,
+    pub column: usize,
+    pub lexeme: String, // 源码中的原文 错误记号中是错误信息
 }
 
 impl Token {
@@ -325,7 +400,13 @@ impl Token {
             start: 0,
             length: 0,
             line: 0,
-            message: String::new(),
+            column: 0,
+            lexeme: String::new(),
         }
     }
+
+    // 字符串字面量的内容 去掉两边的引号
+    pub fn string_value(&self) -> &str {
+        &self.lexeme[1..self.lexeme.len() - 1]
+    }
 }
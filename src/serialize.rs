@@ -0,0 +1,367 @@
+use crate::{
+    as_function, as_string, cfg,
+    chunk::{Chunk, LocalInfo, OpCode, Operands},
+    handle::HandleScope,
+    intrinsic::Intrinsic,
+    is_string, obj_val,
+    object::{Obj, ObjFunction, ObjString, ObjType},
+    value::{as_obj, Value},
+};
+
+// 预编译字节码文件(.loxb)的格式
+// 文件头为魔数、版本号和标志 之后是顶层函数 嵌套函数出现在常量表中
+// 带源码映射时标志之后是源文件名 每个函数在行号表之后多一张列号表
+// 整数均为小端序 u32 数字常量为小端序 f64
+const MAGIC: &[u8; 4] = b"LOXB";
+const VERSION: u8 = 5;
+
+// 文件头标志
+const FLAG_SOURCE_MAP: u8 = 1;
+
+// 常量标签
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+// 把编译好的顶层函数序列化为字节 给出源文件名时附带源码映射
+pub fn serialize(function: *mut ObjFunction, file: Option<&str>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    match file {
+        Some(file) => {
+            out.push(FLAG_SOURCE_MAP);
+            write_str(&mut out, file);
+        }
+        None => out.push(0),
+    }
+    write_function(&mut out, function, file.is_some());
+    out
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: *mut ObjFunction, source_map: bool) {
+    let function = unsafe { function.as_ref().unwrap() };
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalue_count);
+    if function.name.is_null() {
+        out.push(0);
+    } else {
+        out.push(1);
+        write_str(out, unsafe { &(*function.name).chars });
+    }
+    write_u32(out, function.params.len());
+    for param in &function.params {
+        write_str(out, param);
+    }
+    write_u32(out, function.span.start);
+    write_u32(out, function.span.end);
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+    for line in &chunk.lines {
+        write_u32(out, *line);
+    }
+    if source_map {
+        // 没有列号的函数(如从不带映射的字节码恢复的)写 0
+        for i in 0..chunk.code.len() {
+            write_u32(out, chunk.columns.get(i).copied().unwrap_or(0));
+        }
+    }
+    write_u32(out, chunk.locals.len());
+    for local in &chunk.locals {
+        write_str(out, &local.name);
+        write_u32(out, local.slot);
+        write_u32(out, local.start);
+        write_u32(out, local.end);
+    }
+
+    write_u32(out, chunk.constants.values.len());
+    for value in &chunk.constants.values {
+        match *value {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Boolean(false) => out.push(TAG_FALSE),
+            Value::Boolean(true) => out.push(TAG_TRUE),
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            _ if is_string!(*value) => {
+                out.push(TAG_STRING);
+                let string = as_string!(*value);
+                write_str(out, unsafe { &(*string).chars });
+            }
+            _ if value.is_obj_type(ObjType::Function) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, as_function!(*value), source_map);
+            }
+            // 编译器只会产生以上几种常量
+            _ => unreachable!(),
+        }
+    }
+}
+
+// 读取字节时的游标
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn read(&mut self, n: usize) -> Result<&[u8], String> {
+        if self.offset + n > self.bytes.len() {
+            return Err("Unexpected end of bytecode file.".into());
+        }
+        let slice = &self.bytes[self.offset..self.offset + n];
+        self.offset += n;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<usize, String> {
+        let bytes = self.read(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn read_str(&mut self) -> Result<String, String> {
+        let len = self.read_u32()?;
+        String::from_utf8(self.read(len)?.to_vec())
+            .map_err(|_| "Invalid string in bytecode file.".into())
+    }
+}
+
+// 判断字节是否为预编译字节码
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// 从字节恢复顶层函数
+pub fn deserialize(bytes: &[u8]) -> Result<*mut ObjFunction, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.read(4)? != MAGIC {
+        return Err("Not a bytecode file.".into());
+    }
+    let version = reader.read_u8()?;
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {}.", version));
+    }
+    let file = match reader.read_u8()? {
+        0 => None,
+        FLAG_SOURCE_MAP => Some(reader.read_str()?),
+        flags => return Err(format!("Invalid bytecode flags {}.", flags)),
+    };
+    read_function(&mut reader, &file)
+}
+
+fn read_function(reader: &mut Reader, file: &Option<String>) -> Result<*mut ObjFunction, String> {
+    // 函数在填充常量前就要根住 后续分配可能触发GC
+    let scope = HandleScope::new();
+    let function = scope.root(ObjFunction::new()).get();
+    unsafe {
+        (*function).arity = reader.read_u32()?;
+        (*function).upvalue_count = reader.read_u32()?;
+        if reader.read_u8()? != 0 {
+            let name = reader.read_str()?;
+            (*function).name = ObjString::take_string(name);
+        }
+        for _ in 0..reader.read_u32()? {
+            let param = reader.read_str()?;
+            (*function).params.push(param);
+        }
+        (*function).span = reader.read_u32()?..reader.read_u32()?;
+    }
+
+    let chunk = unsafe { &mut (*function).chunk };
+    let count = reader.read_u32()?;
+    chunk.code = reader.read(count)?.to_vec();
+    for _ in 0..count {
+        chunk.lines.push(reader.read_u32()?);
+    }
+    if file.is_some() {
+        for _ in 0..count {
+            chunk.columns.push(reader.read_u32()?);
+        }
+        chunk.file = file.clone();
+    }
+    for _ in 0..reader.read_u32()? {
+        chunk.locals.push(LocalInfo {
+            name: reader.read_str()?,
+            slot: reader.read_u32()?,
+            start: reader.read_u32()?,
+            end: reader.read_u32()?,
+        });
+    }
+
+    let constants = reader.read_u32()?;
+    for _ in 0..constants {
+        let value = match reader.read_u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Boolean(false),
+            TAG_TRUE => Value::Boolean(true),
+            TAG_NUMBER => {
+                let bytes = reader.read(8)?;
+                Value::Number(f64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            TAG_STRING => obj_val!(ObjString::take_string(reader.read_str()?)),
+            TAG_FUNCTION => obj_val!(read_function(reader, file)?),
+            tag => return Err(format!("Invalid constant tag {}.", tag)),
+        };
+        chunk.constants.values.push(value);
+    }
+
+    validate_chunk(chunk)?;
+    // 不信任文件中的数据 栈深度按字节码重新计算
+    let max_stack = cfg::max_stack_depth(chunk, unsafe { (*function).arity } + 1)
+        .map_err(|offset| format!("Unbalanced stack at offset {}.", offset))?;
+    unsafe { (*function).max_stack = max_stack };
+    validate_slots(unsafe { &*function })?;
+    Ok(function)
+}
+
+// 检查字节码的结构 操作码合法、常量下标和类型正确、指令和跳转目标不超出末尾
+// 再经过栈深度和 validate_slots 的检查 执行和反汇编都不会因为坏字节崩溃
+fn validate_chunk(chunk: &Chunk) -> Result<(), String> {
+    let constants = &chunk.constants.values;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let byte = chunk.code[offset];
+        let Some(op) = OpCode::from_byte(byte) else {
+            return Err(format!("Invalid opcode {} at offset {}.", byte, offset));
+        };
+        let operands = op.info().operands;
+        if !matches!(operands, Operands::None) && offset + 1 >= chunk.code.len() {
+            return Err("Truncated instruction at end of bytecode.".into());
+        }
+        let operand = chunk.code.get(offset + 1).copied().unwrap_or(0) as usize;
+        let uses_constant = matches!(
+            operands,
+            Operands::Constant | Operands::Invoke | Operands::WideInvoke | Operands::Closure
+        );
+        if uses_constant && operand >= constants.len() {
+            return Err(format!("Invalid constant index at offset {}.", offset));
+        }
+        if let Operands::Intrinsic = operands {
+            if Intrinsic::from_byte(operand as u8).is_none() {
+                return Err(format!("Invalid intrinsic at offset {}.", offset));
+            }
+        }
+        // 除了 OP_CONSTANT 其他指令的常量都是名字
+        let uses_name = uses_constant && !matches!(op, OpCode::Constant | OpCode::Closure);
+        if uses_name && !is_string!(constants[operand]) {
+            return Err(format!("Expected a name constant at offset {}.", offset));
+        }
+        if let Operands::Closure = operands {
+            if !constants[operand].is_obj_type(ObjType::Function) {
+                return Err(format!("Closure without a function at offset {}.", offset));
+            }
+        }
+        let len = chunk.instruction_len(offset);
+        if offset + len > chunk.code.len() {
+            return Err("Truncated instruction at end of bytecode.".into());
+        }
+        // 跳转目标必须落在字节码内
+        let jump = || ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+        let target = match operands {
+            Operands::Jump => Some(offset + 3 + jump()),
+            Operands::Loop => (offset + 3).checked_sub(jump()),
+            _ => Some(offset),
+        };
+        if !matches!(target, Some(target) if target <= chunk.code.len()) {
+            return Err(format!("Invalid jump target at offset {}.", offset));
+        }
+        offset += len;
+    }
+    Ok(())
+}
+
+// 局部变量槽位不超出函数的栈深度 提升值下标不超出提升值个数
+// 闭包捕获的局部变量和提升值也一样 max_stack 要先按字节码算好
+fn validate_slots(function: &ObjFunction) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let local_ok = |slot: u8| (slot as usize) < function.max_stack;
+    let upvalue_ok = |index: u8| (index as usize) < function.upvalue_count;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let operand = chunk.code.get(offset + 1).copied().unwrap_or(0);
+        let valid = match OpCode::from(chunk.code[offset]) {
+            OpCode::GetLocal | OpCode::SetLocal => local_ok(operand),
+            OpCode::GetUpvalue | OpCode::SetUpvalue => upvalue_ok(operand),
+            OpCode::Closure => {
+                // 捕获描述的个数按嵌套函数的提升值个数算出 见 instruction_len
+                let captures = &chunk.code[offset + 2..offset + chunk.instruction_len(offset)];
+                captures.chunks(2).all(|capture| match capture[0] {
+                    0 => upvalue_ok(capture[1]),
+                    1 => local_ok(capture[1]),
+                    _ => false,
+                })
+            }
+            _ => true,
+        };
+        if !valid {
+            return Err(format!("Invalid slot operand at offset {}.", offset));
+        }
+        offset += chunk.instruction_len(offset);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{drop_vm, init_vm};
+
+    // 用给定的字节码构造函数 写成字节码文件再读回来
+    fn round_trip(code: &[u8], nested_upvalues: Option<usize>) -> Result<*mut ObjFunction, String> {
+        let scope = HandleScope::new();
+        let function = scope.root(ObjFunction::new()).get();
+        if let Some(count) = nested_upvalues {
+            let nested = scope.root(ObjFunction::new()).get();
+            unsafe {
+                (*nested).upvalue_count = count;
+                (*nested).chunk.write_chunk(OpCode::Nil as u8, 1, 1);
+                (*nested).chunk.write_chunk(OpCode::Return as u8, 1, 1);
+                (*function).chunk.add_constant(obj_val!(nested));
+            }
+        }
+        for &byte in code {
+            unsafe { (*function).chunk.write_chunk(byte, 1, 1) };
+        }
+        deserialize(&serialize(function, None))
+    }
+
+    // 越界的局部变量、提升值 栈深度不足 都在读入时拒绝
+    #[test]
+    fn rejects_out_of_range_slots_and_stack_underflow() {
+        init_vm();
+        let (nil, pop, ret) = (OpCode::Nil as u8, OpCode::Pop as u8, OpCode::Return as u8);
+        let get_local = OpCode::GetLocal as u8;
+        let get_upvalue = OpCode::GetUpvalue as u8;
+        let closure = OpCode::Closure as u8;
+
+        assert!(round_trip(&[nil, ret], None).is_ok());
+        assert!(round_trip(&[get_upvalue, 7, pop, nil, ret], None).is_err());
+        assert!(round_trip(&[pop, pop, nil, ret], None).is_err());
+        assert!(round_trip(&[get_local, 250, pop, nil, ret], None).is_err());
+
+        // 捕获槽位1的局部变量 槽位9不存在 提升值0在脚本中不存在
+        let capture = |is_local, index| [nil, closure, 0, is_local, index, pop, pop, nil, ret];
+        assert!(round_trip(&capture(1, 1), Some(1)).is_ok());
+        assert!(round_trip(&capture(1, 9), Some(1)).is_err());
+        assert!(round_trip(&capture(0, 0), Some(1)).is_err());
+        drop_vm();
+    }
+}
@@ -0,0 +1,115 @@
+use std::{fmt, ops::Deref};
+
+// 能直接存在对象里的最大字节数 加上长度和标签后和 String 一样占24字节
+const INLINE_CAP: usize = 22;
+
+// 字符串对象的内容 短字符串直接存放 不再单独向分配器申请
+// 标识符、属性名和大多数消息都很短 长字符串仍放在堆上
+// 字符串对象创建后不再修改 所以长字符串用 Box<str> 不需要容量字段
+#[derive(Clone)]
+pub enum SmallString {
+    Inline { len: u8, bytes: [u8; INLINE_CAP] },
+    Heap(Box<str>),
+}
+
+impl SmallString {
+    pub fn as_str(&self) -> &str {
+        match self {
+            // 只从 &str 或 String 构造 内容一定是合法的 UTF-8
+            SmallString::Inline { len, bytes } => unsafe {
+                std::str::from_utf8_unchecked(&bytes[..*len as usize])
+            },
+            SmallString::Heap(chars) => chars,
+        }
+    }
+
+    // 和 String::capacity 一样是在对象以外占用的字节数 短字符串为0
+    pub fn capacity(&self) -> usize {
+        match self {
+            SmallString::Inline { .. } => 0,
+            SmallString::Heap(chars) => chars.len(),
+        }
+    }
+}
+
+impl From<&str> for SmallString {
+    fn from(chars: &str) -> SmallString {
+        if chars.len() <= INLINE_CAP {
+            let mut bytes = [0; INLINE_CAP];
+            bytes[..chars.len()].copy_from_slice(chars.as_bytes());
+            SmallString::Inline {
+                len: chars.len() as u8,
+                bytes,
+            }
+        } else {
+            SmallString::Heap(chars.into())
+        }
+    }
+}
+
+impl From<String> for SmallString {
+    fn from(chars: String) -> SmallString {
+        if chars.len() <= INLINE_CAP {
+            SmallString::from(chars.as_str())
+        } else {
+            SmallString::Heap(chars.into_boxed_str())
+        }
+    }
+}
+
+impl Deref for SmallString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for SmallString {
+    fn eq(&self, other: &SmallString) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl PartialEq<str> for SmallString {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for SmallString {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for SmallString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for SmallString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_strings_are_inline() {
+        let short = SmallString::from("a".repeat(INLINE_CAP));
+        let long = SmallString::from("a".repeat(INLINE_CAP + 1));
+        assert_eq!(short.capacity(), 0);
+        assert_eq!(long.capacity(), INLINE_CAP + 1);
+        assert_eq!(short, *"a".repeat(INLINE_CAP).as_str());
+        assert_eq!(SmallString::from("héllo"), "héllo");
+        assert_eq!(
+            std::mem::size_of::<SmallString>(),
+            std::mem::size_of::<String>()
+        );
+    }
+}
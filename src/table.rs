@@ -1,5 +1,50 @@
 use std::{collections::HashMap, ptr::write};
 
+// FNV-1a 字符串哈希 在创建字符串时计算一次
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+// 驻留字符串集合 按预先算好的哈希分桶 同一内容只有一个 ObjString
+// 因此字符串相等就是指针相等
+pub struct StringSet {
+    buckets: HashMap<u32, Vec<*mut ObjString>>,
+}
+
+impl StringSet {
+    pub fn new() -> StringSet {
+        StringSet {
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn find(&self, chars: &str, hash: u32) -> Option<*mut ObjString> {
+        self.buckets
+            .get(&hash)?
+            .iter()
+            .find(|string| unsafe { (***string).chars == chars })
+            .copied()
+    }
+
+    pub fn insert(&mut self, string: *mut ObjString) {
+        let hash = unsafe { (*string).hash };
+        self.buckets.entry(hash).or_default().push(string);
+    }
+
+    // 驻留表不持有字符串 回收前移除没有被标记的字符串
+    pub fn remove_white(&mut self) {
+        self.buckets.retain(|_, strings| {
+            strings.retain(|string| unsafe { (**string).obj.is_marked });
+            !strings.is_empty()
+        });
+    }
+}
+
 use crate::{memory::allocate, object::ObjString, value::Value};
 
 pub struct Table {
@@ -21,24 +66,14 @@ impl Table {
     }
 
     pub fn set(&mut self, key: *mut ObjString, value: Value) -> bool {
-        match self.map.insert(key, value) {
-            Some(_) => false,
-            None => true,
-        }
+        self.map.insert(key, value).is_none()
     }
 
     pub fn remove(&mut self, key: *mut ObjString) {
         self.map.remove(&key);
     }
 
-    pub fn get_key(&self, key: *mut ObjString) -> Option<*mut ObjString> {
-        match self.map.get_key_value(&key) {
-            Some(kv) => Some(kv.0.clone()),
-            None => None,
-        }
-    }
-
     pub fn add_all(&mut self, from: &Table) {
-        self.map.extend(from.map.clone().into_iter())
+        self.map.extend(from.map.clone())
     }
 }
@@ -1,4 +1,6 @@
-use crate::object::{Obj, ObjType, Object};
+use std::fmt;
+
+use crate::object::{Obj, ObjType};
 
 #[derive(Clone, Copy)]
 pub enum Value {
@@ -28,9 +30,42 @@ macro_rules! is_number {
     }};
 }
 
+// 按 C printf 的 %.{precision}g 格式化数字 与 clox 的输出保持一致
+pub fn format_number(n: f64, precision: usize) -> String {
+    if n.is_nan() {
+        return "nan".into();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf".into() } else { "-inf".into() };
+    }
+    let precision = precision.max(1);
+
+    // 先按有效位数舍入 再根据指数决定用定点还是科学计数法
+    let scientific = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, n)).into()
+    }
+}
+
+// 去掉小数部分末尾的0 以及多余的小数点
+fn trim_fraction(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
 pub fn as_obj(value: Value) -> *mut Obj {
     if let Value::Object(obj) = value {
-        obj.clone()
+        obj
     } else {
         panic!("as_obj error")
     }
@@ -54,18 +89,48 @@ macro_rules! obj_val {
     };
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Boolean(b) => write!(f, "{}", if *b { "true" } else { "false" }),
+            Value::Nil => write!(f, "nil"),
+            Value::Number(n) => write!(f, "{}", format_number(*n, 6)),
+            Value::Object(obj) => unsafe { write!(f, "{}", **obj) },
+        }
+    }
+}
+
 impl Value {
     pub fn print(&self) {
+        print!("{}", self);
+    }
+
+    // 值的类型名 用于调试输出和错误信息
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Boolean(b) => print!("{}", if *b { "true" } else { "false" }),
-            Value::Nil => print!("nil"),
-            Value::Number(n) => print!("{}", n),
-            Value::Object(obj) => unsafe { (*(*obj)).print() },
+            Value::Nil => "nil",
+            Value::Boolean(_) => "bool",
+            Value::Number(_) => "number",
+            Value::Object(obj) => match unsafe { (**obj).type_ } {
+                ObjType::BoundMethod => "bound method",
+                ObjType::Class => "class",
+                ObjType::Closure => "closure",
+                ObjType::Function => "function",
+                ObjType::Instance => "instance",
+                ObjType::Native => "native",
+                ObjType::String => "string",
+                ObjType::Upvalue => "upvalue",
+                ObjType::List => "list",
+                ObjType::Buffer => "buffer",
+                ObjType::Builder => "builder",
+                ObjType::UnboundMethod => "unbound method",
+                ObjType::WeakRef => "weak ref",
+            },
         }
     }
 
     pub fn is_obj_type(&self, type_: ObjType) -> bool {
-        is_obj!(self) && unsafe { (*as_obj(self.clone())).type_ == type_ }
+        is_obj!(self) && unsafe { (*as_obj(*self)).type_ == type_ }
     }
 }
 
@@ -1,115 +1,423 @@
-use std::collections::HashMap;
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::path::PathBuf;
 use std::ptr::null_mut;
-use std::time::Instant;
-
-use crate::chunk::OpCode;
-use crate::compiler::{ClassCompiler, Compiler, FunctionType, Parser};
+use std::time::{Duration, Instant};
+
+use crate::chunk::{OpCode, Operands};
+use crate::compiler::CompilerCtx;
+use crate::coverage::Coverage;
+use crate::deep::{deep_clone, deep_equal, inspect};
+use crate::diagnostic::{self, ErrorSink, Severity, Sources, StderrSink};
+use crate::handle::HandleScope;
+use crate::intrinsic::{self, Intrinsic};
+#[cfg(feature = "debug_log_gc")]
+use crate::memory::GcEvent;
+use crate::memory::{self, GcListener};
 use crate::object::{
-    NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
-    ObjString, ObjType, ObjUpvalue,
+    Arity, NativeFn, NativeFunction, Obj, ObjBoundMethod, ObjBuffer, ObjClass, ObjClosure,
+    ObjFunction, ObjInstance, ObjList, ObjNative, ObjString, ObjType, ObjUnboundMethod, ObjUpvalue,
+    ObjWeakRef,
+};
+use crate::output::{self, Output};
+use crate::provider::{Provider, SystemProvider};
+use crate::table::{hash_string, StringSet, Table};
+use crate::value::{as_obj, format_number, Value};
+use crate::{
+    as_bound_method, as_buffer, as_class, as_closure, as_function, as_instance, as_list, as_native,
+    as_number, as_string, as_unbound_method, is_buffer, is_class, is_instance, is_list, is_number,
+    is_obj, is_string, obj_val,
 };
-use crate::scanner::Scanner;
-use crate::table::Table;
-use crate::value::{as_obj, Value};
 use crate::{
-    as_bound_method, as_class, as_closure, as_function, as_instance, as_native, as_number,
-    as_string, is_class, is_instance, is_number, is_obj, is_string, obj_val,
+    buffer, builder, debug, finalize, formats, hash, heap, list, module, path, plugin, reflect,
+    weak,
 };
 
 pub const UINT8_COUNT: usize = u8::MAX as usize + 1;
-const FRAMES_MAX: usize = 64;
-const STACK_MAX: usize = UINT8_COUNT * FRAMES_MAX;
-
-static mut VM: *mut VM = null_mut();
+const FRAMES_MAX: usize = 64; // 默认的调用深度上限 可用 set_max_call_depth 修改
+const TRACE_REPEAT_SHOWN: usize = 3; // 栈回溯中连续相同的栈帧最多显示几个
+const STACK_INITIAL: usize = UINT8_COUNT; // 栈的初始槽数 调用时按需扩容
+const MAX_REPEAT_LEN: usize = 1 << 28; // 字符串重复结果的最大字节数
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024; // 有时间上限时每执行这么多条指令看一次时钟
+                                          // 取指前检查整条指令不越出字节码 调试构建默认打开 发布构建用 checked_ip 特性打开
+const CHECKED_IP: bool = cfg!(any(debug_assertions, feature = "checked_ip"));
+
+thread_local! {
+    // 当前线程的虚拟机 每个线程可以各自运行一个虚拟机
+    static VM: Cell<*mut VM> = const { Cell::new(null_mut()) };
+}
 
 pub fn init_vm() {
-    let box_vm = Box::new(VM::new());
-    unsafe { VM = Box::into_raw(box_vm) };
-    vm().stack_top = vm().stack.as_mut_ptr();
+    attach_vm(Box::new(VM::new()));
+    #[cfg(feature = "debug_log_gc")]
+    vm().subscribe_gc(Box::new(log_gc_event));
     vm().init_string = ObjString::take_string("init".into());
-    vm().define_native("clock", clock_native);
+    vm().define_native("clock", Arity::Exact(0), clock_native);
+    vm().define_native("random", Arity::Exact(0), random_native);
+    vm().define_native("instructions", Arity::Exact(0), instructions_native);
+    vm().define_native("allocations", Arity::Exact(0), allocations_native);
+    vm().define_native("gcCount", Arity::Exact(0), gc_count_native);
+    vm().define_native("randomInt", Arity::Exact(2), random_int_native);
+    vm().define_native("seedRandom", Arity::Exact(1), seed_random_native);
+    vm().define_native("toFixed", Arity::Exact(2), to_fixed_native);
+    vm().define_native("toPrecision", Arity::Exact(2), to_precision_native);
+    vm().define_native("deepEqual", Arity::Exact(2), deep_equal_native);
+    vm().define_native("clone", Arity::Exact(1), clone_native);
+    vm().define_native("inspect", Arity::Exact(1), inspect_native);
+    vm().define_native("bool", Arity::Exact(1), bool_native);
+    list::define_list_natives();
+    buffer::define_buffer_natives();
+    builder::define_builder_natives();
+    reflect::define_reflect_natives();
+    weak::define_weak_natives();
+    finalize::define_finalize_natives();
+    heap::define_heap_natives();
+    output::define_output_natives();
+    path::define_path_natives();
+    formats::define_format_natives();
+    hash::define_hash_natives();
+    intrinsic::define_intrinsic_natives();
+    intrinsic::register_intrinsics();
 }
 
-pub fn drop_vm() {
-    unsafe {
-        let _ = Box::from_raw(VM);
+#[cfg(feature = "debug_log_gc")]
+fn log_gc_event(event: &GcEvent) {
+    match *event {
+        GcEvent::Start { .. } => println!("-- gc begin"),
+        GcEvent::End {
+            bytes_before,
+            bytes_after,
+            next_gc,
+            ..
+        } => {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                bytes_before - bytes_after,
+                bytes_before,
+                bytes_after,
+                next_gc,
+            );
+        }
+    }
+}
+
+// 用 Lox 写的标准前导
+const PRELUDE: &str = include_str!("prelude.lox");
+
+// 执行标准前导 前导中的顶层函数本来就是给用户用的 不检查是否被使用 也不打印字节码和执行轨迹 不算覆盖率
+pub fn load_prelude() {
+    let warn_unused_globals = vm().warn_unused_globals;
+    let print_code = vm().print_code;
+    let print_stats = vm().print_stats;
+    let trace = vm().trace;
+    vm().warn_unused_globals = false;
+    vm().print_code = false;
+    vm().print_stats = false;
+    vm().trace = false;
+    let coverage = vm().coverage.take();
+
+    let result = vm().interpret(PRELUDE.into());
+
+    vm().warn_unused_globals = warn_unused_globals;
+    vm().print_code = print_code;
+    vm().print_stats = print_stats;
+    vm().trace = trace;
+    vm().coverage = coverage;
+    if !matches!(result, InterpretResult::Ok) {
+        panic!("Failed to load the prelude.");
     }
+    // 前导的定义和原生函数一样由所有领域共享
+    let prelude = std::mem::take(&mut vm().globals.map);
+    vm().builtins.map.extend(prelude);
+}
+
+pub fn drop_vm() {
+    drop(detach_vm());
 }
 
 pub fn vm() -> &'static mut VM {
-    unsafe { VM.as_mut().unwrap()  as &'static mut VM}
+    unsafe { VM.get().as_mut().unwrap() as &'static mut VM }
+}
+
+// 从当前线程取出虚拟机 可以移动到别的线程后用 attach_vm 装上继续使用
+pub fn detach_vm() -> Box<VM> {
+    let vm = VM.replace(null_mut());
+    assert!(!vm.is_null(), "no VM on this thread");
+    unsafe { Box::from_raw(vm) }
+}
+
+// 把虚拟机装到当前线程 当前线程不能已经有虚拟机
+pub fn attach_vm(vm: Box<VM>) {
+    assert!(VM.get().is_null(), "this thread already has a VM");
+    VM.set(Box::into_raw(vm));
+}
+
+pub type StartHook = Box<dyn FnMut(&Script) + Send>;
+pub type ExitHook = Box<dyn FnMut(&Script, &InterpretResult) + Send>;
+// 传入原生函数名和参数 返回false时拒绝这次调用
+pub type AuditHook = Box<dyn FnMut(&str, &[Value]) -> bool + Send>;
+
+// 观察的变量 在安全点检查 值变化时打印到标准错误
+pub enum WatchTarget {
+    Global(String), // 全局变量名
+    Local(usize),   // 正在执行的函数的槽位 槽位0是被调用者或this
+}
+
+pub struct Watch {
+    target: WatchTarget,
+    last: Option<String>, // 上次打印的内容
 }
 
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    Yielded, // 分步执行用完了指令配额
+    Timeout, // 超过了时间上限 错误已经报告
+}
+
+// 领域 释放时它的全局变量随之释放 不能在执行中释放当前领域
+pub struct Realm {
+    id: usize,
+}
+
+impl Drop for Realm {
+    fn drop(&mut self) {
+        let vm = vm();
+        if vm.current_realm == self.id {
+            vm.leave_realm();
+        }
+        vm.realms.remove(&self.id);
+    }
+}
+
+// 分步执行的结果
+pub enum StepResult {
+    Done,    // 脚本执行完毕
+    Yielded, // 用完了指令配额 可以继续 step
+    Error,   // 运行时错误 错误已经报告
+    Timeout, // 超过了时间上限 错误已经报告
+}
+
+// 编译错误 具体的错误信息在编译时已经打印
+#[derive(Debug)]
+pub struct CompileError;
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Compile error.")
+    }
+}
+
+// 编译好的顶层函数 存活期间函数登记为GC根 可以反复执行而不必重新解析
+// 必须在 drop_vm 之前释放
+pub struct Script {
+    function: *mut ObjFunction,
+}
+
+impl Script {
+    pub fn new(function: *mut ObjFunction) -> Script {
+        vm().scripts.push(function);
+        Script { function }
+    }
+
+    pub fn function(&self) -> *mut ObjFunction {
+        self.function
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        let scripts = &mut vm().scripts;
+        if let Some(i) = scripts
+            .iter()
+            .rposition(|function| *function == self.function)
+        {
+            scripts.swap_remove(i);
+        }
+    }
 }
 
 // 调用帧
 #[derive(Clone, Copy)]
 pub struct CallFrame {
     pub closure: *mut ObjClosure, // 调用的函数闭包
-    ip: *mut u8,                  // 指向字节码数组的指针 指函数执行到哪了
-    slots: *mut Value,            // 指向vm栈中该函数使用的第一个局部变量
+    code: *const u8,              // 函数字节码的起始地址
+    ip: usize,                    // 下一个要读的字节在字节码中的下标 指函数执行到哪了
+    slots: usize,                 // 该函数使用的第一个局部变量在vm栈中的下标
 }
 
 impl CallFrame {
     fn new() -> CallFrame {
         CallFrame {
             closure: null_mut(),
-            ip: null_mut(),
-            slots: null_mut(),
+            code: std::ptr::null(),
+            ip: 0,
+            slots: 0,
         }
     }
+
+    // 正在执行的指令在字节码中的偏移 ip 已经越过了它的操作码
+    // 安全点上 ip 可能刚跳回到函数开头 这时取第一条指令
+    pub fn instruction_offset(&self) -> usize {
+        self.ip.saturating_sub(1)
+    }
+
+    // 检查模式下取指前确认整条指令(含操作数)都在字节码内 长度按指令元数据计算
+    // 越界说明编译器生成了错误的字节码 直接 panic 不再往下读
+    fn check_instruction(&self) {
+        let function = unsafe { (*self.closure).function };
+        let chunk = unsafe { &(*function).chunk };
+        let name = match unsafe { (*function).name.as_ref() } {
+            Some(name) => name.chars.as_str(),
+            None => "script",
+        };
+        assert!(
+            self.ip < chunk.code.len(),
+            "instruction pointer {} is past the end of '{}' ({} bytes)",
+            self.ip,
+            name,
+            chunk.code.len()
+        );
+        // 未知的操作码由执行循环报告运行时错误
+        let Some(op) = OpCode::from_byte(chunk.code[self.ip]) else {
+            return;
+        };
+        // 闭包指令的长度要读常量索引后才知道
+        let len = match op.info().operands {
+            Operands::Closure if self.ip + 1 >= chunk.code.len() => 2,
+            _ => chunk.instruction_len(self.ip),
+        };
+        assert!(
+            self.ip + len <= chunk.code.len(),
+            "{} at offset {} in '{}' reads past the end of its chunk ({} bytes)",
+            op.info().name,
+            self.ip,
+            name,
+            chunk.code.len()
+        );
+    }
+
+    // 栈帧中第 slot 个槽位的值 槽位0是被调用者或this
+    pub fn slot(&self, slot: usize) -> Value {
+        vm().stack[self.slots + slot]
+    }
 }
 
-pub struct VM {
-    pub frames: [CallFrame; FRAMES_MAX], // 栈帧数组 所有函数调用的执行点
-    pub frame_count: usize,              // 当前调用栈数
+// 虚拟机独占它指向的所有对象和表 回调和提供者都要求 Send
+// 裸指针只在虚拟机所在的线程上解引用 整个虚拟机可以移动到别的线程
+unsafe impl Send for VM {}
 
-    pub stack: [Value; STACK_MAX],      // 虚拟机栈
-    pub stack_top: *mut Value,          // 栈顶指针 总是指向栈顶
-    pub globals: Table,                 // 全局变量表
-    pub strings: Table,                 // 全局字符串表
+pub struct VM {
+    pub frames: Vec<CallFrame>, // 栈帧数组 所有函数调用的执行点 长度即调用深度上限
+    pub frame_count: usize,     // 当前调用栈数
+
+    pub stack: Vec<Value>, // 虚拟机栈 栈帧和栈顶都用下标 扩容后只有打开的提升值要重新定位
+    pub stack_top: usize,  // 栈顶下标 指向第一个空槽
+    pub globals: Table,    // 当前领域的全局变量表
+    pub builtins: Table,   // 原生函数和前导 所有领域共享 全局变量表中找不到时查找
+    pub realms: HashMap<usize, Table>, // 不在执行中的领域的全局变量表
+    pub current_realm: usize, // 当前领域 0 为默认领域
+    pub next_realm: usize,
+    pub strings: StringSet,             // 驻留字符串集合
     pub init_string: *mut ObjString,    // 构造器名称
     pub open_upvalues: *mut ObjUpvalue, // 全局提升值
 
     pub bytes_allocated: usize, // 已经分配的内存
+    pub total_allocated: usize, // 累计分配的内存 不因回收减少
+    pub allocation_count: u64,  // 累计分配的次数
     pub next_gc: usize,         // 出发下一次gc的阈值
+    pub external_bytes: usize,  // 上次回收以来原生函数登记的对象以外的内存
+    pub gc_requested: bool,     // 有待执行的回收 在下一个安全点或分配时执行
+    pub gc_count: u64,          // 累计回收的次数
+    pub instruction_count: u64, // 累计执行的指令数
+    pub warning_count: usize,   // 累计报告的编译警告数
 
     pub objects: *mut Obj,         // 对象根链表
     pub gray_stack: Vec<*mut Obj>, // 灰色对象栈
-
-    pub current_compiler: *mut Compiler,
-    pub parser: Parser,
-    pub scanner: Option<Scanner>,
-    pub class_compiler: *mut ClassCompiler,
+    pub handles: Vec<Value>,       // 原生函数句柄根栈
+
+    pub compiling: *mut CompilerCtx, // 正在进行的编译 其中的函数是GC根
+
+    pub print_code: bool,          // 编译结束时打印字节码
+    pub print_stats: bool,         // 编译结束时打印每个函数的统计
+    pub trace: bool,               // 打印执行的每条指令和当时的栈
+    pub deny_warnings: bool,       // 把编译警告视为错误
+    pub warn_unused_globals: bool, // 检查未使用的顶层函数和类 REPL 中关闭
+    pub hot_reload: bool,          // 重新定义函数和类时原地更新 REPL 中打开
+    pub repl_locals: Vec<String>,  // REPL 会话的局部变量 值在栈底的槽位1起
+    repl_input: bool,              // 正在执行 REPL 的一段输入
+    pub color: bool,               // 诊断信息使用颜色和源码片段
+    pub sources: Sources,          // 编译过的源文件 运行时错误据此给出源码片段
+    pub strict_math: bool,         // 除零和NaN视为运行时错误
+    pub strict_globals: bool,      // 给未声明的全局变量赋值是编译错误
+    pub debug_info: bool,          // 编译时生成局部变量的调试信息
+
+    pub char_strings: [*mut ObjString; 128], // 单个 ASCII 字符字符串的缓存
+
+    pub module_path: Vec<PathBuf>,        // 模块搜索目录 不含 LOX_PATH
+    pub loaded_modules: HashSet<PathBuf>, // 已经导入过的模块 每个模块只执行一次
+    pub module_cache: bool,               // 缓存导入模块的编译结果
+
+    pub provider: Box<dyn Provider>, // 时钟、随机数等不确定性的来源
+    pub error_sink: Box<dyn ErrorSink>, // 编译诊断和运行时错误的去处
+
+    pub pending_native_error: Option<String>, // 原生函数报告的错误 调用返回后处理
+
+    pub builtin_methods: HashMap<ObjType, Table>, // 内置类型的原生方法 如 weakRef(x).get()
+    pub weak_refs: Vec<*mut ObjWeakRef>,          // 所有存活的弱引用 GC时检查
+
+    pub finalizers: HashMap<*mut Obj, Value>, // 登记了终结器的对象 终结器是GC根 对象不是
+    pub pending_finalizers: Vec<(Value, Value)>, // 待执行的 (终结器, 对象) 在指令之间执行
+    pub scripts: Vec<*mut ObjFunction>,       // 存活的 Script 持有的顶层函数
+
+    pub start_hooks: Vec<StartHook>,   // 脚本执行前的回调
+    pub exit_hooks: Vec<ExitHook>,     // 脚本执行后的回调
+    pub audit_hooks: Vec<AuditHook>,   // 调用需要审计的原生函数前的回调
+    pub watches: Vec<Watch>,           // 观察的变量
+    pub coverage: Option<Coverage>,    // 记录执行过的源码行
+    pub context: Option<*mut dyn Any>, // run_with_context 传入的上下文
+    pub step_limit: Option<u64>,       // 分步执行时本次 step 的指令数上限
+    pub timeout: Option<Duration>,     // 每次 run 的时间上限 超时后在安全点中断
+    deadline: Option<Instant>,         // 本次 run 的截止时刻
+    timeout_check: u64,                // 下次看时钟时的指令数
+    timed_out: bool,                   // 执行因超时中断
+
+    pub output: Output, // print 语句的输出
+
+    pub intrinsics: Vec<Value>, // 按编号排列的内联函数对应的原生函数
+
+    pub gc_listeners: Vec<GcListener>, // GC遥测事件的订阅者
+    pub last_gc_end: Instant,          // 上次回收结束的时间 用于计算分配速率
+    pub bytes_after_gc: usize,         // 上次回收结束时的内存
 }
 
 macro_rules! read_byte {
     ($frame:expr) => {
         unsafe {
-            let result = *(*$frame).ip;
-            (*$frame).ip = (*$frame).ip.add(1);
+            let result = *(*$frame).code.add((*$frame).ip);
+            (*$frame).ip += 1;
             result
         }
     };
 }
 
 macro_rules! read_constant {
-    ($frame:expr) => {
-        unsafe {
-            (*(*(*$frame).closure).function).chunk.constants.values[read_byte!($frame) as usize]
-        }
-    };
+    ($frame:expr) => {{
+        let index = read_byte!($frame) as usize;
+        unsafe { (&(*(*(*$frame).closure).function).chunk.constants.values)[index] }
+    }};
 }
 
 macro_rules! read_short {
     ($frame:expr) => {
         unsafe {
-            (*$frame).ip = (*$frame).ip.add(2);
-            (((*((*$frame).ip.sub(2))) as u16) << 8) | *(*$frame).ip.sub(1) as u16
+            (*$frame).ip += 2;
+            let ip = (*$frame).code.add((*$frame).ip);
+            ((*ip.sub(2) as u16) << 8) | *ip.sub(1) as u16
         }
     };
 }
@@ -148,21 +456,221 @@ macro_rules! binary_op {
     }};
 }
 
+// 位运算 操作数截断为 i64 结果再转回数字 NaN 为0 超出范围的取 i64 的最值
+macro_rules! bitwise_op {
+    ($vm:expr, $op:expr) => {{
+        match ($vm.peek(0), $vm.peek(1)) {
+            (Value::Number(b), Value::Number(a)) => {
+                $vm.stack_top -= 2;
+                $vm.push(Value::Number($op(a as i64, b as i64) as f64));
+            }
+            _ => {
+                $vm.runtime_error("Operands must be numbers.".into());
+                return InterpretResult::RuntimeError;
+            }
+        }
+    }};
+}
+
+// 严格数学模式下 运算结果为NaN视为运行时错误
+macro_rules! check_nan {
+    ($vm:expr) => {{
+        if $vm.strict_math && matches!($vm.peek(0), Value::Number(n) if n.is_nan()) {
+            $vm.runtime_error("Operation produced NaN.".into());
+            return InterpretResult::RuntimeError;
+        }
+    }};
+}
+
+// 从提供者取值 出错时报告运行时错误
+fn provided(value: Result<f64, String>) -> Value {
+    match value {
+        Ok(value) => Value::Number(value),
+        Err(message) => {
+            vm().native_error(message);
+            Value::Nil
+        }
+    }
+}
+
+// clock() 程序启动以来的秒数
 fn clock_native(_arg_count: usize, _args: *mut Value) -> Value {
-    let now = Instant::now();
-    let secs = now.elapsed().as_secs_f64();
-    Value::Number(secs)
+    provided(vm().provider.clock())
+}
+
+// instructions() 启动以来执行的指令数 前后两次的差就是一段代码的开销
+fn instructions_native(_arg_count: usize, _args: *mut Value) -> Value {
+    Value::Number(vm().instruction_count as f64)
+}
+
+// allocations() 启动以来分配堆内存的次数 回收不会减少
+fn allocations_native(_arg_count: usize, _args: *mut Value) -> Value {
+    Value::Number(vm().allocation_count as f64)
+}
+
+// gcCount() 启动以来垃圾回收的次数
+fn gc_count_native(_arg_count: usize, _args: *mut Value) -> Value {
+    Value::Number(vm().gc_count as f64)
+}
+
+// random() [0, 1) 的随机数
+fn random_native(_arg_count: usize, _args: *mut Value) -> Value {
+    provided(vm().provider.random())
+}
+
+// randomInt(lo, hi) [lo, hi] 中的随机整数
+fn random_int_native(_arg_count: usize, args: *mut Value) -> Value {
+    let (lo, hi) = match unsafe { (*args, *args.add(1)) } {
+        (Value::Number(lo), Value::Number(hi)) if lo.fract() == 0.0 && hi.fract() == 0.0 => {
+            (lo, hi)
+        }
+        _ => {
+            vm().native_error("randomInt() expects two integers.".into());
+            return Value::Nil;
+        }
+    };
+    if lo > hi {
+        vm().native_error("randomInt() expects lo <= hi.".into());
+        return Value::Nil;
+    }
+    match vm().provider.random() {
+        Ok(random) => Value::Number(lo + (random * (hi - lo + 1.0)).floor()),
+        Err(message) => provided(Err(message)),
+    }
+}
+
+// seedRandom(n) 重新设定随机数种子 相同的种子得到相同的随机数序列
+fn seed_random_native(_arg_count: usize, args: *mut Value) -> Value {
+    match unsafe { *args } {
+        Value::Number(n) => vm().provider.seed(n.to_bits()),
+        _ => vm().native_error("seedRandom() expects a number.".into()),
+    }
+    Value::Nil
 }
 
-fn is_falsey(value: Value) -> bool {
+// 读取 (数字, 位数) 两个参数 参数不合法时报错并返回None
+fn number_and_digits(name: &str, args: *mut Value) -> Option<(f64, usize)> {
+    let (number, digits) = unsafe { (*args, *args.add(1)) };
+    match (number, digits) {
+        (Value::Number(n), Value::Number(d)) if (0.0..=100.0).contains(&d) && d.fract() == 0.0 => {
+            Some((n, d as usize))
+        }
+        (Value::Number(_), Value::Number(_)) => {
+            vm().native_error(format!(
+                "{}() digits must be an integer between 0 and 100.",
+                name
+            ));
+            None
+        }
+        _ => {
+            vm().native_error(format!("{}() expects two numbers.", name));
+            None
+        }
+    }
+}
+
+// toFixed(n, digits) 保留小数点后 digits 位
+fn to_fixed_native(_arg_count: usize, args: *mut Value) -> Value {
+    match number_and_digits("toFixed", args) {
+        Some((n, digits)) => obj_val!(ObjString::take_string(format!("{:.*}", digits, n))),
+        None => Value::Nil,
+    }
+}
+
+// toPrecision(n, digits) 保留 digits 位有效数字
+fn to_precision_native(_arg_count: usize, args: *mut Value) -> Value {
+    match number_and_digits("toPrecision", args) {
+        Some((n, digits)) => obj_val!(ObjString::take_string(format_number(n, digits))),
+        None => Value::Nil,
+    }
+}
+
+// deepEqual(a, b) 递归比较实例的字段
+fn deep_equal_native(_arg_count: usize, args: *mut Value) -> Value {
+    let (a, b) = unsafe { (*args, *args.add(1)) };
+    Value::Boolean(deep_equal(a, b, &mut vec![]))
+}
+
+// inspect(v) 嵌套的列表和实例的多行可读表示 调试时使用
+fn inspect_native(_arg_count: usize, args: *mut Value) -> Value {
+    let text = inspect(unsafe { *args }, &mut vec![]);
+    obj_val!(ObjString::take_string(text))
+}
+
+// bool(v) 按条件判断的规则转换为布尔值
+fn bool_native(_arg_count: usize, args: *mut Value) -> Value {
+    Value::Boolean(!is_falsey(unsafe { *args }))
+}
+
+// clone(v) 递归复制实例
+fn clone_native(_arg_count: usize, args: *mut Value) -> Value {
+    let scope = HandleScope::new();
+    deep_clone(unsafe { *args }, &mut HashMap::new(), &scope)
+}
+
+// 只有 nil 和 false 为假 数字0、空字符串和空列表都为真
+pub fn is_falsey(value: Value) -> bool {
     match value {
         Value::Nil => true,
         Value::Boolean(b) => !b,
-        _ => true,
+        _ => false,
+    }
+}
+
+// 栈回溯中省略的重复栈帧
+fn write_hidden_frames(out: &mut String, count: usize) {
+    if count > 0 {
+        writeln!(out, "... {} more frames like this ...", count).unwrap();
+    }
+}
+
+// 栈回溯中的一帧
+fn write_frame(out: &mut String, frame: &CallFrame) {
+    let function = unsafe { (*frame.closure).function };
+    let instruction = frame.instruction_offset();
+    write!(out, "[line {}] in ", unsafe {
+        (&(*function).chunk.lines)[instruction]
+    })
+    .unwrap();
+    if unsafe { (*function).name.is_null() } {
+        out.push_str("script");
+    } else {
+        // 参数按名字显示当前的值 槽位0是被调用者或this
+        let params = unsafe { &(*function).params };
+        let args = params
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("{}={}", name, frame.slot(i + 1)))
+            .collect::<Vec<_>>();
+        write!(
+            out,
+            "{}({})",
+            unsafe { &(*(*function).name).chars },
+            args.join(", ")
+        )
+        .unwrap();
+    }
+    // 知道源文件时再给出文件名、行号和列号
+    let chunk = unsafe { &(*function).chunk };
+    match (&chunk.file, chunk.columns.get(instruction)) {
+        (Some(file), Some(column)) => {
+            writeln!(out, " ({}:{}:{})", file, chunk.lines[instruction], column)
+        }
+        (Some(file), None) => writeln!(out, " ({})", file),
+        _ => writeln!(out),
+    }
+    .unwrap();
+    // 有调试信息时再列出参数以外仍然有效的局部变量
+    let arity = unsafe { (*function).arity };
+    for local in unsafe { (*function).chunk.live_locals(instruction) } {
+        if local.slot > arity {
+            writeln!(out, "    {} = {}", local.name, frame.slot(local.slot)).unwrap();
+        }
     }
 }
 
-fn values_equal(a: Value, b: Value) -> bool {
+// 字符串都经过驻留 内容相同的字符串是同一个对象 所以对象只比较指针
+pub fn values_equal(a: Value, b: Value) -> bool {
     match (a, b) {
         (Value::Boolean(bool1), Value::Boolean(bool2)) => bool1 == bool2,
         (Value::Nil, Value::Nil) => true,
@@ -175,83 +683,573 @@ fn values_equal(a: Value, b: Value) -> bool {
 impl VM {
     pub fn new() -> VM {
         VM {
-            frames: [CallFrame::new(); FRAMES_MAX],
+            frames: vec![CallFrame::new(); FRAMES_MAX],
             frame_count: 0,
 
-            stack: [Value::Nil; STACK_MAX],
-            stack_top: std::ptr::null_mut(),
+            stack: vec![Value::Nil; STACK_INITIAL],
+            stack_top: 0,
             globals: Table {
                 map: HashMap::new(),
             },
-            strings: Table {
+            builtins: Table {
                 map: HashMap::new(),
             },
+            realms: HashMap::new(),
+            current_realm: 0,
+            next_realm: 1,
+            strings: StringSet::new(),
             init_string: null_mut(),
             open_upvalues: null_mut(),
 
             bytes_allocated: 0,
+            total_allocated: 0,
+            allocation_count: 0,
             next_gc: 1024 * 1024,
+            external_bytes: 0,
+            gc_requested: false,
+            gc_count: 0,
+            instruction_count: 0,
+            warning_count: 0,
 
             objects: null_mut(),
             gray_stack: vec![],
-
-            current_compiler: null_mut(),
-            parser: Parser::new(),
-            scanner: None,
-            class_compiler: null_mut(),
+            handles: vec![],
+
+            compiling: null_mut(),
+
+            print_code: cfg!(feature = "debug_print_code"),
+            print_stats: false,
+            trace: cfg!(feature = "debug_trace_execution"),
+            deny_warnings: false,
+            warn_unused_globals: true,
+            hot_reload: false,
+            repl_locals: vec![],
+            repl_input: false,
+            color: false,
+            sources: Sources::default(),
+            strict_math: false,
+            strict_globals: false,
+            debug_info: false,
+
+            char_strings: [null_mut(); 128],
+
+            module_path: vec![],
+            loaded_modules: HashSet::new(),
+            module_cache: true,
+
+            provider: Box::new(SystemProvider::new()),
+            error_sink: Box::new(StderrSink),
+
+            pending_native_error: None,
+
+            builtin_methods: HashMap::new(),
+            weak_refs: vec![],
+
+            finalizers: HashMap::new(),
+            pending_finalizers: vec![],
+            scripts: vec![],
+
+            start_hooks: vec![],
+            exit_hooks: vec![],
+            audit_hooks: vec![],
+            watches: vec![],
+            coverage: None,
+            context: None,
+            step_limit: None,
+            timeout: None,
+            deadline: None,
+            timeout_check: 0,
+            timed_out: false,
+
+            output: Output::new(),
+
+            intrinsics: vec![],
+
+            gc_listeners: vec![],
+            last_gc_end: Instant::now(),
+            bytes_after_gc: 0,
         }
     }
 
-    fn define_native(&mut self, name: &str, function: NativeFn) {
-        self.push(obj_val!(ObjString::take_string(name.into())));
-        self.push(obj_val!(ObjNative::new(function)));
-        self.globals
-            .set(as_string!(self.stack[0]), self.stack[1]);
-        self.pop();
-        self.pop();
+    // 订阅GC事件
+    pub fn subscribe_gc(&mut self, listener: GcListener) {
+        self.gc_listeners.push(listener);
+    }
+
+    pub fn define_native(&mut self, name: &str, arity: Arity, function: NativeFn) {
+        self.define_native_function(name, arity, NativeFunction::Builtin(function));
+    }
+
+    // 给内置类型定义原生方法 调用时接收者位于 args[-1] 不计入参数个数
+    pub fn define_builtin_method(
+        &mut self,
+        type_: ObjType,
+        name: &str,
+        arity: Arity,
+        function: NativeFn,
+    ) {
+        let scope = HandleScope::new();
+        let name = scope.root(ObjString::take_string(name.into()));
+        let native = scope.root(ObjNative::new(
+            NativeFunction::Builtin(function),
+            name.get(),
+            arity,
+        ));
+        self.builtin_methods
+            .entry(type_)
+            .or_insert_with(|| Table {
+                map: HashMap::new(),
+            })
+            .set(name.get(), native.value());
+    }
+
+    pub fn define_native_function(&mut self, name: &str, arity: Arity, function: NativeFunction) {
+        let scope = HandleScope::new();
+        let name = scope.root(ObjString::take_string(name.into()));
+        let native = scope.root(ObjNative::new(function, name.get(), arity));
+        self.builtins.set(name.get(), native.value());
+    }
+
+    // 创建新的领域 领域之间全局变量互相隔离 共享驻留字符串、原生函数和前导
+    pub fn create_realm(&mut self) -> Realm {
+        let id = self.next_realm;
+        self.next_realm += 1;
+        self.realms.insert(
+            id,
+            Table {
+                map: HashMap::new(),
+            },
+        );
+        Realm { id }
+    }
+
+    // 切换到领域 之后编译执行的脚本使用该领域的全局变量
+    pub fn enter_realm(&mut self, realm: &Realm) {
+        self.switch_realm(realm.id);
+    }
+
+    // 回到默认领域
+    pub fn leave_realm(&mut self) {
+        self.switch_realm(0);
+    }
+
+    fn switch_realm(&mut self, id: usize) {
+        if id == self.current_realm {
+            return;
+        }
+        let globals = self.realms.remove(&id).expect("realm was dropped");
+        let previous = std::mem::replace(&mut self.globals, globals);
+        self.realms.insert(self.current_realm, previous);
+        self.current_realm = id;
     }
 
+    // 编译并执行 同一个虚拟机上的多次调用共享全局变量和已导入的模块 REPL 依赖这一点
+    // 解析器的状态每次都从头开始 上一次编译(包括出错的编译)不会影响下一次
     pub fn interpret(&mut self, source: String) -> InterpretResult {
-        let function = self.compile(source);
+        match self.compile(source) {
+            Ok(script) => self.run(&script),
+            Err(CompileError) => InterpretResult::CompileError,
+        }
+    }
+
+    // 执行 REPL 的一段输入 顶层 var 声明的变量是会话的局部变量
+    // 执行成功后留在栈底 后面的输入可以接着使用 出错时回到这段输入之前的变量
+    pub fn interpret_repl(&mut self, source: String) -> InterpretResult {
+        let mut locals = Some(self.repl_locals.clone());
+        let function = self.compile_source(source, None, &mut locals);
         if function.is_null() {
             return InterpretResult::CompileError;
         }
+        self.repl_input = true;
+        let result = self.run(&Script::new(function));
+        self.repl_input = false;
+        if let (InterpretResult::Ok, Some(locals)) = (&result, locals) {
+            self.repl_locals = locals;
+        }
+        result
+    }
+
+    // 丢弃 REPL 会话的局部变量 捕获它们的闭包保留各自的值
+    pub fn reset_repl_locals(&mut self) {
+        self.repl_locals.clear();
+        self.reset_stack();
+    }
+
+    // 栈底属于 REPL 会话的槽位数 槽位0和会话的局部变量
+    fn repl_slots(&self) -> usize {
+        match self.repl_locals.len() {
+            0 => 0,
+            count => count + 1,
+        }
+    }
+
+    // 在全新的全局环境中编译执行 看不到之前定义的全局变量 模块也重新导入
+    // 结束后丢弃这次的全局变量 恢复调用前的环境 原生函数和前导仍然可用
+    pub fn interpret_isolated(&mut self, source: String) -> InterpretResult {
+        let previous = self.current_realm;
+        let loaded_modules = std::mem::take(&mut self.loaded_modules);
+        let realm = self.create_realm();
+        self.enter_realm(&realm);
+        let result = self.interpret(source);
+        self.switch_realm(previous);
+        drop(realm);
+        self.loaded_modules = loaded_modules;
+        result
+    }
+
+    // 执行已经编译好(或从字节码文件恢复)的顶层函数 每次执行都从头开始 全局变量在多次执行间共享
+    pub fn run(&mut self, script: &Script) -> InterpretResult {
+        for hook in self.start_hooks.iter_mut() {
+            hook(script);
+        }
+
+        self.start(script);
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.timeout_check = self.instruction_count;
+        let result = loop {
+            match self.step(u64::MAX) {
+                StepResult::Done => break InterpretResult::Ok,
+                StepResult::Yielded => {}
+                StepResult::Error => break InterpretResult::RuntimeError,
+                StepResult::Timeout => break InterpretResult::Timeout,
+            }
+        };
+        self.deadline = None;
+
+        for hook in self.exit_hooks.iter_mut() {
+            hook(script, &result);
+        }
+        result
+    }
 
+    // 准备分步执行脚本 之后反复调用 step 直到返回 Done 或 Error
+    // 分步执行期间不能再 start 或 run 别的脚本 生命周期回调只在 run 中调用
+    pub fn start(&mut self, script: &Script) {
+        let function = script.function;
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add_function(function);
+        }
         self.push(obj_val!(function));
         let closure = ObjClosure::new(function);
         self.pop();
-        self.push(obj_val!(closure));
-        self.call(closure, 0);
+        // REPL 会话的局部变量已经在栈底 新的顶层函数放进槽位0 局部变量作为它的参数
+        let locals = self.repl_slots().saturating_sub(1);
+        if self.repl_input && locals > 0 {
+            self.stack[0] = obj_val!(closure);
+            self.call(closure, locals);
+        } else {
+            self.push(obj_val!(closure));
+            self.call(closure, 0);
+        }
+    }
+
+    // 最多执行 max_instructions 条指令 执行状态保存在调用帧中 下次调用从让出处继续
+    // 配额只在安全点检查 原生函数重入虚拟机的调用也不会中途让出 实际执行的指令数可能超过配额
+    pub fn step(&mut self, max_instructions: u64) -> StepResult {
+        if self.frame_count == 0 {
+            return StepResult::Done;
+        }
+        self.step_limit = Some(self.instruction_count.saturating_add(max_instructions));
+        let result = self.execute(0);
+        self.step_limit = None;
+        self.output.flush();
+        // 超时可能发生在原生函数重入的调用中 传到这里时已经成了普通的运行时错误
+        match result {
+            InterpretResult::Ok => StepResult::Done,
+            InterpretResult::Yielded => StepResult::Yielded,
+            _ if std::mem::take(&mut self.timed_out) => StepResult::Timeout,
+            _ => StepResult::Error,
+        }
+    }
+
+    // 执行脚本 执行期间原生函数可以通过 vm().context() 取得 context 执行结束后恢复原来的上下文
+    pub fn run_with_context(&mut self, script: &Script, context: &mut dyn Any) -> InterpretResult {
+        let previous = self.context.replace(context as *mut dyn Any);
+        let result = self.run(script);
+        self.context = previous;
+        result
+    }
+
+    // 当前执行的上下文 没有上下文或类型不符时返回None
+    pub fn context<T: Any>(&mut self) -> Option<&mut T> {
+        self.context
+            .and_then(|context| unsafe { (*context).downcast_mut::<T>() })
+    }
+
+    // 每次执行脚本之前调用
+    pub fn on_start(&mut self, hook: StartHook) {
+        self.start_hooks.push(hook);
+    }
+
+    // 每次脚本执行结束后调用 传入执行结果
+    pub fn on_exit(&mut self, hook: ExitHook) {
+        self.exit_hooks.push(hook);
+    }
+
+    // 每次调用需要审计的原生函数之前调用 任何一个回调返回false都会拒绝调用
+    pub fn on_audit(&mut self, hook: AuditHook) {
+        self.audit_hooks.push(hook);
+    }
+
+    // 观察一个变量 之后每个安全点上值和上次不同就打印出来
+    pub fn watch(&mut self, target: WatchTarget) {
+        self.watches.push(Watch { target, last: None });
+    }
 
-        return self.run();
+    // 把已定义的原生函数标记为需要审计 读写文件、访问系统的原生函数定义后都要标记
+    pub fn audit_native(&mut self, name: &str) {
+        let name = ObjString::take_string(name.into());
+        if let Some(&native) = self.builtins.get(name) {
+            unsafe { (*as_native!(native)).audited = true };
+        }
     }
 
+    // 登记或取消(传入nil)对象的终结器
+    pub fn set_finalizer(&mut self, object: *mut Obj, finalizer: Value) {
+        if let Value::Nil = finalizer {
+            self.finalizers.remove(&object);
+        } else {
+            self.finalizers.insert(object, finalizer);
+        }
+    }
+
+    // 安全点检查 需要中断当前的执行时返回结果 否则返回None继续执行
+    // 所有需要打断执行的检查都放在这里 不要分散到各条指令中
+    fn safepoint(&mut self, base: usize) -> Option<InterpretResult> {
+        // 原生函数请求的回收 这时所有的值都在栈上
+        if self.gc_requested {
+            memory::collect_garbage();
+        }
+        if !self.watches.is_empty() {
+            self.check_watches();
+        }
+        // 执行GC期间排队的终结器
+        if !self.pending_finalizers.is_empty() && !self.run_finalizers() {
+            return Some(InterpretResult::RuntimeError);
+        }
+        // 分步执行时配额用完就让出 只有最外层能让出 重入的调用要执行完才返回原生函数
+        if base == 0
+            && self
+                .step_limit
+                .is_some_and(|limit| self.instruction_count >= limit)
+        {
+            return Some(InterpretResult::Yielded);
+        }
+        // 超时后整个执行都要停下 重入的调用也一样
+        if let Some(deadline) = self.deadline {
+            if self.instruction_count >= self.timeout_check {
+                self.timeout_check = self.instruction_count + TIMEOUT_CHECK_INTERVAL;
+                if Instant::now() >= deadline {
+                    self.deadline = None;
+                    self.timed_out = true;
+                    self.runtime_error("Execution timed out.".into());
+                    return Some(InterpretResult::Timeout);
+                }
+            }
+        }
+        None
+    }
+
+    // 值按打印的形式比较 列表和实例的内容变化也能看到
+    fn check_watches(&mut self) {
+        let frame = &self.frames[self.frame_count - 1];
+        let function = unsafe { (*frame.closure).function };
+        let line = unsafe { (&(*function).chunk.lines)[frame.instruction_offset()] };
+        let mut watches = std::mem::take(&mut self.watches);
+        for watch in &mut watches {
+            let (label, current) = match &watch.target {
+                WatchTarget::Global(name) => {
+                    let value = self
+                        .strings
+                        .find(name, hash_string(name))
+                        .and_then(|key| self.globals.get(key));
+                    (name.clone(), value.map(|value| value.to_string()))
+                }
+                WatchTarget::Local(slot) => {
+                    let name = match unsafe { (*function).name.as_ref() } {
+                        Some(name) => name.chars.as_str(),
+                        None => "script",
+                    };
+                    let value = (frame.slots + slot < self.stack_top).then(|| frame.slot(*slot));
+                    (
+                        format!("slot {} in {}", slot, name),
+                        value.map(|value| value.to_string()),
+                    )
+                }
+            };
+            // 一开始就未定义的不打印 局部变量换了栈帧也算变化
+            let first = watch.last.is_none() && current.is_none();
+            let current = format!(
+                "{} = {}",
+                label,
+                current.as_deref().unwrap_or("<undefined>")
+            );
+            if watch.last.as_ref() != Some(&current) {
+                if !first {
+                    eprintln!("[line {}] watch {}", line, current);
+                }
+                watch.last = Some(current);
+            }
+        }
+        self.watches = watches;
+    }
+
+    // 依次执行排队的终结器 终结器出错时错误已经报告 返回false
+    fn run_finalizers(&mut self) -> bool {
+        while let Some((finalizer, object)) = self.pending_finalizers.pop() {
+            if self.call_function(finalizer, &[object]).is_none() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // 从原生函数重入虚拟机 调用 Lox 中的可调用对象并取回返回值
+    // 发生运行时错误时错误已经报告且栈已重置 返回None 原生函数应尽快返回
+    // 调用可能使栈扩容 原生函数收到的参数指针随之失效 要在调用之前读出参数
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> Option<Value> {
+        let base = self.frame_count;
+        if !self.ensure_stack(args.len() + 1) {
+            return None;
+        }
+        self.push(callee);
+        for arg in args {
+            self.push(*arg);
+        }
+
+        if !self.call_value(callee, args.len()) {
+            return None;
+        }
+        // 闭包会压入新栈帧 执行到它返回为止
+        if self.frame_count > base && !matches!(self.execute(base), InterpretResult::Ok) {
+            return None;
+        }
+
+        Some(self.pop())
+    }
+
+    // 设置调用深度上限 栈的上限随之按每帧 256 个槽位计算
+    // 执行中的栈帧数组不能移动 只能在两次执行之间调用
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        assert!(
+            self.frame_count == 0,
+            "cannot change the call depth while running"
+        );
+        self.frames = vec![CallFrame::new(); depth];
+    }
+
+    // 保证栈顶之上至少还有 slots 个空槽
+    // 扩容可能移动整个栈 栈帧和栈顶是下标不受影响 打开的提升值按新地址重新定位
+    fn ensure_stack(&mut self, slots: usize) -> bool {
+        let top = self.stack_top;
+        if top + slots <= self.stack.len() {
+            return true;
+        }
+        let stack_max = UINT8_COUNT * self.frames.len();
+        if top + slots > stack_max {
+            self.runtime_error("Stack overflow.".into());
+            return false;
+        }
+
+        let old = self.stack.as_mut_ptr();
+        let len = (top + slots).next_power_of_two().min(stack_max);
+        self.stack.resize(len, Value::Nil);
+        let new = self.stack.as_mut_ptr();
+        if new != old {
+            // 旧的栈已经释放 只按地址差换算
+            let rebase = |p: *mut Value| unsafe { new.byte_add(p as usize - old as usize) };
+            let mut upvalue = self.open_upvalues;
+            while !upvalue.is_null() {
+                unsafe {
+                    (*upvalue).location = rebase((*upvalue).location);
+                    upvalue = (*upvalue).next;
+                }
+            }
+        }
+        true
+    }
+
+    // REPL 会话的局部变量留在栈底 其余的槽位都丢弃 指向它们的提升值先关闭
     fn reset_stack(&mut self) {
-        self.stack_top = &mut self.stack as *mut Value;
+        let keep = self.repl_slots();
+        self.close_upvalues(keep);
+        self.stack_top = keep;
         self.frame_count = 0;
-        self.open_upvalues = null_mut();
-    }
-
-    fn runtime_error(&mut self, message: String) {
-        eprintln!("{}", message);
-
-        let mut i = self.frame_count as i32 - 1;
-        while i >= 0 {
-            let frame = &self.frames[i as usize];
-            let function = unsafe { (*(*frame).closure).function };
-            let instruction =
-                frame.ip as usize - unsafe { (*function).chunk.code.as_mut_ptr() } as usize - 1;
-            eprint!("[line {}] in ", unsafe {
-                (*function).chunk.lines[instruction]
-            });
-            if unsafe { (*function).name.is_null() } {
-                eprintln!("script");
+    }
+
+    // 原生函数也通过它报告错误 报告后原生函数应直接返回
+    pub fn runtime_error(&mut self, message: String) {
+        // 先写出错误之前的输出
+        self.output.flush();
+        let mut report = self.render_error(&message);
+        report.push_str(&self.stack_trace());
+        self.error_sink.report(Severity::Error, &report);
+        self.reset_stack();
+    }
+
+    // 运行时错误的消息 知道出错位置的源码时和编译错误一样附上源码片段
+    // 不用颜色时第一行仍是 clox 格式的消息
+    fn render_error(&self, message: &str) -> String {
+        let mut out = if self.color {
+            diagnostic::header(Severity::Error, message)
+        } else {
+            message.to_string()
+        };
+        out.push('\n');
+        let located = self.frames[..self.frame_count].last().and_then(|frame| {
+            let chunk = unsafe { &(*(*frame.closure).function).chunk };
+            let instruction = frame.instruction_offset();
+            let file = chunk.file.as_deref()?;
+            let line = chunk.lines[instruction];
+            let (source, start) =
+                self.sources
+                    .locate(file, line, *chunk.columns.get(instruction)?)?;
+            Some(diagnostic::snippet(
+                Severity::Error,
+                Some(file),
+                line,
+                source,
+                start,
+                1,
+                self.color,
+            ))
+        });
+        out.push_str(&located.unwrap_or_default());
+        out
+    }
+
+    // 原生函数报告错误 调用返回后由虚拟机转换为运行时错误 栈回溯中包含原生函数名
+    // 报告错误后原生函数应尽快返回 返回值会被忽略
+    pub fn native_error(&mut self, message: String) {
+        self.pending_native_error.get_or_insert(message);
+    }
+
+    // 递归时同一函数同一行的栈帧连续出现 只显示前几个 其余合并成一行
+    fn stack_trace(&self) -> String {
+        let mut out = String::new();
+        let mut last = None;
+        let mut repeated = 0;
+        let mut hidden = 0;
+        for i in (0..self.frame_count).rev() {
+            let frame = &self.frames[i];
+            let function = unsafe { (*frame.closure).function };
+            let line = unsafe { (&(*function).chunk.lines)[frame.instruction_offset()] };
+            if last == Some((function, line)) {
+                repeated += 1;
             } else {
-                eprintln!("{}()", unsafe { &(*(*function).name).chars });
+                write_hidden_frames(&mut out, hidden);
+                hidden = 0;
+                repeated = 1;
+                last = Some((function, line));
+            }
+            if repeated > TRACE_REPEAT_SHOWN {
+                hidden += 1;
+                continue;
             }
-            i -= 1;
+            write_frame(&mut out, frame);
         }
-        self.reset_stack();
+        write_hidden_frames(&mut out, hidden);
+        out
     }
 
     fn call(&mut self, closure: *mut ObjClosure, arg_count: usize) -> bool {
@@ -264,49 +1262,74 @@ impl VM {
             return false;
         }
         // 调用栈过长
-        if self.frame_count == FRAMES_MAX {
+        if self.frame_count == self.frames.len() {
             self.runtime_error("Stack overflow.".into());
             return false;
         }
+        // 按编译时算出的深度预留新栈帧要用的槽位 被调用者和参数已经在栈上
+        let max_stack = unsafe { (*(*closure).function).max_stack };
+        if !self.ensure_stack(max_stack.saturating_sub(arg_count + 1)) {
+            return false;
+        }
         // 记录新函数栈帧
         let frame = &mut self.frames[self.frame_count];
         self.frame_count += 1;
         let frame = frame as *mut CallFrame;
         unsafe {
             (*frame).closure = closure;
-            (*frame).ip = (*(*closure).function).chunk.code.as_mut_ptr();
-            (*frame).slots = self.stack_top.sub(arg_count + 1);
+            (*frame).code = (*(*closure).function).chunk.code.as_ptr();
+            (*frame).ip = 0;
+            (*frame).slots = self.stack_top - arg_count - 1;
         }
 
         true
     }
 
-    fn run(&mut self) -> InterpretResult {
+    // 执行到栈帧数回落到 base 为止 重入调用时 base 为调用前的栈帧数
+    fn execute(&mut self, base: usize) -> InterpretResult {
         // 拿到vm中的栈帧
         let mut frame = &mut self.frames[self.frame_count - 1] as *mut CallFrame;
 
         loop {
-            #[cfg(feature = "debug_trace_execution")]
-            {
+            if self.trace {
                 print!("          ");
-                let mut slot = self.stack.as_mut_ptr();
-                while slot < self.stack_top {
+                for value in &self.stack[..self.stack_top] {
                     print!("[ ");
-                    (unsafe { *slot }).print();
+                    value.print();
                     print!(" ]");
-                    slot = unsafe { slot.add(1) };
                 }
-                println!("");
+                println!();
                 unsafe {
                     let chunk = &mut (*(*(*frame).closure).function).chunk;
-                    let tmp = chunk.code.as_mut_ptr() as usize;
-                    chunk.disassemble_instruction((*frame).ip as usize - tmp);
+                    chunk.disassemble_instruction((*frame).ip);
                 }
             }
 
-            let instruction: OpCode = read_byte!(frame).into();
-
-            let op_code: OpCode = instruction.into();
+            self.instruction_count += 1;
+            if let Some(coverage) = &mut self.coverage {
+                unsafe { coverage.hit((*(*frame).closure).function, (*frame).ip) };
+            }
+            if CHECKED_IP {
+                unsafe { (*frame).check_instruction() };
+            }
+            let byte = read_byte!(frame);
+            let Some(instruction) = OpCode::from_byte(byte) else {
+                self.runtime_error(format!("Unknown opcode {}.", byte));
+                return InterpretResult::RuntimeError;
+            };
+
+            // 记录执行前的栈深度 执行后按指令元数据校验栈效应
+            let (depth_before, frames_before, expected_effect) = unsafe {
+                let operands = (*frame).code.add((*frame).ip);
+                let arg_count = instruction.info().operands.arg_count(|i| *operands.add(i));
+                (
+                    self.stack_top as isize,
+                    self.frame_count,
+                    instruction.info().effect.resolve(arg_count),
+                )
+            };
+
+            let op_code: OpCode = instruction;
             match op_code {
                 OpCode::Constant => {
                     let constant = read_constant!(frame);
@@ -319,22 +1342,18 @@ impl VM {
                     self.pop();
                 }
                 OpCode::GetLocal => {
-                    let slot = read_byte!(frame);
-                    unsafe {
-                        self.push(*(*frame).slots.add(slot as usize));
-                    }
+                    let slot = unsafe { (*frame).slots } + read_byte!(frame) as usize;
+                    self.push(self.stack[slot]);
                 }
                 OpCode::SetLocal => {
-                    let slot = read_byte!(frame);
-                    unsafe {
-                        std::ptr::write((*frame).slots.add(slot as usize), self.peek(0));
-                    }
+                    let slot = unsafe { (*frame).slots } + read_byte!(frame) as usize;
+                    self.stack[slot] = self.peek(0);
                 }
                 OpCode::GetGlobal => {
                     let name = read_string!(frame);
 
-                    match self.globals.get(name) {
-                        Some(value) => self.push(value.clone()),
+                    match self.globals.get(name).or_else(|| self.builtins.get(name)) {
+                        Some(value) => self.push(*value),
                         None => {
                             self.runtime_error(format!("Undefined variable '{}'.", unsafe {
                                 &(*name).chars
@@ -345,14 +1364,20 @@ impl VM {
                 }
                 OpCode::DefineGlobal => {
                     let name = read_string!(frame);
-                    let p = self.peek(0);
+                    let mut p = self.peek(0);
+                    if self.hot_reload {
+                        if let Some(&old) = self.globals.get(name) {
+                            p = self.reload(old, p);
+                        }
+                    }
                     self.globals.set(name, p);
                     self.pop();
                 }
                 OpCode::SetGlobal => {
                     let name = read_string!(frame);
                     let p = self.peek(0);
-                    if self.globals.set(name, p) {
+                    // 给共享的原生函数赋值只在当前领域中覆盖
+                    if self.globals.set(name, p) && self.builtins.get(name).is_none() {
                         self.globals.remove(name);
                         self.runtime_error(format!("Undefined variable '{}'.", unsafe {
                             &(*name).chars
@@ -376,20 +1401,25 @@ impl VM {
                     }
                 }
                 OpCode::GetProperty => {
-                    if !is_instance!(self.peek(0)) {
-                        self.runtime_error("Only instances have properties.".into());
-                        return InterpretResult::RuntimeError;
-                    }
-
-                    let instance = as_instance!(self.peek(0));
                     let name = read_string!(frame);
-
-                    if let Some(value) = self.globals.get(name) {
-                        let v = value.clone();
-                        self.pop();
-                        self.push(v);
-                    } else if !self.bind_method(unsafe { (*instance).class }, name) {
-                        return InterpretResult::RuntimeError;
+                    // 类的属性为未绑定方法和 name、superclass 函数对象也有少量只读属性
+                    if is_class!(self.peek(0)) {
+                        if !self.unbound_method(name) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else if !is_instance!(self.peek(0)) {
+                        if !self.function_property(name) {
+                            return InterpretResult::RuntimeError;
+                        }
+                    } else {
+                        let instance = as_instance!(self.peek(0));
+                        if let Some(value) = unsafe { (*(*instance).fields).get(name) } {
+                            let v = *value;
+                            self.pop();
+                            self.push(v);
+                        } else if !self.bind_method(unsafe { (*instance).class }, name) {
+                            return InterpretResult::RuntimeError;
+                        }
                     }
                 }
                 OpCode::SetProperty => {
@@ -399,8 +1429,13 @@ impl VM {
                     }
 
                     let instance = as_instance!(self.peek(1));
+                    let name = read_string!(frame);
+                    if unsafe { (*instance).frozen } {
+                        self.runtime_error("Cannot set a field on a frozen instance.".into());
+                        return InterpretResult::RuntimeError;
+                    }
                     unsafe {
-                        (*(*instance).fields).set(read_string!(frame), self.peek(0));
+                        (*(*instance).fields).set(name, self.peek(0));
                     }
                     let value = self.pop();
                     self.pop();
@@ -422,20 +1457,50 @@ impl VM {
                 OpCode::Greater => binary_op!(self, bool, >),
                 OpCode::Less => binary_op!(self, bool, <),
                 OpCode::Add => {
-                    if is_string!(self.peek(0)) && is_string!(self.peek(1)) {
+                    // 字符串与字符串或数字相加时拼接 数字按 print 的格式转换
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    if (is_string!(a) && (is_string!(b) || is_number!(b)))
+                        || (is_number!(a) && is_string!(b))
+                    {
                         self.concatenate();
                     } else if (is_number!(self.peek(0)) && is_number!(self.peek(1))) {
                         let b = as_number!(self.pop());
                         let a = as_number!(self.pop());
                         self.push(Value::Number(a + b));
+                        check_nan!(self);
+                    } else {
+                        self.runtime_error(
+                            "Operands must be two numbers, two strings, or a string and a number."
+                                .into(),
+                        );
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::Subtract => {
+                    binary_op!(self, f64, -);
+                    check_nan!(self);
+                }
+                OpCode::Multiply => {
+                    if is_string!(self.peek(0)) || is_string!(self.peek(1)) {
+                        if !self.repeat_string() {
+                            return InterpretResult::RuntimeError;
+                        }
                     } else {
-                        self.runtime_error("Operands must be two numbers or two strings.".into());
+                        binary_op!(self, f64, *);
+                        check_nan!(self);
+                    }
+                }
+                OpCode::Divide => {
+                    if self.strict_math
+                        && is_number!(self.peek(1))
+                        && matches!(self.peek(0), Value::Number(n) if n == 0.0)
+                    {
+                        self.runtime_error("Division by zero.".into());
                         return InterpretResult::RuntimeError;
                     }
+                    binary_op!(self, f64, /);
+                    check_nan!(self);
                 }
-                OpCode::Subtract => binary_op!(self, f64, -),
-                OpCode::Multiply => binary_op!(self, f64, *),
-                OpCode::Divide => binary_op!(self, f64, /),
                 OpCode::Not => {
                     let top = self.pop();
                     self.push(Value::Boolean(is_falsey(top)));
@@ -448,32 +1513,46 @@ impl VM {
                     let top = self.pop();
                     self.push(Value::Number(-as_number!(top)));
                 }
+                OpCode::BitAnd => bitwise_op!(self, |a, b| a & b),
+                OpCode::BitOr => bitwise_op!(self, |a, b| a | b),
+                OpCode::BitXor => bitwise_op!(self, |a, b| a ^ b),
+                // 移位数只取低6位
+                OpCode::ShiftLeft => bitwise_op!(self, |a: i64, b| a.wrapping_shl(b as u32)),
+                OpCode::ShiftRight => bitwise_op!(self, |a: i64, b| a.wrapping_shr(b as u32)),
+                OpCode::BitNot => {
+                    let Value::Number(n) = self.peek(0) else {
+                        self.runtime_error("Operand must be a number.".into());
+                        return InterpretResult::RuntimeError;
+                    };
+                    self.stack[self.stack_top - 1] = Value::Number(!(n as i64) as f64);
+                }
                 OpCode::Print => {
-                    self.pop().print();
-                    println!("");
+                    let value = self.pop();
+                    self.output.print_line(value);
+                    // 跟踪执行时立即写出 保持和轨迹的先后顺序
+                    if self.trace {
+                        self.output.flush();
+                    }
                 }
                 OpCode::Jump => {
                     let offset = read_short!(frame);
-                    unsafe {
-                        (*frame).ip = (*frame).ip.add(offset as usize);
-                    }
+                    unsafe { (*frame).ip += offset as usize };
                 }
                 OpCode::JumpIfFalse => {
                     let offset = read_short!(frame);
                     if is_falsey(self.peek(0)) {
-                        unsafe {
-                            (*frame).ip = (*frame).ip.add(offset as usize);
-                        }
+                        unsafe { (*frame).ip += offset as usize };
                     }
                 }
                 OpCode::Loop => {
                     let offset = read_short!(frame);
-                    unsafe {
-                        (*frame).ip = (*frame).ip.sub(offset as usize);
-                    }
+                    unsafe { (*frame).ip -= offset as usize };
                 }
-                OpCode::Call => {
-                    let arg_count = read_byte!(frame);
+                OpCode::Call | OpCode::CallWide => {
+                    let arg_count = match op_code {
+                        OpCode::Call => read_byte!(frame) as usize,
+                        _ => read_short!(frame) as usize,
+                    };
                     let p = self.peek(arg_count as i32);
                     if !self.call_value(p, arg_count) {
                         return InterpretResult::RuntimeError;
@@ -482,17 +1561,37 @@ impl VM {
                     // 调用成功后将栈帧还回去
                     frame = &mut self.frames[self.frame_count - 1];
                 }
-                OpCode::Invoke => {
+                // 局部变量和提升值中多半是闭包 是闭包时直接调用 省去按类型分派
+                OpCode::CallLocal | OpCode::CallUpvalue => {
+                    let arg_count = read_byte!(frame) as usize;
+                    let callee = self.peek(arg_count as i32);
+                    let called = if callee.is_obj_type(ObjType::Closure) {
+                        self.call(as_closure!(callee), arg_count)
+                    } else {
+                        self.call_value(callee, arg_count)
+                    };
+                    if !called {
+                        return InterpretResult::RuntimeError;
+                    }
+                    frame = &mut self.frames[self.frame_count - 1];
+                }
+                OpCode::Invoke | OpCode::InvokeWide => {
                     let method = read_string!(frame);
-                    let arg_count = read_byte!(frame);
+                    let arg_count = match op_code {
+                        OpCode::Invoke => read_byte!(frame) as usize,
+                        _ => read_short!(frame) as usize,
+                    };
                     if !self.invoke(method, arg_count) {
                         return InterpretResult::RuntimeError;
                     }
                     frame = &mut self.frames[self.frame_count - 1];
                 }
-                OpCode::SuperInvoke => {
+                OpCode::SuperInvoke | OpCode::SuperInvokeWide => {
                     let method = read_string!(frame);
-                    let arg_count = read_byte!(frame);
+                    let arg_count = match op_code {
+                        OpCode::SuperInvoke => read_byte!(frame) as usize,
+                        _ => read_short!(frame) as usize,
+                    };
                     let superclass = as_class!(self.pop());
                     if !self.invoke_from_class(superclass, method, arg_count) {
                         return InterpretResult::RuntimeError;
@@ -511,7 +1610,7 @@ impl VM {
                         unsafe {
                             if is_local != 0 {
                                 let ptr = (*closure).upvalues.add(i);
-                                *ptr = self.capture_upvalue((*frame).slots.add(index as usize));
+                                *ptr = self.capture_upvalue((*frame).slots + index as usize);
                             } else {
                                 let ptr = (*closure).upvalues.add(i);
                                 *ptr = *(*(*frame).closure).upvalues.add(index as usize);
@@ -521,20 +1620,40 @@ impl VM {
                     }
                 }
                 OpCode::CloseUpvalue => {
-                    self.close_upvalues(unsafe { self.stack_top.sub(1) });
+                    self.close_upvalues(self.stack_top - 1);
                     self.pop();
                 }
+                OpCode::PopN => {
+                    let count = read_byte!(frame) as usize;
+                    self.stack_top -= count;
+                }
+                OpCode::CloseUpvalueN => {
+                    let count = read_byte!(frame) as usize;
+                    self.close_upvalues(self.stack_top - count);
+                    self.stack_top -= count;
+                }
                 OpCode::Return => {
                     let result = self.pop();
-                    self.close_upvalues((unsafe { *frame }).slots);
+                    let slots = unsafe { (*frame).slots };
                     self.frame_count -= 1;
+                    // REPL 输入顶层的局部变量连同槽位0留在栈上 提升值保持打开 后面的输入接着使用
+                    if self.frame_count == 0 && self.repl_input {
+                        if self.stack_top == slots + 1 {
+                            self.pop();
+                        }
+                        return InterpretResult::Ok;
+                    }
+                    self.close_upvalues(slots);
                     if self.frame_count == 0 {
                         self.pop();
                         return InterpretResult::Ok;
                     }
 
-                    self.stack_top = (unsafe { *frame }).slots;
+                    self.stack_top = slots;
                     self.push(result);
+                    if self.frame_count == base {
+                        return InterpretResult::Ok;
+                    }
                     frame = &mut self.frames[self.frame_count - 1];
                 }
                 OpCode::Class => {
@@ -549,11 +1668,95 @@ impl VM {
 
                     let subclass = as_class!(self.peek(0));
                     unsafe {
+                        (*subclass).superclass = as_class!(superclass);
                         (*(*subclass).methods).add_all(&*(*as_class!(superclass)).methods);
                     }
                     self.pop(); // Subclass.
                 }
                 OpCode::Method => self.define_method(read_string!(frame)),
+                OpCode::BuildList => {
+                    let item_count = read_byte!(frame) as usize;
+                    let items = self.stack[self.stack_top - item_count..self.stack_top].to_vec();
+                    // 元素仍在栈上 分配列表时不会被回收
+                    let list = ObjList::new(items);
+                    self.stack_top -= item_count;
+                    self.push(obj_val!(list));
+                }
+                OpCode::GetIndex => {
+                    if !self.get_index() {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::SetIndex => {
+                    if !self.set_index() {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::Import => {
+                    let name = read_string!(frame);
+                    if !self.import_module(name) {
+                        return InterpretResult::RuntimeError;
+                    }
+                    frame = &mut self.frames[self.frame_count - 1];
+                }
+                OpCode::CallIntrinsic => {
+                    let id = read_byte!(frame);
+                    let arg_count = read_byte!(frame);
+                    let callee = self.peek(arg_count as i32);
+                    // 全局变量仍是原来的原生函数时直接求值 否则按普通调用处理
+                    let result = match Intrinsic::from_byte(id) {
+                        Some(intrinsic)
+                            if arg_count == 1
+                                && values_equal(callee, self.intrinsics[id as usize]) =>
+                        {
+                            intrinsic.apply(self.peek(0))
+                        }
+                        _ => None,
+                    };
+                    match result {
+                        Some(result) => {
+                            self.stack_top -= 2;
+                            self.push(result);
+                        }
+                        None => {
+                            if !self.call_value(callee, arg_count as usize) {
+                                return InterpretResult::RuntimeError;
+                            }
+                            frame = &mut self.frames[self.frame_count - 1];
+                        }
+                    }
+                }
+            }
+
+            // 调用与返回会切换栈帧 其余指令的栈深度变化必须与元数据一致
+            if cfg!(debug_assertions) && self.frame_count == frames_before {
+                let depth_after = self.stack_top as isize;
+                debug_assert_eq!(
+                    depth_after - depth_before,
+                    expected_effect as isize,
+                    "stack effect mismatch for {}",
+                    instruction.info().name
+                );
+            }
+
+            // 循环回跳和调用、返回之后是安全点 指令已执行完 执行状态都在栈帧中
+            if matches!(
+                instruction,
+                OpCode::Loop
+                    | OpCode::Call
+                    | OpCode::CallWide
+                    | OpCode::CallLocal
+                    | OpCode::CallUpvalue
+                    | OpCode::CallIntrinsic
+                    | OpCode::Invoke
+                    | OpCode::InvokeWide
+                    | OpCode::SuperInvoke
+                    | OpCode::SuperInvokeWide
+                    | OpCode::Return
+            ) {
+                if let Some(result) = self.safepoint(base) {
+                    return result;
+                }
             }
         }
 
@@ -567,7 +1770,9 @@ impl VM {
         self.pop();
     }
 
-    fn close_upvalues(&mut self, last: *mut Value) {
+    // 关闭指向第 last 个槽位及其以上的提升值
+    fn close_upvalues(&mut self, last: usize) {
+        let last = self.stack_slot(last);
         unsafe {
             while !self.open_upvalues.is_null() && (*self.open_upvalues).location >= last {
                 let upvalue = self.open_upvalues;
@@ -578,8 +1783,9 @@ impl VM {
         }
     }
 
-    // 捕获提升值
-    fn capture_upvalue(&mut self, local: *mut Value) -> *mut ObjUpvalue {
+    // 捕获栈中第 local 个槽位的提升值
+    fn capture_upvalue(&mut self, local: usize) -> *mut ObjUpvalue {
+        let local = self.stack_slot(local);
         let mut prev_upvalue: *mut ObjUpvalue = null_mut();
         let mut upvalue = self.open_upvalues;
         while !upvalue.is_null() && unsafe { (*upvalue).location } > local {
@@ -604,35 +1810,44 @@ impl VM {
         created_upvalue
     }
 
-    fn invoke(&mut self, name: *mut ObjString, arg_count: u8) -> bool {
+    fn invoke(&mut self, name: *mut ObjString, arg_count: usize) -> bool {
         let receiver = self.peek(arg_count as i32);
 
         if !is_instance!(receiver) {
-            self.runtime_error("Only instances have methods.".into());
-            return false;
+            // 内置类型的原生方法 接收者留在被调用者的槽位上
+            let method = match receiver {
+                Value::Object(obj) => self
+                    .builtin_methods
+                    .get(&unsafe { (*obj).type_ })
+                    .and_then(|methods| methods.get(name))
+                    .copied(),
+                _ => None,
+            };
+            return match method {
+                Some(method) => self.call_value(method, arg_count),
+                None => {
+                    self.runtime_error("Only instances have methods.".into());
+                    false
+                }
+            };
         }
 
         let instance = as_instance!(receiver);
         if let Some(value) = unsafe { (*(*instance).fields).get(name) } {
-            unsafe {
-                std::ptr::write(
-                    self.stack_top.offset(-(arg_count as isize) - 1),
-                    value.clone(),
-                );
-            }
-            return self.call_value(value.clone(), arg_count);
+            self.stack[self.stack_top - arg_count - 1] = *value;
+            return self.call_value(*value, arg_count);
         }
-        return self.invoke_from_class(unsafe { (*instance).class }, name, arg_count);
+        self.invoke_from_class(unsafe { (*instance).class }, name, arg_count)
     }
 
     fn invoke_from_class(
         &mut self,
         class: *mut ObjClass,
         name: *mut ObjString,
-        arg_count: u8,
+        arg_count: usize,
     ) -> bool {
         if let Some(method) = unsafe { (*(*class).methods).get(name) } {
-            self.call(as_closure!(method.clone()), arg_count as usize)
+            self.call(as_closure!(*method), arg_count)
         } else {
             self.runtime_error(format!("Undefined property '{}'.", unsafe {
                 &(*name).chars
@@ -642,27 +1857,37 @@ impl VM {
     }
 
     // 调用 值类型  仅接受 函数 类 方法
-    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
         if is_obj!(callee) {
             match unsafe { (*as_obj(callee)).type_ } {
                 ObjType::BoundMethod => {
                     let bound = as_bound_method!(callee);
-                    unsafe {
-                        let ptr = self.stack_top.offset(-(arg_count as isize) - 1);
-                        std::ptr::write(ptr, (*bound).receiver);
-                        return self.call((*bound).method, arg_count as usize);
+                    self.stack[self.stack_top - arg_count - 1] = unsafe { (*bound).receiver };
+                    return self.call(unsafe { (*bound).method }, arg_count);
+                }
+                ObjType::UnboundMethod => {
+                    if arg_count == 0 {
+                        self.runtime_error("Expected a receiver as the first argument.".into());
+                        return false;
                     }
+                    // 参数整体下移一格 接收者落在方法的0号槽位
+                    let method = unsafe { (*as_unbound_method!(callee)).method };
+                    let args = self.stack_top - arg_count;
+                    self.stack.copy_within(args..self.stack_top, args - 1);
+                    self.stack_top -= 1;
+                    return self.call(method, arg_count - 1);
                 }
                 ObjType::Class => {
                     let class = as_class!(callee);
-                    unsafe {
-                        let ptr = self.stack_top.offset(-(arg_count as isize) - 1);
-                        std::ptr::write(ptr, Value::Object(ObjInstance::new(class) as *mut Obj));
-                    }
+                    let instance = Value::Object(ObjInstance::new(class) as *mut Obj);
+                    self.stack[self.stack_top - arg_count - 1] = instance;
 
+                    // init 的查找规则 类自己定义了 init 就只用它 父类的 init 要显式用 super.init 调用
+                    // 没有定义时 Inherit 已经把最近的祖先的 init 复制过来 参数原样传给它
+                    // 整条继承链上都没有 init 时不接受参数
                     match unsafe { (*(*class).methods).get(self.init_string) } {
                         Some(initializer) => {
-                            return self.call(as_closure!(initializer.clone()), arg_count as usize);
+                            return self.call(as_closure!(*initializer), arg_count);
                         }
                         None => {
                             if arg_count != 0 {
@@ -676,13 +1901,50 @@ impl VM {
                         }
                     }
                 }
-                ObjType::Closure => return self.call(as_closure!(callee), arg_count as usize),
+                ObjType::Closure => return self.call(as_closure!(callee), arg_count),
                 ObjType::Native => {
-                    let native = unsafe { as_native!(callee).as_mut().unwrap() }.function;
-                    let result = native(arg_count as usize, unsafe {
-                        self.stack_top.sub(arg_count as usize)
-                    });
-                    self.stack_top = unsafe { self.stack_top.sub((arg_count + 1) as usize) };
+                    let native = unsafe { as_native!(callee).as_mut().unwrap() };
+                    if !native.arity.accepts(arg_count) {
+                        self.runtime_error(format!(
+                            "Expected {} arguments but got {} in native '{}'.",
+                            native.arity,
+                            arg_count,
+                            unsafe { &(*native.name).chars }
+                        ));
+                        return false;
+                    }
+                    let args = self.stack_slot(self.stack_top - arg_count);
+                    if native.audited && !self.audit(native.name, args, arg_count) {
+                        self.runtime_error(format!("Call to native '{}' was denied.", unsafe {
+                            &(*native.name).chars
+                        }));
+                        return false;
+                    }
+                    let native = native.function;
+                    // 原生函数内创建的句柄在调用结束时统一释放
+                    let _scope = HandleScope::new();
+                    let result = match native {
+                        NativeFunction::Builtin(function) => function(arg_count, args),
+                        NativeFunction::Plugin(function) => {
+                            plugin::call_plugin_native(function, arg_count, args)
+                        }
+                    };
+                    if let Some(message) = self.pending_native_error.take() {
+                        // 先写出错误之前的输出
+                        self.output.flush();
+                        let mut report = self.render_error(&message);
+                        let name = unsafe { &(*(*as_native!(callee)).name).chars };
+                        writeln!(report, "[native] in {}()", name).unwrap();
+                        report.push_str(&self.stack_trace());
+                        self.error_sink.report(Severity::Error, &report);
+                        self.reset_stack();
+                        return false;
+                    }
+                    // 原生函数调用的 Lox 代码发生了运行时错误 栈已经重置
+                    if self.frame_count == 0 {
+                        return false;
+                    }
+                    self.stack_top -= arg_count + 1;
                     self.push(result);
                     return true;
                 }
@@ -693,26 +1955,295 @@ impl VM {
         false
     }
 
+    // 把原生函数的调用交给审计回调 有回调拒绝时返回false
+    fn audit(&mut self, name: *mut ObjString, args: *mut Value, arg_count: usize) -> bool {
+        let name = unsafe { &(*name).chars };
+        let args = unsafe { std::slice::from_raw_parts(args, arg_count) };
+        self.audit_hooks.iter_mut().all(|hook| hook(name, args))
+    }
+
+    // 把下标转换为 [0, len) 内的整数
+    fn check_index(&mut self, index: Value, len: usize) -> Option<usize> {
+        let index = match index {
+            Value::Number(n) if n.fract() == 0.0 => n,
+            _ => {
+                self.runtime_error("Index must be an integer.".into());
+                return None;
+            }
+        };
+        if index < 0.0 || index >= len as f64 {
+            self.runtime_error("Index out of range.".into());
+            return None;
+        }
+        Some(index as usize)
+    }
+
+    fn get_index(&mut self) -> bool {
+        let index = self.peek(0);
+        let target = self.peek(1);
+
+        let result = if is_list!(target) {
+            let list = unsafe { &*as_list!(target) };
+            match self.check_index(index, list.items.len()) {
+                Some(i) => list.items[i],
+                None => return false,
+            }
+        } else if is_buffer!(target) {
+            let buffer = unsafe { &*as_buffer!(target) };
+            match self.check_index(index, buffer.bytes.len()) {
+                Some(i) => Value::Number(buffer.bytes[i] as f64),
+                None => return false,
+            }
+        } else if is_string!(target) {
+            let string = as_string!(target);
+            let string = unsafe { &*string };
+            match self.check_index(index, string.chars.chars().count()) {
+                Some(i) => {
+                    let c = string.chars.chars().nth(i).unwrap();
+                    obj_val!(ObjString::take_string(c.to_string()))
+                }
+                None => return false,
+            }
+        } else {
+            self.runtime_error("Only lists, buffers and strings can be indexed.".into());
+            return false;
+        };
+
+        self.pop();
+        self.pop();
+        self.push(result);
+        true
+    }
+
+    fn set_index(&mut self) -> bool {
+        let value = self.peek(0);
+        let index = self.peek(1);
+        let target = self.peek(2);
+
+        if is_list!(target) {
+            let list = unsafe { &mut *as_list!(target) };
+            match self.check_index(index, list.items.len()) {
+                Some(i) => list.items[i] = value,
+                None => return false,
+            }
+        } else if is_buffer!(target) {
+            let byte = match value {
+                Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(&n) => n as u8,
+                _ => {
+                    self.runtime_error("Buffer byte must be an integer from 0 to 255.".into());
+                    return false;
+                }
+            };
+            let buffer = unsafe { &mut *as_buffer!(target) };
+            match self.check_index(index, buffer.bytes.len()) {
+                Some(i) => buffer.bytes[i] = byte,
+                None => return false,
+            }
+        } else {
+            self.runtime_error("Only list and buffer items can be assigned.".into());
+            return false;
+        }
+
+        self.pop();
+        self.pop();
+        self.pop();
+        self.push(value);
+        true
+    }
+
+    // 函数的内省属性 fn.name 和 fn.arity 支持闭包、绑定方法和原生函数
+    // 参数个数可变的原生函数 arity 为最少参数个数 不限个数的为nil
+    fn function_property(&mut self, name: *mut ObjString) -> bool {
+        let callee = self.peek(0);
+        let (function_name, arity) = match callee {
+            Value::Object(obj) => match unsafe { (*obj).type_ } {
+                ObjType::Closure | ObjType::BoundMethod | ObjType::UnboundMethod => {
+                    let closure = if callee.is_obj_type(ObjType::Closure) {
+                        as_closure!(callee)
+                    } else if callee.is_obj_type(ObjType::BoundMethod) {
+                        unsafe { (*as_bound_method!(callee)).method }
+                    } else {
+                        unsafe { (*as_unbound_method!(callee)).method }
+                    };
+                    let function = unsafe { (*closure).function };
+                    // 未绑定方法还要多接收一个接收者参数
+                    let mut arity = unsafe { (*function).arity };
+                    if callee.is_obj_type(ObjType::UnboundMethod) {
+                        arity += 1;
+                    }
+                    (unsafe { (*function).name }, Value::Number(arity as f64))
+                }
+                ObjType::Native => {
+                    let native = unsafe { &*as_native!(callee) };
+                    let arity = match native.arity {
+                        Arity::Exact(n) | Arity::Range(n, _) => Value::Number(n as f64),
+                        Arity::Any => Value::Nil,
+                    };
+                    (native.name, arity)
+                }
+                _ => {
+                    self.runtime_error("Only instances have properties.".into());
+                    return false;
+                }
+            },
+            _ => {
+                self.runtime_error("Only instances have properties.".into());
+                return false;
+            }
+        };
+
+        let value = match unsafe { (*name).chars.as_str() } {
+            "name" if function_name.is_null() => Value::Nil,
+            "name" => obj_val!(function_name),
+            "arity" => arity,
+            _ => {
+                self.runtime_error(format!("Undefined property '{}'.", unsafe {
+                    &(*name).chars
+                }));
+                return false;
+            }
+        };
+        self.pop();
+        self.push(value);
+        true
+    }
+
+    // 导入模块 压入模块的顶层闭包并调用 已经导入过的模块直接得到nil
+    // 模块在执行前就登记为已导入 循环导入不会重复执行
+    fn import_module(&mut self, name: *mut ObjString) -> bool {
+        let name = unsafe { (*name).chars.clone() };
+        let path = match module::resolve_module(&name) {
+            Some(path) => path,
+            None => {
+                self.runtime_error(format!("Could not find module '{}'.", name));
+                return false;
+            }
+        };
+        let path = path.canonicalize().unwrap_or(path);
+        if !self.loaded_modules.insert(path.clone()) {
+            self.push(Value::Nil);
+            return true;
+        }
+
+        let function = match module::load_module(&path) {
+            Ok(function) => function,
+            Err(message) => {
+                self.runtime_error(format!("Could not load module '{}': {}", name, message));
+                return false;
+            }
+        };
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add_function(function);
+        }
+        self.push(obj_val!(function));
+        let closure = ObjClosure::new(function);
+        self.pop();
+        self.push(obj_val!(closure));
+        self.call(closure, 0)
+    }
+
+    // 热重载 把已有的函数和类原地换成新的定义 返回要存入全局变量的值
+    // 之前存下的引用(列表里的函数、实例、绑定方法)也就用上了新代码
+    // 闭包换成新的函数体 提升值个数不同时无法替换
+    // 类清空方法表后仍用旧的类对象 类声明随后的 OP_INHERIT、OP_METHOD 会把新方法加到它上面 冻结的类不动
+    fn reload(&mut self, old: Value, new: Value) -> Value {
+        if old.is_obj_type(ObjType::Closure) && new.is_obj_type(ObjType::Closure) {
+            let (closure, function) = (as_closure!(old), unsafe { (*as_closure!(new)).function });
+            unsafe {
+                if (*closure).upvalue_count == (*function).upvalue_count {
+                    (*closure).function = function;
+                    return old;
+                }
+            }
+        } else if is_class!(old) && is_class!(new) {
+            let class = as_class!(old);
+            unsafe {
+                if !(*class).frozen {
+                    (*class).superclass = null_mut();
+                    (*(*class).methods).map.clear();
+                    return old;
+                }
+            }
+        }
+        new
+    }
+
     // 连接字符串
     fn concatenate(&mut self) {
-        let b = as_string!(self.peek(0));
-        let a = as_string!(self.peek(1));
+        let b = self.peek(0);
+        let a = self.peek(1);
 
-        unsafe {
-            let result = String::new();
-            let result = ObjString::take_string(result + &(*a).chars + &(*b).chars);
+        // 操作数在分配完成前留在栈上
+        let result = ObjString::take_string(format!("{}{}", a, b));
+
+        self.pop();
+        self.pop();
 
-            self.pop();
-            self.pop();
+        self.push(Value::Object(result as *mut Obj));
+    }
 
-            self.push(Value::Object(result as *mut Obj));
+    // 字符串重复 "ab" * 3 或 3 * "ab"
+    fn repeat_string(&mut self) -> bool {
+        let (a, b) = (self.peek(1), self.peek(0));
+        let (string, count) = if is_string!(a) { (a, b) } else { (b, a) };
+        let count = match count {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+            _ => {
+                self.runtime_error(
+                    "String repetition count must be a non-negative integer.".into(),
+                );
+                return false;
+            }
+        };
+        if !is_string!(string) {
+            self.runtime_error("Operands must be numbers.".into());
+            return false;
+        }
+
+        let string = as_string!(string);
+        // 结果过长时报错 不让分配失败终止整个进程
+        let chars = unsafe { &(*string).chars };
+        if chars
+            .len()
+            .checked_mul(count)
+            .is_none_or(|total| total > MAX_REPEAT_LEN)
+        {
+            self.runtime_error("String repetition result is too long.".into());
+            return false;
         }
+        let result = ObjString::take_string(chars.repeat(count));
+
+        self.pop();
+        self.pop();
+        self.push(obj_val!(result));
+        true
+    }
+
+    // Class.method 取出未绑定方法 调用时显式传入接收者
+    // 同名的方法优先于 name 和 superclass 属性
+    fn unbound_method(&mut self, name: *mut ObjString) -> bool {
+        let class = as_class!(self.peek(0));
+        let value = unsafe {
+            match ((*(*class).methods).get(name), (*name).chars.as_str()) {
+                (Some(method), _) => obj_val!(ObjUnboundMethod::new(as_closure!(*method))),
+                (None, "name") => obj_val!((*class).name),
+                (None, "superclass") if (*class).superclass.is_null() => Value::Nil,
+                (None, "superclass") => obj_val!((*class).superclass),
+                (None, _) => {
+                    self.runtime_error(format!("Undefined property '{}'.", &(*name).chars));
+                    return false;
+                }
+            }
+        };
+        self.pop();
+        self.push(value);
+        true
     }
 
     fn bind_method(&mut self, class: *mut ObjClass, name: *mut ObjString) -> bool {
         unsafe {
             if let Some(method) = (*(*class).methods).get(name) {
-                let bound = ObjBoundMethod::new(self.peek(0), as_closure!(method.clone()));
+                let bound = ObjBoundMethod::new(self.peek(0), as_closure!(*method));
                 self.pop();
                 self.push(obj_val!(bound));
                 true
@@ -724,31 +2255,406 @@ impl VM {
     }
 
     fn peek(&mut self, distance: i32) -> Value {
-        return unsafe { *self.stack_top.offset((-1 - distance) as isize) }.clone();
+        self.stack[self.stack_top - 1 - distance as usize]
     }
 
-    fn compile(&mut self, source: String) -> *mut ObjFunction {
-        let scanner = Scanner::new(source);
-        self.scanner = Some(scanner);
-        let mut compiler = Compiler::new(FunctionType::Script);
+    pub fn compile(&mut self, source: String) -> Result<Script, CompileError> {
+        self.compile_with_file(source, None)
+    }
 
-        self.parser.had_error = false;
-        self.parser.panic_mode = false;
+    // 只编译不执行 返回所有函数的反汇编文本 供显示生成的字节码
+    pub fn compile_to_text(&mut self, source: String) -> Result<String, CompileError> {
+        let script = self.compile(source)?;
+        let mut out = String::new();
+        debug::write_function(&mut out, script.function());
+        Ok(out)
+    }
 
-        compiler.compile()
+    // 编译来自文件的源码 函数记下文件名 栈回溯中会给出
+    pub fn compile_with_file(
+        &mut self,
+        source: String,
+        file: Option<&str>,
+    ) -> Result<Script, CompileError> {
+        let function = self.compile_function(source, file);
+        if function.is_null() {
+            Err(CompileError)
+        } else {
+            Ok(Script::new(function))
+        }
     }
 
-    pub fn push(&mut self, value: Value) {
-        unsafe {
-            *self.stack_top = value;
-            self.stack_top = self.stack_top.add(1);
+    // 编译源码 出错时返回空指针 返回的函数没有被根住 调用方要在下次分配前保存好
+    pub fn compile_function(&mut self, source: String, file: Option<&str>) -> *mut ObjFunction {
+        self.compile_source(source, file, &mut None)
+    }
+
+    // repl_locals 为 Some 时按 REPL 的一段输入编译 编译后换成加上新变量的列表
+    fn compile_source(
+        &mut self,
+        source: String,
+        file: Option<&str>,
+        repl_locals: &mut Option<Vec<String>>,
+    ) -> *mut ObjFunction {
+        // 每次编译都用新的上下文 错误标志、记号和声明记录都不沿用上一次的
+        let mut ctx = CompilerCtx::new(source, file);
+        ctx.repl_locals = repl_locals.take();
+        ctx.print_code = self.print_code;
+        ctx.print_stats = self.print_stats;
+        ctx.deny_warnings = self.deny_warnings;
+        ctx.strict_globals = self.strict_globals;
+        ctx.warn_unused_globals = self.warn_unused_globals;
+        ctx.debug_info = self.debug_info;
+
+        let result = ctx.compile();
+        *repl_locals = ctx.repl_locals.take();
+        if let Some(file) = file {
+            self.sources.add(file, ctx.source());
+        }
+        let diagnostics = match &result {
+            Ok(_) => &ctx.diagnostics,
+            Err(diagnostics) => diagnostics,
+        };
+        for diagnostic in diagnostics {
+            if matches!(diagnostic.severity, Severity::Warning) {
+                self.warning_count += 1;
+            }
+            let text = diagnostic.render(ctx.source(), file, self.color);
+            self.error_sink.report(diagnostic.severity, &text);
         }
+        result.unwrap_or(null_mut())
+    }
+
+    pub fn push(&mut self, value: Value) {
+        // 栈帧预留的槽位不够说明栈深度分析有误
+        debug_assert!(self.stack_top < self.stack.len(), "value stack overflow");
+        self.stack[self.stack_top] = value;
+        self.stack_top += 1;
     }
 
     pub fn pop(&mut self) -> Value {
-        unsafe {
-            self.stack_top = self.stack_top.sub(1);
-            *self.stack_top
+        self.stack_top -= 1;
+        self.stack[self.stack_top]
+    }
+
+    // 栈中第 index 个槽位的地址 交给提升值和原生函数 栈扩容后失效
+    fn stack_slot(&mut self, index: usize) -> *mut Value {
+        unsafe { self.stack.as_mut_ptr().add(index) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // 在当前线程新建虚拟机执行 source 返回全局变量 result
+    fn run_and_read(source: &str) -> f64 {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        assert!(matches!(vm().interpret(source.into()), InterpretResult::Ok));
+        let result = read_result();
+        drop_vm();
+        result
+    }
+
+    fn read_result() -> f64 {
+        let name = ObjString::take_string("result".into());
+        as_number!(*vm().globals.get(name).unwrap())
+    }
+
+    #[test]
+    fn two_vms_on_two_threads() {
+        let fib = thread::spawn(|| {
+            run_and_read(
+                "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                 var result = fib(15);\n",
+            )
+        });
+        let sum = thread::spawn(|| {
+            run_and_read(
+                "var result = 0;\n\
+                 for (var i = 0; i < 1000; i = i + 1) result = result + i;\n",
+            )
+        });
+        assert_eq!(fib.join().unwrap(), 610.0);
+        assert_eq!(sum.join().unwrap(), 499500.0);
+    }
+
+    #[test]
+    fn move_vm_to_another_thread() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        assert!(matches!(
+            vm().interpret("var result = 1;\n".into()),
+            InterpretResult::Ok
+        ));
+        let moved = detach_vm();
+
+        let result = thread::spawn(move || {
+            attach_vm(moved);
+            assert!(matches!(
+                vm().interpret("result = result + 1;\n".into()),
+                InterpretResult::Ok
+            ));
+            let result = read_result();
+            drop_vm();
+            result
+        });
+        assert_eq!(result.join().unwrap(), 2.0);
+    }
+
+    #[test]
+    fn timeout_interrupts_infinite_loop() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        vm().timeout = Some(Duration::from_millis(50));
+        assert!(matches!(
+            vm().interpret("while (true) {}\n".into()),
+            InterpretResult::Timeout
+        ));
+        // 超时后虚拟机还能继续使用
+        assert!(matches!(
+            vm().interpret("var result = 1;\n".into()),
+            InterpretResult::Ok
+        ));
+        assert_eq!(read_result(), 1.0);
+        drop_vm();
+    }
+
+    #[test]
+    fn audit_hook_can_deny_native() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        vm().on_audit(Box::new(|name, args| {
+            assert_eq!(name, "heapDump");
+            assert_eq!(args.len(), 1);
+            false
+        }));
+        assert!(matches!(
+            vm().interpret("heapDump(\"never-written.json\");\n".into()),
+            InterpretResult::RuntimeError
+        ));
+        // 没有标记的原生函数不经过审计
+        assert!(matches!(
+            vm().interpret("var result = abs(-1);\n".into()),
+            InterpretResult::Ok
+        ));
+        assert_eq!(read_result(), 1.0);
+        drop_vm();
+    }
+
+    // REPL 顶层的 var 在后面的输入中仍是同一个局部变量 闭包看到的也是它
+    // 出错的输入中新声明的变量被丢弃 :reset 后全部清除
+    #[test]
+    fn repl_locals_persist_across_inputs() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        let local = |name: &str| {
+            let slot = vm().repl_locals.iter().rposition(|local| local == name);
+            slot.map(|slot| as_number!(vm().stack[slot + 1]))
+        };
+        for input in [
+            "var count = 1;",
+            "fun inc() { count = count + 1; }",
+            "inc(); inc();",
+            "var total = count * 10;",
+        ] {
+            assert!(matches!(
+                vm().interpret_repl(input.into()),
+                InterpretResult::Ok
+            ));
         }
+        assert_eq!(local("count"), Some(3.0));
+        assert_eq!(local("total"), Some(30.0));
+
+        let result = vm().interpret_repl("var lost = 1; inc(); nil();".into());
+        assert!(matches!(result, InterpretResult::RuntimeError));
+        assert!(matches!(
+            vm().interpret_repl("var bad = ;".into()),
+            InterpretResult::CompileError
+        ));
+        assert_eq!(vm().repl_locals, ["count", "total"]);
+        assert_eq!(local("count"), Some(4.0));
+
+        vm().reset_repl_locals();
+        assert!(vm().repl_locals.is_empty());
+        assert_eq!(vm().stack_top, 0);
+        assert!(matches!(
+            vm().interpret_repl("inc();".into()),
+            InterpretResult::Ok
+        ));
+        drop_vm();
+    }
+
+    // 比较函数递归到栈扩容后 sort 仍返回原来的列表
+    #[test]
+    fn sort_survives_stack_growth_in_comparator() {
+        let source = "fun deep(n) {\n\
+                        var a = 1; var b = 2; var c = 3; var d = 4; var e = 5;\n\
+                        var f = 6; var g = 7; var h = 8; var i = 9; var j = 10;\n\
+                        if (n == 0) return a + b + c + d + e + f + g + h + i + j;\n\
+                        return deep(n - 1);\n\
+                      }\n\
+                      fun compare(x, y) { deep(40); return x - y; }\n\
+                      var list = [3, 1, 2];\n\
+                      var sorted = sort(list, compare);\n\
+                      var result = sorted == list ? sorted[0] * 100 + sorted[1] * 10 + sorted[2] : -1;\n";
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        assert!(matches!(vm().interpret(source.into()), InterpretResult::Ok));
+        assert!(vm().stack.len() > STACK_INITIAL);
+        assert_eq!(read_result(), 123.0);
+        drop_vm();
+    }
+
+    struct Collected(Arc<Mutex<Vec<(Severity, String)>>>);
+
+    impl ErrorSink for Collected {
+        fn report(&mut self, severity: Severity, text: &str) {
+            self.0.lock().unwrap().push((severity, text.into()));
+        }
+    }
+
+    // 编译诊断和运行时错误(含栈回溯)都交给 error_sink 不直接写标准错误
+    #[test]
+    fn diagnostics_go_through_the_error_sink() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        let reports = Arc::new(Mutex::new(vec![]));
+        vm().error_sink = Box::new(Collected(reports.clone()));
+        assert!(matches!(
+            vm().interpret("print 1 +;".into()),
+            InterpretResult::CompileError
+        ));
+        assert!(matches!(
+            vm().interpret("{ var unused = 1; }".into()),
+            InterpretResult::Ok
+        ));
+        let result = vm().interpret("fun f() { return -nil; }\nf();".into());
+        assert!(matches!(result, InterpretResult::RuntimeError));
+        drop_vm();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert!(matches!(reports[0].0, Severity::Error));
+        assert_eq!(reports[0].1, "[line 1] Error at ';': Expect expression.\n");
+        assert!(matches!(reports[1].0, Severity::Warning));
+        assert_eq!(
+            reports[1].1,
+            "[line 1] Warning at 'unused': Local variable 'unused' is never read.\n"
+        );
+        assert!(matches!(reports[2].0, Severity::Error));
+        assert_eq!(
+            reports[2].1,
+            "Operand must be a number.\n[line 1] in f()\n[line 2] in script\n"
+        );
+    }
+
+    // 反汇编文本包含脚本和嵌套的函数 编译错误时返回错误
+    #[test]
+    fn compile_to_text_includes_nested_functions() {
+        init_vm();
+        vm().print_code = false;
+        let text = vm()
+            .compile_to_text("fun f() { return 1; }\nprint f();\n".into())
+            .unwrap();
+        assert!(text.starts_with("== <script> ==\n"), "{}", text);
+        assert!(text.contains("\n== f ==\n"), "{}", text);
+        assert!(text.contains("OP_RETURN"), "{}", text);
+        assert!(vm().compile_to_text("print ;".into()).is_err());
+        drop_vm();
+    }
+
+    // 调用局部变量和提升值用专用指令 调用全局变量仍用 OP_CALL
+    #[test]
+    fn calls_to_locals_and_upvalues_use_specialized_opcodes() {
+        init_vm();
+        vm().print_code = false;
+        let text = vm()
+            .compile_to_text(
+                "fun outer() {\n\
+                   fun count(n) { if (n == 0) return 0; return count(n - 1) + 1; }\n\
+                   var f = count;\n\
+                   return f(3);\n\
+                 }\n\
+                 print outer();\n"
+                    .into(),
+            )
+            .unwrap();
+        let section = |name: &str| {
+            let start = text.find(&format!("== {} ==\n", name)).unwrap();
+            let rest = &text[start + 1..];
+            rest[..rest.find("\n== ").unwrap_or(rest.len())].to_string()
+        };
+        assert!(section("count").contains("OP_CALL_UPVALUE"), "{}", text);
+        assert!(section("outer").contains("OP_CALL_LOCAL"), "{}", text);
+        let script = section("<script>");
+        assert!(script.contains("OP_CALL "), "{}", text);
+        assert!(!script.contains("OP_CALL_LOCAL"), "{}", text);
+        drop_vm();
+    }
+
+    // 缓冲区的字节不在对象中 登记后循环分配大缓冲区也会触发回收
+    #[test]
+    fn large_native_allocations_trigger_gc() {
+        let source = "for (var i = 0; i < 8; i = i + 1) { buffer(1000000); }\n\
+                      var result = gcCount();\n";
+        assert!(run_and_read(source) >= 3.0);
+    }
+
+    #[test]
+    fn globals_persist_unless_isolated() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        assert!(matches!(
+            vm().interpret("var result = 1;\n".into()),
+            InterpretResult::Ok
+        ));
+        // 出错的编译不影响下一次
+        assert!(matches!(
+            vm().interpret("var broken = (;\n".into()),
+            InterpretResult::CompileError
+        ));
+        assert!(matches!(
+            vm().interpret("result = result + 1;\n".into()),
+            InterpretResult::Ok
+        ));
+        // 隔离执行看不到会话中的变量 定义的变量也不会留下
+        assert!(matches!(
+            vm().interpret_isolated("result = 10;\n".into()),
+            InterpretResult::RuntimeError
+        ));
+        assert!(matches!(
+            vm().interpret_isolated("var result = 10;\n".into()),
+            InterpretResult::Ok
+        ));
+        assert_eq!(read_result(), 2.0);
+        drop_vm();
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "checked_ip"))]
+    #[should_panic(expected = "OP_DEFINE_GLOBAL at offset 2 in 'script' reads past the end")]
+    fn checked_ip_catches_truncated_operand() {
+        init_vm();
+        vm().trace = false;
+        vm().print_code = false;
+        let Ok(script) = vm().compile("var result = 1;\n".into()) else {
+            panic!("compile failed");
+        };
+        // 去掉 DefineGlobal 的操作数和结尾的 Nil Return
+        let code = unsafe { &mut (*script.function()).chunk.code };
+        code.truncate(code.len() - 3);
+        vm().run(&script);
     }
 }
@@ -0,0 +1,33 @@
+use crate::{
+    as_weak_ref, obj_val,
+    object::{Arity, Obj, ObjType, ObjWeakRef},
+    value::{as_obj, Value},
+    vm::vm,
+};
+
+pub fn define_weak_natives() {
+    vm().define_native("weakRef", Arity::Exact(1), weak_ref_native);
+    vm().define_builtin_method(ObjType::WeakRef, "get", Arity::Exact(0), weak_get_method);
+}
+
+// weakRef(obj) 创建不阻止 obj 被回收的弱引用
+fn weak_ref_native(_arg_count: usize, args: *mut Value) -> Value {
+    match unsafe { *args } {
+        Value::Object(target) => obj_val!(ObjWeakRef::new(target)),
+        _ => {
+            vm().native_error("weakRef() expects an object.".into());
+            Value::Nil
+        }
+    }
+}
+
+// ref.get() 目标还存活时返回目标 已被回收时返回nil
+fn weak_get_method(_arg_count: usize, args: *mut Value) -> Value {
+    let weak = as_weak_ref!(unsafe { *args.sub(1) });
+    let target = unsafe { (*weak).target };
+    if target.is_null() {
+        Value::Nil
+    } else {
+        Value::Object(target)
+    }
+}
@@ -0,0 +1,32 @@
+// 调用深度上限和栈溢出时的栈回溯
+use std::process::{Command, Output};
+
+const RECURSE: &str = "fun f(n) { return f(n + 1); }\nf(0);\n";
+
+fn eval(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(args)
+        .args(["-e", RECURSE])
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn overflow_trace_trims_repeated_frames() {
+    let output = eval(&["--max-call-depth", "200"]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines[0], "Stack overflow.");
+    assert!(lines[1].starts_with("[line 1] in f(n=198)"), "{}", stderr);
+    assert!(lines[3].starts_with("[line 1] in f(n=196)"), "{}", stderr);
+    assert_eq!(lines[4], "... 196 more frames like this ...");
+    assert!(lines[5].starts_with("[line 2] in script"), "{}", stderr);
+    assert_eq!(lines.len(), 6);
+}
+
+#[test]
+fn max_call_depth_must_be_positive() {
+    let output = eval(&["--max-call-depth", "0"]);
+    assert_eq!(output.status.code(), Some(64));
+}
@@ -0,0 +1,38 @@
+// 集成测试共用的辅助函数 每个测试文件通过 mod common 引入 各自只用到其中一部分
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+// 把源码写入临时文件并运行 返回进程的输出和退出状态
+pub fn run_output(name: &str, source: &str) -> Output {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("rslox-{}-{}.lox", name, std::process::id()));
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+// 运行源码 要求正常退出 返回标准输出
+pub fn run(name: &str, source: &str) -> String {
+    let output = run_output(name, source);
+    assert!(
+        output.status.success(),
+        "exit status {:?}\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// 调试特性会把字节码和执行轨迹也打印到标准输出 只检查 print 输出的那一行
+pub fn printed(output: &str, line: &str) -> bool {
+    output.lines().any(|printed| printed == line)
+}
@@ -0,0 +1,35 @@
+// 用 rslox test 运行 tests/lox 下按 craftinginterpreters 测试集格式写的用例
+// 未通过的用例记在 KNOWN_FAILURES 中 修好之后要从列表中删掉 列表即是与 Lox 规范的差距
+use std::{env, path::Path, process::Command};
+
+const KNOWN_FAILURES: &[&str] = &[];
+
+fn run_suite(dir: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .arg("test")
+        .arg(dir)
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn lox_suite() {
+    let report = run_suite(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox"));
+    let mut failures: Vec<&str> = report
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix("FAIL "))
+        .filter_map(|line| line.split(':').next())
+        .collect();
+    failures.sort();
+    assert_eq!(failures, KNOWN_FAILURES, "\n{}", report);
+}
+
+// LOX_TEST_SUITE 指向 craftinginterpreters 仓库的 test 目录时 顺便运行官方测试集
+// 只打印各分类的通过情况(cargo test -- --nocapture) 不作为失败
+#[test]
+fn official_suite() {
+    if let Some(dir) = env::var_os("LOX_TEST_SUITE") {
+        println!("{}", run_suite(Path::new(&dir)));
+    }
+}
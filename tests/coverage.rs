@@ -0,0 +1,37 @@
+// --coverage 写出执行过的源码行
+use std::{fs, process::Command};
+
+fn coverage_of(source: &str, out: &str) -> String {
+    let path = std::env::temp_dir().join(format!("rslox-{}-{}", std::process::id(), out));
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(["-e", source, "--coverage"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    report
+}
+
+#[test]
+fn lcov_lists_executed_and_missed_lines() {
+    let source = "var i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}\nif (i > 5) {\n  print i;\n}\n";
+    let report = coverage_of(source, "cov.lcov");
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[..2], ["TN:", "SF:<script>"], "{}", report);
+    assert!(lines.contains(&"DA:1,1"), "{}", report);
+    assert!(lines.contains(&"DA:3,3"), "{}", report);
+    assert!(lines.contains(&"DA:6,0"), "{}", report);
+    assert!(lines.contains(&"end_of_record"), "{}", report);
+}
+
+#[test]
+fn json_report_for_json_path() {
+    let report = coverage_of("fun f() {\n  return 1;\n}\n", "cov.json");
+    assert!(
+        report.contains("\"<script>\": {\"2\": 0, \"3\": 1"),
+        "{}",
+        report
+    );
+}
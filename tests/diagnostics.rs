@@ -0,0 +1,37 @@
+// 运行时错误和编译错误一样给出出错的源码行 --color 只添加样式
+use std::{fs, process::Command};
+
+fn run_with(name: &str, args: &[&str]) -> String {
+    let path = std::env::temp_dir().join(format!("rslox-{}-{}.lox", name, std::process::id()));
+    fs::write(&path, "var a = 1;\nprint -\"x\";\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("Operand must be a number."), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("{}:2:8", path.display())),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("print -\"x\";"), "{}", stderr);
+    stderr
+}
+
+#[test]
+fn runtime_error_shows_source_line() {
+    run_with("snippet", &["--color", "always"]);
+}
+
+#[test]
+fn plain_runtime_error_shows_source_line() {
+    let stderr = run_with("plain-snippet", &["--color", "never"]);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines[0], "Operand must be a number.");
+    assert_eq!(lines[4], "  |        ^");
+    assert!(lines[5].starts_with("[line 2] in script"), "{}", stderr);
+    assert!(!stderr.contains('\x1b'), "{}", stderr);
+}
@@ -0,0 +1,25 @@
+// 错误信息出现在它之前的 print 输出之后
+use std::{fs, process::Command};
+
+#[test]
+fn native_error_follows_earlier_prints() {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("rslox-order-{}.lox", std::process::id()));
+    let log = dir.join(format!("rslox-order-{}.log", std::process::id()));
+    fs::write(&path, "print \"one\";\nprint \"two\";\nrandomInt(5, 1);\n").unwrap();
+    let out = fs::File::create(&log).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(["--no-prelude"])
+        .arg(&path)
+        .stdout(out.try_clone().unwrap())
+        .stderr(out)
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(70));
+    let text = fs::read_to_string(&log).unwrap();
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&log).unwrap();
+    let two = text.find("two").unwrap();
+    let error = text.find("[native] in randomInt()").unwrap();
+    assert!(two < error, "{}", text);
+}
@@ -0,0 +1,44 @@
+// rslox examples 附带的示例都要能正常运行 改动语言或原生函数时不会让示例悄悄失效
+mod common;
+
+use std::process::{Command, Output};
+
+use common::printed;
+
+fn examples(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .arg("examples")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn every_listed_example_runs() {
+    let listing = String::from_utf8_lossy(&examples(&[]).stdout).into_owned();
+    let names: Vec<&str> = listing
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    assert!(!names.is_empty(), "{}", listing);
+    for name in names {
+        let output = examples(&[name]);
+        assert!(
+            output.status.success(),
+            "example '{}' failed\n{}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
+
+#[test]
+fn example_output_and_source() {
+    let output = String::from_utf8_lossy(&examples(&["classes"]).stdout).into_owned();
+    assert!(printed(&output, "Rex makes a sound. Well, Rex barks."));
+    let source = String::from_utf8_lossy(&examples(&["classes", "--source"]).stdout).into_owned();
+    assert!(source.contains("class Dog < Animal {"));
+    assert_eq!(examples(&["missing"]).status.code(), Some(64));
+}
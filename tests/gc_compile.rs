@@ -0,0 +1,50 @@
+// 默认特性开启了 debug_stress_gc 每次分配都会回收
+// 编译期间分配的函数、字符串常量必须始终可达
+mod common;
+
+use common::{printed, run};
+
+#[test]
+fn large_class() {
+    let mut source = String::from("class Big {\n");
+    for i in 0..60 {
+        source.push_str(&format!(
+            "  method{i}(a) {{ var name = \"method{i}\"; return a + {i} + len(name) - len(name); }}\n"
+        ));
+    }
+    source.push_str("}\nfun sum() {\n  var big = Big();\n  var sum = 0;\n");
+    for i in 0..60 {
+        source.push_str(&format!("  sum = big.method{i}(sum);\n"));
+    }
+    source.push_str("  return sum;\n}\nprint sum();\n");
+    assert!(printed(&run("large_class", &source), "1770"));
+}
+
+#[test]
+fn nested_closures() {
+    let mut source = String::new();
+    for i in 0..20 {
+        source.push_str(&format!("fun f{i}(x) {{ var s{i} = \"level{i}\";\n"));
+    }
+    source.push_str("return x;\n");
+    for i in (0..20).rev() {
+        source.push_str("}\n");
+        if i > 0 {
+            source.push_str(&format!("return f{i}(x);\n"));
+        }
+    }
+    source.push_str("print f0(42);\n");
+    assert!(printed(&run("nested_closures", &source), "42"));
+}
+
+#[test]
+fn many_string_constants() {
+    let mut source = String::from("fun count() {\n  var n = 0;\n  var s;\n");
+    for i in 0..100 {
+        source.push_str(&format!(
+            "  s = \"constant{i}\"; if (s != nil) n = n + 1;\n"
+        ));
+    }
+    source.push_str("  return n;\n}\nprint count();\n");
+    assert!(printed(&run("many_string_constants", &source), "100"));
+}
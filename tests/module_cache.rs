@@ -0,0 +1,64 @@
+// 编译缓存不能绕过影响编译结果的选项
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+// 在 dir 中用独立的缓存目录运行 main.lox
+fn output_in(dir: &Path, flags: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(flags)
+        .arg(dir.join("main.lox"))
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .output()
+        .unwrap()
+}
+
+fn run_in(dir: &Path, flags: &[&str]) -> Option<i32> {
+    output_in(dir, flags).status.code()
+}
+
+fn module_dir(name: &str, module: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("rslox-cache-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("lib.lox"), module).unwrap();
+    fs::write(dir.join("main.lox"), "import \"lib\";\nprint \"ok\";\n").unwrap();
+    dir
+}
+
+#[test]
+fn deny_warnings_is_part_of_the_cache_key() {
+    let dir = module_dir("deny", "fun f() { var unused = 1; }\nf();\n");
+    assert_eq!(run_in(&dir, &[]), Some(0));
+    assert_ne!(run_in(&dir, &["--deny-warnings"]), Some(0));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn strict_is_part_of_the_cache_key() {
+    let dir = module_dir("strict", "fun f() { undeclared = 1; }\n");
+    assert_eq!(run_in(&dir, &[]), Some(0));
+    assert_ne!(run_in(&dir, &["--strict"]), Some(0));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn warnings_are_reported_on_every_run() {
+    let dir = module_dir("warnings", "fun f() { var unused = 1; }\nf();\n");
+    for _ in 0..2 {
+        let output = output_in(&dir, &[]);
+        assert_eq!(output.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("unused"));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dump_bytecode_bypasses_the_cache() {
+    let dir = module_dir("dump", "fun f() { return 1; }\n");
+    assert_eq!(run_in(&dir, &[]), Some(0));
+    let output = output_in(&dir, &["--dump-bytecode"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("== f =="));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,60 @@
+// 源码末尾的各种情况 不能越界 也不能丢掉最后一个字符
+mod common;
+
+use common::{printed, run, run_output};
+use std::process::Command;
+
+// 编译错误时的退出码和标准错误
+fn compile_error(name: &str, source: &str) -> String {
+    let output = run_output(name, source);
+    assert_eq!(output.status.code(), Some(65));
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn empty_source() {
+    run("empty", "");
+}
+
+#[test]
+fn whitespace_and_comment_only() {
+    run("blank", "  \n\t\n");
+    run("comment", "// nothing here");
+}
+
+#[test]
+fn last_token_without_newline() {
+    assert!(printed(&run("no_newline", "print 12;"), "12"));
+    assert!(printed(&run("trailing_space", "print 3;   "), "3"));
+    assert!(printed(&run("trailing_comment", "print 4; // done"), "4"));
+}
+
+#[test]
+fn single_token() {
+    let stderr = compile_error("single_token", "x");
+    assert!(stderr.contains("[line 1] Error at end: Expect ';' after expression."));
+}
+
+#[test]
+fn ends_mid_statement() {
+    let stderr = compile_error("mid_number", "print 12");
+    assert!(stderr.contains("[line 1] Error at end: Expect ';' after value."));
+    let stderr = compile_error("mid_identifier", "var abc = abc");
+    assert!(stderr.contains("Error at end"));
+}
+
+#[test]
+fn ends_inside_string() {
+    let stderr = compile_error("mid_string", "print \"abc");
+    assert!(stderr.contains("Error: Unterminated string."));
+}
+
+#[test]
+fn eval_without_newline() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(["-e", "print 5;"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(printed(&String::from_utf8_lossy(&output.stdout), "5"));
+}
@@ -0,0 +1,22 @@
+// --stats 打印每个函数的字节码块统计
+mod common;
+
+use std::process::Command;
+
+use common::printed;
+
+#[test]
+fn stats_report_limits_and_histogram() {
+    let source = "fun add(a, b) { var sum = a + b; return sum; }\nprint add(1, 2);\n";
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(["--stats", "-e", source])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(printed(&stdout, "== stats add =="), "{}", stdout);
+    assert!(printed(&stdout, "locals     4 / 256"), "{}", stdout);
+    assert!(printed(&stdout, "upvalues   0 / 256"), "{}", stdout);
+    assert!(printed(&stdout, "  OP_GET_LOCAL         3"), "{}", stdout);
+    assert!(printed(&stdout, "== stats <script> =="), "{}", stdout);
+}
@@ -0,0 +1,64 @@
+// 只有 nil 和 false 为假 其余的值(包括 0、空字符串和空列表)都为真
+// 每个程序把结果收集到一个列表里最后一起打印
+mod common;
+
+use common::{printed, run};
+
+#[test]
+fn if_takes_branch_for_truthy_values() {
+    let source = "\
+var r = [];
+if (0) push(r, 1); else push(r, -1);
+if (\"\") push(r, 2); else push(r, -2);
+if ([]) push(r, 3); else push(r, -3);
+if (clock) push(r, 4); else push(r, -4);
+if (nil) push(r, -5); else push(r, 5);
+if (false) push(r, -6); else push(r, 6);
+if (true) push(r, 7); else push(r, -7);
+print r;
+";
+    assert!(printed(&run("if", source), "[1, 2, 3, 4, 5, 6, 7]"));
+}
+
+#[test]
+fn while_loops_on_truthy_condition() {
+    let source = "\
+var n = 3;
+var count = 0;
+while (n) {
+  count = count + 1;
+  if (count == 3) n = nil;
+}
+var skipped = 0;
+while (false) skipped = skipped + 1;
+print [count, skipped];
+";
+    assert!(printed(&run("while", source), "[3, 0]"));
+}
+
+#[test]
+fn and_or_return_operands() {
+    let source = "print [0 and 1, nil and 1, false and 1, 0 or 1, nil or 2, false or nil];\n";
+    assert!(printed(
+        &run("and_or", source),
+        "[1, nil, false, 0, 2, nil]"
+    ));
+}
+
+#[test]
+fn not_negates_truthiness() {
+    let source = "print [!0, !\"\", ![], !nil, !false, !true];\n";
+    assert!(printed(
+        &run("not", source),
+        "[false, false, false, true, true, false]"
+    ));
+}
+
+#[test]
+fn bool_converts_by_truthiness() {
+    let source = "print [bool(0), bool(\"\"), bool([]), bool(nil), bool(false), bool(true)];\n";
+    assert!(printed(
+        &run("bool", source),
+        "[true, true, true, false, false, true]"
+    ));
+}
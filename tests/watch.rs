@@ -0,0 +1,29 @@
+// --watch-global 和 --watch-local 在安全点上打印变化的值
+use std::process::Command;
+
+#[test]
+fn watches_print_changes_at_safepoints() {
+    let source = "var total = 0;\n\
+                  fun add(n) { total = total + n; }\n\
+                  for (var i = 1; i < 3; i = i + 1) add(i);\n";
+    let output = Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(["--watch-global", "total", "--watch-local", "1"])
+        .args(["-e", source])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let watched: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.contains("watch total"))
+        .collect();
+    assert_eq!(
+        watched,
+        [
+            "[line 2] watch total = 0",
+            "[line 3] watch total = 1",
+            "[line 3] watch total = 3"
+        ]
+    );
+    assert!(stderr.contains("watch slot 1 in add = 2"), "{}", stderr);
+}